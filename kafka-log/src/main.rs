@@ -1,23 +1,21 @@
+use maelstrom_core::{
+    env_flag, env_parse, error_details, Clock, ErrorDetails, KvClient, KvReply, Message, Output,
+    RetryBudget, Runtime,
+};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
-    io::{self, BufRead},
-    time::Duration,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    time::{Duration, Instant, UNIX_EPOCH},
 };
 
-#[derive(Deserialize)]
-struct Request<'a> {
-    src: &'a str,
-    dest: &'a str,
-    body: RequestBody<'a>,
-}
+type Request<'a> = Message<'a, RequestBody<'a>>;
+type Response<'a> = Message<'a, ResponseBody<'a>>;
 
-#[derive(Serialize)]
-struct Response<'a> {
-    src: &'a str,
-    dest: &'a str,
-    body: ResponseBody<'a>,
-}
+/// Send times of messages, as `[offset, ms since the Unix epoch]` by key, so
+/// `poll_since` works on whichever node the messages reach.
+type Stamps<'a> = HashMap<&'a str, Vec<(i64, u64)>>;
 
 #[derive(Deserialize)]
 #[serde(rename_all = "snake_case")]
@@ -35,21 +33,68 @@ enum RequestBody<'a> {
     Poll {
         msg_id: i64,
         offsets: HashMap<&'a str, i64>,
+        /// Server-side consumer position from an earlier `poll_ok`; pass an
+        /// unknown token (e.g. `""`) to open a new one.
+        #[serde(default)]
+        cursor: Option<String>,
+        /// Also poll every known key starting with this prefix, from offset 0
+        /// unless `offsets` or the cursor says otherwise.
+        #[serde(default)]
+        prefix: Option<String>,
     },
     Send {
         msg_id: i64,
         key: &'a str,
         msg: i64,
+        /// Write at this offset instead of the next free one. Repeating a
+        /// write is fine; a different message already there is an error.
+        #[serde(default)]
+        offset: Option<i64>,
     },
     CommitOffsets {
         msg_id: i64,
         offsets: HashMap<&'a str, i64>,
+        /// Only commit a key if its committed offset is currently this value
+        /// (`-1` for a key with nothing committed). Keys that don't match are
+        /// left alone and listed in the reply's `failed`.
+        #[serde(default)]
+        expected: HashMap<&'a str, i64>,
+        /// Commit `offsets` even where they are lower than the committed
+        /// ones, by starting a new offset generation for each key.
+        #[serde(default)]
+        reset: bool,
+    },
+    Watermarks {
+        msg_id: i64,
+        keys: Vec<&'a str>,
     },
     ListCommittedOffsets {
         msg_id: i64,
         keys: Vec<&'a str>,
+        /// Read from the retained snapshot closest to this offsets version
+        /// instead of the current offsets.
+        #[serde(default)]
+        version: Option<u64>,
+    },
+    Error {
+        in_reply_to: i64,
+        #[serde(default)]
+        code: i8,
+        #[serde(default)]
+        text: &'a str,
+        #[serde(default)]
+        details: ErrorDetails,
+    },
+    ReadOk {
+        in_reply_to: i64,
+        value: serde_json::Value,
+    },
+    WriteOk {
+        in_reply_to: i64,
+    },
+    CasOk {
+        in_reply_to: i64,
     },
-    Error,
     GetUpdates {
         msg_id: i64,
         offsets: HashMap<&'a str, i64>,
@@ -57,15 +102,64 @@ enum RequestBody<'a> {
     GetUpdatesOk {
         in_reply_to: i64,
         updates: HashMap<&'a str, Vec<[i64; 2]>>,
+        /// When the `send` of each update was handled, as `[offset, ms]`.
+        #[serde(default)]
+        stamps: Stamps<'a>,
     },
     Sync {
         msg_id: i64,
         offsets: HashMap<&'a str, i64>,
         updates: HashMap<&'a str, Vec<[i64; 2]>>,
+        /// The offset generation of each key in `offsets`, if not 0.
+        #[serde(default)]
+        generations: HashMap<&'a str, u64>,
+        #[serde(default)]
+        stamps: Stamps<'a>,
     },
     SyncOk {
         in_reply_to: i64,
     },
+    /// The owner's answer to a forwarded `send`.
+    SendOk {
+        in_reply_to: i64,
+        offset: i64,
+    },
+    FetchRange {
+        msg_id: i64,
+        key: &'a str,
+        from: i64,
+        to: i64,
+    },
+    FetchRangeOk {
+        key: &'a str,
+        msgs: Vec<[i64; 2]>,
+        #[serde(default)]
+        stamps: Vec<(i64, u64)>,
+    },
+    /// A peer's newly stored `send`, pushed with `replicate_sends`. Unacked.
+    Replicate {
+        key: &'a str,
+        offset: i64,
+        msg: i64,
+        /// When the peer handled the `send`.
+        #[serde(default)]
+        sent_at: Option<u64>,
+    },
+    /// Committed messages in `keys` whose `send` was handled after `since`,
+    /// in milliseconds since the Unix epoch, by whichever node took it.
+    PollSince {
+        msg_id: i64,
+        keys: Vec<&'a str>,
+        since: u64,
+    },
+    /// Debug-only: the messages sent to `key` that haven't been synced yet.
+    ReadUncommitted {
+        msg_id: i64,
+        key: &'a str,
+    },
+    DumpConfig {
+        msg_id: i64,
+    },
 }
 
 #[derive(Serialize)]
@@ -80,23 +174,67 @@ enum ResponseBody<'a> {
     },
     PollOk {
         in_reply_to: i64,
-        msgs: HashMap<&'a str, &'a [[i64; 2]]>,
+        msgs: HashMap<&'a str, Vec<[i64; 2]>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cursor: Option<String>,
+        /// Requested keys left out by `poll_max_keys`, to poll for next.
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        more: Vec<&'a str>,
+        /// For each key polled at `LATEST_OFFSET`, the tail offset to poll
+        /// from next.
+        #[serde(skip_serializing_if = "HashMap::is_empty")]
+        latest: HashMap<&'a str, i64>,
     },
     SendOk {
         in_reply_to: i64,
         offset: i64,
     },
+    Replicate {
+        key: &'a str,
+        offset: i64,
+        msg: i64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sent_at: Option<u64>,
+    },
+    /// The answer to `poll_since`, whose hits needn't be contiguous.
+    #[serde(rename = "poll_ok")]
+    PollSinceOk {
+        in_reply_to: i64,
+        msgs: HashMap<&'a str, Vec<[i64; 2]>>,
+    },
+    /// A client's `send` forwarded to the node that owns the key.
+    Send {
+        msg_id: i64,
+        key: &'a str,
+        msg: i64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        offset: Option<i64>,
+    },
     CommitOffsetsOk {
         in_reply_to: i64,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        failed: Vec<String>,
+    },
+    /// Keys with no messages are left out.
+    WatermarksOk {
+        in_reply_to: i64,
+        watermarks: HashMap<&'a str, Watermark>,
     },
+    /// Keys that were never committed are left out, as the workload
+    /// expects, rather than reported as 0, which is itself a committable
+    /// offset. A key committed to 0 is sent as 0.
     ListCommittedOffsetsOk {
         in_reply_to: i64,
         offsets: HashMap<&'a str, i64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        version: Option<u64>,
     },
     Error {
         in_reply_to: i64,
         code: i8,
         text: &'a str,
+        #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+        details: ErrorDetails,
     },
     GetUpdates {
         msg_id: i64,
@@ -105,171 +243,2967 @@ enum ResponseBody<'a> {
     GetUpdatesOk {
         in_reply_to: i64,
         updates: HashMap<&'a str, &'a [[i64; 2]]>,
+        #[serde(skip_serializing_if = "HashMap::is_empty")]
+        stamps: Stamps<'a>,
     },
     Sync {
         msg_id: i64,
         offsets: &'a HashMap<&'a str, i64>,
         updates: &'a HashMap<&'a str, &'a [[i64; 2]]>,
+        #[serde(skip_serializing_if = "HashMap::is_empty")]
+        generations: &'a HashMap<&'a str, u64>,
+        #[serde(skip_serializing_if = "HashMap::is_empty")]
+        stamps: Stamps<'a>,
     },
     SyncOk {
         in_reply_to: i64,
     },
+    FetchRange {
+        msg_id: i64,
+        key: &'a str,
+        from: i64,
+        to: i64,
+    },
+    FetchRangeOk {
+        in_reply_to: i64,
+        key: &'a str,
+        msgs: Vec<[i64; 2]>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        stamps: Vec<(i64, u64)>,
+    },
+    ReadUncommittedOk {
+        in_reply_to: i64,
+        msgs: &'a [[i64; 2]],
+    },
+    /// The `Config` the node is running with.
+    DumpConfigOk {
+        in_reply_to: i64,
+        config: serde_json::Value,
+    },
+}
+
+/// Runtime options, read from the environment at startup. `Default` gives
+/// the documented defaults, as if no variable were set.
+#[derive(Serialize)]
+struct Config {
+    /// Enables debug-only requests such as `read_uncommitted` (`KAFKA_DEBUG=1`).
+    debug: bool,
+    /// Reject `commit_offsets` beyond the end of a key's log with error 14
+    /// instead of accepting them (`KAFKA_STRICT_COMMITS=1`).
+    strict_commits: bool,
+    /// Mirror committed logs and offsets into lin-kv and read them back on
+    /// init, so a restarted node recovers them (`KAFKA_PERSIST=1`).
+    persist: bool,
+    /// Push every stored `send` to all peers' uncommitted logs at once,
+    /// instead of leaving them to be gathered by the next commit
+    /// (`KAFKA_REPLICATE_SENDS=1`), so peers' polls see them sooner.
+    replicate_sends: bool,
+    /// Upper bound on messages returned per key by `poll`. Consumers that
+    /// are far behind get batches up to this size, consumers near the tail
+    /// get smaller ones (`KAFKA_POLL_MAX_BATCH`, unbounded by default).
+    poll_max_batch: Option<usize>,
+    /// Smallest batch an adaptive `poll` returns while more messages are
+    /// available (`KAFKA_POLL_MIN_BATCH`, default 1).
+    poll_min_batch: usize,
+    /// Most keys one `poll_ok` carries. The rest, in key order, are listed
+    /// in its `more` (`KAFKA_POLL_MAX_KEYS`, unbounded by default).
+    poll_max_keys: Option<usize>,
+    /// Where a `poll` without a `cursor` starts
+    /// (`KAFKA_POLL_MODE=offsets|auto-advance`, default `offsets`).
+    poll_mode: PollMode,
+    /// Stop the node on an input line that isn't a valid message instead of
+    /// logging it to stderr and moving on (`KAFKA_ABORT_ON_PARSE_ERROR=1`).
+    abort_on_parse_error: bool,
+    /// Write spans and events at this level and above to stderr
+    /// (`KAFKA_TRACE=error|warn|info|debug|trace`, off by default).
+    trace: Option<String>,
+    /// Assigns each key an owning node that every `send` for it is forwarded
+    /// to (`KAFKA_PARTITION=hash-mod|consistent-hash|range`). Without one,
+    /// any node accepts sends for any key.
+    #[serde(serialize_with = "serialize_partition")]
+    partition: Option<Box<dyn PartitionStrategy>>,
+    /// Resend a `sync` to peers that haven't acked it after this long, with
+    /// only the keys they still lack (`KAFKA_SYNC_RETRY_MS`, no retries by
+    /// default).
+    sync_retry: Option<Duration>,
+    /// Gossip committed offsets for a key at most once per this interval;
+    /// commits in between are coalesced into the next round and acked once
+    /// it has synced (`KAFKA_COMMIT_GOSSIP_MS`, unthrottled by default).
+    commit_gossip_interval: Option<Duration>,
+    /// `sync` resends allowed per second across all pending syncs, with
+    /// bursts of up to a second's worth (`KAFKA_RETRY_BUDGET`, unlimited by
+    /// default). A resend over budget waits another `sync_retry`.
+    retry_budget: Option<u32>,
+    /// Reject `commit_offsets` with error 11 while this many commits are
+    /// still gathering updates or waiting on `sync` acks
+    /// (`KAFKA_MAX_PENDING_SYNCS`, unbounded by default).
+    max_pending_syncs: Option<usize>,
+    /// How a `send` without an offset picks one
+    /// (`KAFKA_OFFSET_ALLOCATION=local|interleaved|lin-kv`, default `local`).
+    offset_allocation: OffsetAllocation,
+    /// Keep at most this many committed messages per key, compacting older
+    /// ones away once they are at or below the key's committed offset
+    /// (`KAFKA_RETAIN_COMMITTED`, everything kept by default).
+    retain_committed: Option<usize>,
+    /// Append compacted messages to this NDJSON file, one line per key and
+    /// compaction, and read them back for polls that reach below what is
+    /// still kept (`KAFKA_SNAPSHOT_PATH`, compacted messages are dropped by
+    /// default).
+    snapshot_path: Option<String>,
+}
+
+/// Where a `poll` without a `cursor` starts reading each key.
+#[derive(Default, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum PollMode {
+    /// At the requested offsets. A consumer that crashes before committing
+    /// polls the same messages again, so each is seen at least once.
+    #[default]
+    Offsets,
+    /// Where the client's previous poll of the key left off, through a
+    /// cursor the node keeps per client; the requested offset only counts
+    /// for the first poll of a key. No message is returned to a client
+    /// twice, but one it crashes before processing is lost to it, so each
+    /// is seen at most once.
+    AutoAdvance,
+}
+
+/// Where the offsets of `send`s without one come from.
+#[derive(Default, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum OffsetAllocation {
+    /// One past the highest offset this node has seen for the key. Offsets
+    /// follow arrival order, but two nodes taking sends for the same key
+    /// concurrently can hand out the same one, and only one of the two
+    /// messages survives the next merge.
+    #[default]
+    Local,
+    /// Like `Local`, rounded up to the next offset congruent to this node's
+    /// position in `node_ids` modulo the node count. No two nodes ever pick
+    /// the same offset, and each node's offsets for a key increase, but
+    /// sends through different nodes are only ordered as far as each has
+    /// seen the other's: a send can get a lower offset than one another node
+    /// acked earlier. Logs have holes where other nodes' slots went unused,
+    /// so they are not repaired or waited on, which is why this is opt-in.
+    Interleaved,
+    /// A per-key counter in lin-kv, bumped by `cas` before the `send_ok`.
+    /// Offsets are unique and follow the order sends were acked in across
+    /// all nodes, at the cost of a lin-kv round trip (or more, when nodes
+    /// race for the same key) per send.
+    LinKv,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            debug: false,
+            strict_commits: false,
+            persist: false,
+            replicate_sends: false,
+            poll_max_batch: None,
+            poll_min_batch: 1,
+            poll_max_keys: None,
+            poll_mode: PollMode::Offsets,
+            abort_on_parse_error: false,
+            trace: None,
+            partition: None,
+            sync_retry: None,
+            commit_gossip_interval: None,
+            retry_budget: None,
+            max_pending_syncs: None,
+            offset_allocation: OffsetAllocation::Local,
+            retain_committed: None,
+            snapshot_path: None,
+        }
+    }
+}
+
+impl Config {
+    fn from_env() -> Self {
+        let default = Config::default();
+        Config {
+            debug: env_flag("KAFKA_DEBUG"),
+            strict_commits: env_flag("KAFKA_STRICT_COMMITS"),
+            persist: env_flag("KAFKA_PERSIST"),
+            replicate_sends: env_flag("KAFKA_REPLICATE_SENDS"),
+            poll_max_batch: env_parse("KAFKA_POLL_MAX_BATCH"),
+            poll_min_batch: env_parse("KAFKA_POLL_MIN_BATCH").unwrap_or(default.poll_min_batch),
+            poll_max_keys: env_parse("KAFKA_POLL_MAX_KEYS"),
+            poll_mode: match std::env::var("KAFKA_POLL_MODE").as_deref() {
+                Ok("auto-advance") => PollMode::AutoAdvance,
+                Ok("offsets") => PollMode::Offsets,
+                _ => default.poll_mode,
+            },
+            abort_on_parse_error: env_flag("KAFKA_ABORT_ON_PARSE_ERROR"),
+            trace: std::env::var("KAFKA_TRACE").ok(),
+            partition: match std::env::var("KAFKA_PARTITION").as_deref() {
+                Ok("hash-mod") => Some(Box::new(HashMod)),
+                Ok("consistent-hash") => Some(Box::new(ConsistentHash { vnodes: 64 })),
+                Ok("range") => Some(Box::new(Range)),
+                _ => default.partition,
+            },
+            sync_retry: env_parse("KAFKA_SYNC_RETRY_MS").map(Duration::from_millis),
+            commit_gossip_interval: env_parse("KAFKA_COMMIT_GOSSIP_MS").map(Duration::from_millis),
+            retry_budget: env_parse("KAFKA_RETRY_BUDGET"),
+            max_pending_syncs: env_parse("KAFKA_MAX_PENDING_SYNCS"),
+            offset_allocation: match std::env::var("KAFKA_OFFSET_ALLOCATION").as_deref() {
+                Ok("local") => OffsetAllocation::Local,
+                Ok("lin-kv") => OffsetAllocation::LinKv,
+                Ok("interleaved") => OffsetAllocation::Interleaved,
+                _ => default.offset_allocation,
+            },
+            retain_committed: env_parse("KAFKA_RETAIN_COMMITTED"),
+            snapshot_path: std::env::var("KAFKA_SNAPSHOT_PATH").ok(),
+        }
+    }
+
+    /// How many of the `lag` messages past a consumer's offset one `poll`
+    /// returns: half the lag, kept within the configured batch bounds.
+    fn poll_batch(&self, lag: usize) -> usize {
+        match self.poll_max_batch {
+            Some(max) => (lag / 2).clamp(self.poll_min_batch.min(max), max).min(lag),
+            None => lag,
+        }
+    }
+}
+
+/// Decides which node owns a key.
+trait PartitionStrategy {
+    fn owner(&self, key: &str, nodes: &[String]) -> String;
+
+    /// Its `KAFKA_PARTITION` name.
+    fn name(&self) -> &'static str;
+}
+
+/// `Config::partition` by name, for `dump_config`.
+fn serialize_partition<S: serde::Serializer>(
+    partition: &Option<Box<dyn PartitionStrategy>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    partition
+        .as_ref()
+        .map(|partition| partition.name())
+        .serialize(serializer)
+}
+
+/// The key's hash modulo the node count.
+struct HashMod;
+
+impl PartitionStrategy for HashMod {
+    fn owner(&self, key: &str, nodes: &[String]) -> String {
+        nodes[(fnv1a(key.as_bytes()) % nodes.len() as u64) as usize].clone()
+    }
+
+    fn name(&self) -> &'static str {
+        "hash-mod"
+    }
+}
+
+/// Each node takes `vnodes` points on a hash ring and owns the keys hashing
+/// up to each point, so adding a node moves only about 1/N of the keys.
+struct ConsistentHash {
+    vnodes: usize,
+}
+
+impl PartitionStrategy for ConsistentHash {
+    fn owner(&self, key: &str, nodes: &[String]) -> String {
+        let mut ring: Vec<(u64, &String)> = nodes
+            .iter()
+            .flat_map(|node| {
+                (0..self.vnodes).map(move |i| (ring_point(format!("{node}#{i}").as_bytes()), node))
+            })
+            .collect();
+        ring.sort_unstable();
+        let hash = ring_point(key.as_bytes());
+        let i = ring.partition_point(|&(point, _)| point < hash);
+        ring[i % ring.len()].1.clone()
+    }
+
+    fn name(&self) -> &'static str {
+        "consistent-hash"
+    }
+}
+
+/// Splits the key space into contiguous ranges by first byte, one per node
+/// in node-id order, so neighbouring keys share an owner.
+struct Range;
+
+impl PartitionStrategy for Range {
+    fn owner(&self, key: &str, nodes: &[String]) -> String {
+        let mut sorted: Vec<&String> = nodes.iter().collect();
+        sorted.sort_by_key(|id| (id.len(), id.as_str()));
+        let first = key.bytes().next().unwrap_or(0) as usize;
+        sorted[first * sorted.len() / 256].clone()
+    }
+
+    fn name(&self) -> &'static str {
+        "range"
+    }
+}
+
+/// FNV-1a, chosen because every node must hash keys identically.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Where `bytes` lands on `ConsistentHash`'s ring. FNV-1a's high bits
+/// barely move between inputs differing only at the end, like `n1#0` and
+/// `n1#1`, which would bunch each node's points together, so they go
+/// through MurmurHash3's finalizer first.
+fn ring_point(bytes: &[u8]) -> u64 {
+    let mut hash = fnv1a(bytes);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ hash >> 33
 }
 
 #[derive(Default)]
 struct Node {
+    config: Config,
+    /// The time as the node sees it, which tests move by hand.
+    clock: Clock,
     node_id: String,
     node_id_i64: i64,
     node_ids: Vec<String>,
     topology: Vec<String>,
     commited_offsets: HashMap<String, i64>,
-    offsets_ready_to_commit: HashMap<String, i64>,
+    /// Bumped for a key by each `commit_offsets` reset. Committed offsets
+    /// merge by `(generation, offset)`, so a reset wins over any offset
+    /// from before it, however high. Keys missing here are at 0.
+    offset_generations: HashMap<String, u64>,
     commited_msgs: HashMap<String, Vec<[i64; 2]>>,
     uncommited_msgs: HashMap<String, Vec<[i64; 2]>>,
-    ongoing_syncs: HashMap<i64, usize>,
+    /// When each message's `send` was handled, in milliseconds since the
+    /// Unix epoch, by key and offset. Stamped by the node that took the
+    /// `send` and passed on with the message by `sync`, `replicate` and
+    /// `fetch_range_ok`.
+    sent_at: HashMap<String, BTreeMap<i64, u64>>,
+    /// Nodes yet to ack each commit's `sync`, by `msg_id`.
+    ongoing_syncs: HashMap<i64, HashSet<String>>,
+    next_msg_id: i64,
+    /// Per-cursor next offset to poll, by key.
+    cursors: HashMap<String, HashMap<String, i64>>,
+    next_cursor: u64,
+    /// Bumped every time `commited_offsets` changes.
+    offsets_version: u64,
+    /// The last few versions of `commited_offsets`, oldest first.
+    offset_snapshots: VecDeque<(u64, HashMap<String, i64>)>,
+    /// Keys whose committed state has been written to lin-kv.
+    persisted_keys: HashSet<String>,
+    /// Outstanding lin-kv writes, and reads issued while recovering after a
+    /// restart. Built at init.
+    lin_kv: KvClient<LinKvOp>,
+    /// Keys of a conditional commit whose expectation didn't hold, reported
+    /// once the rest of the commit is acknowledged. By sync `msg_id`.
+    commit_failures: HashMap<i64, Vec<String>>,
+    /// Commits waiting on their `sync`: client and its `msg_id`, by sync
+    /// `msg_id`. Throttled rounds have no client and no entry.
+    pending_commits: HashMap<i64, (String, i64)>,
+    /// Sends waiting on a lin-kv offset allocation, by an id of their own.
+    pending_sends: HashMap<i64, PendingSend>,
+    /// Sends forwarded to a key's owner: client and its `msg_id`.
+    forwarded_sends: HashMap<i64, (String, i64)>,
+    /// `sync`s some peer hasn't acked yet, by `msg_id`.
+    unacked_syncs: HashMap<i64, UnackedSync>,
+    /// The highest committed offset each peer has acked a `sync` for, by key.
+    peer_synced: HashMap<String, HashMap<String, (u64, i64)>>,
+    /// Commits waiting on `get_updates_ok`s, by `msg_id`.
+    gathering: HashMap<i64, Gathering>,
+    /// The newest `sync` (by `msg_id`) whose offsets were applied from each
+    /// peer, by key.
+    applied_syncs: HashMap<String, HashMap<String, i64>>,
+    /// When each key's committed offset was last gossiped.
+    last_offset_gossip: HashMap<String, Instant>,
+    /// Commits held back by `commit_gossip_interval`, highest offset per key.
+    throttled_offsets: HashMap<String, i64>,
+    /// Commits with keys in `throttled_offsets`, answered once every round
+    /// carrying their keys has synced.
+    held_commits: Vec<HeldCommit>,
+    /// Built from `Config::retry_budget` at init.
+    retry_budget: Option<RetryBudget>,
+    /// Commits rejected by `max_pending_syncs` so far.
+    shed_commits: u64,
+    /// The highest offset compacted out of each key's committed log.
+    compacted_through: HashMap<String, i64>,
+    /// The lowest offset written to `Config::snapshot_path` for each key.
+    snapshot_earliest: HashMap<String, i64>,
 }
 
-static EMPTY: Vec<[i64; 2]> = Vec::new();
+/// One compaction of one key, as a line of the snapshot file.
+#[derive(Serialize, Deserialize)]
+struct SnapshotEntry {
+    key: String,
+    msgs: Vec<[i64; 2]>,
+}
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let stdin = io::stdin();
-    let mut node = Node::default();
-    // let mut file = std::fs::File::create("/home/eman/gossip-glomers/maelstrom-echo/foo.txt")?;
-    for line in stdin.lock().lines() {
-        std::thread::sleep(Duration::from_millis(10));
-        let line = line?;
-        // std::io::Write::write_all(&mut file, line.as_bytes())?;
-        let request: Request = serde_json::from_str(&line)?;
+/// The offsets a consumer can currently poll for a key, both inclusive.
+#[derive(Serialize)]
+struct Watermark {
+    earliest: i64,
+    latest: i64,
+}
 
-        let response_body = match request.body {
-            RequestBody::Init {
-                msg_id,
-                node_id,
-                node_ids,
-            } => {
-                node.node_id_i64 = i64::from_str_radix(&node_id[1..], 10).unwrap();
-                node.node_id = node_id;
-                node.node_ids = node_ids;
-                ResponseBody::InitOk {
-                    in_reply_to: msg_id,
-                }
-            }
-            RequestBody::Topology { msg_id, topology } => {
-                node.topology = topology.get(node.node_id.as_str()).unwrap().clone();
-                ResponseBody::TopologyOk {
-                    in_reply_to: msg_id,
-                }
+/// A commit collecting uncommitted messages from every node before its `sync`.
+struct Gathering {
+    offsets: HashMap<String, i64>,
+    generations: HashMap<String, u64>,
+    updates: HashMap<String, Vec<[i64; 2]>>,
+    /// Nodes that haven't answered `get_updates` yet.
+    waiting: HashSet<String>,
+}
+
+/// A client's commit waiting on throttled rounds as well as its own sync.
+struct HeldCommit {
+    client: String,
+    msg_id: i64,
+    failed: Vec<String>,
+    /// Its keys still in `throttled_offsets`.
+    throttled: HashSet<String>,
+    /// Rounds carrying its keys that some node hasn't acked, by `msg_id`.
+    syncs: HashSet<i64>,
+}
+
+/// A `sync` waiting on acks, kept so it can be resent to the peers missing.
+struct UnackedSync {
+    sent_at: Instant,
+    offsets: HashMap<String, i64>,
+    generations: HashMap<String, u64>,
+    updates: HashMap<String, Vec<[i64; 2]>>,
+    peers: HashSet<String>,
+}
+
+/// A client's `send` parked until lin-kv confirms its offset.
+struct PendingSend {
+    client: String,
+    msg_id: i64,
+    key: String,
+    msg: i64,
+    /// The offset the outstanding `cas` is trying to claim.
+    offset: i64,
+}
+
+/// What a lin-kv request is for: the continuation its reply runs, in
+/// `Node::on_lin_kv_reply`.
+enum LinKvOp {
+    /// Writing committed state; nothing to do once acked.
+    Persist,
+    /// Reading committed state back for recovery.
+    Keys,
+    Log(String),
+    Offset(String),
+    /// Claiming an offset for a pending send.
+    Allocate(i64),
+    /// Reading a key's offset counter after losing a `cas` race for it.
+    Counter(i64),
+}
+
+impl Node {
+    /// Remembers a `sync` just sent to `peers` for `retry_syncs`.
+    fn track_sync<'a>(
+        &mut self,
+        msg_id: i64,
+        offsets: &HashMap<&str, i64>,
+        updates: &HashMap<&str, &[[i64; 2]]>,
+        generations: &HashMap<&str, u64>,
+        peers: impl IntoIterator<Item = &'a String>,
+    ) {
+        if self.config.sync_retry.is_none() {
+            return;
+        }
+        let sync = UnackedSync {
+            sent_at: self.clock.now(),
+            offsets: offsets.iter().map(|(&k, &v)| (k.to_owned(), v)).collect(),
+            generations: generations
+                .iter()
+                .map(|(&k, &g)| (k.to_owned(), g))
+                .collect(),
+            updates: updates
+                .iter()
+                .map(|(&k, &v)| (k.to_owned(), v.to_vec()))
+                .collect(),
+            peers: peers
+                .into_iter()
+                .filter(|&id| id != &self.node_id)
+                .cloned()
+                .collect(),
+        };
+        if !sync.peers.is_empty() {
+            self.unacked_syncs.insert(msg_id, sync);
+        }
+    }
+
+    fn ack_sync(&mut self, msg_id: i64, peer: &str) {
+        let Some(sync) = self.unacked_syncs.get_mut(&msg_id) else {
+            return;
+        };
+        if !sync.peers.remove(peer) {
+            return;
+        }
+        let synced = self.peer_synced.entry(peer.to_owned()).or_default();
+        for (k, &v) in &sync.offsets {
+            let synced_at = (sync.generations.get(k).copied().unwrap_or(0), v);
+            let acked = synced.entry(k.clone()).or_insert(synced_at);
+            *acked = synced_at.max(*acked);
+        }
+        if sync.peers.is_empty() {
+            self.unacked_syncs.remove(&msg_id);
+        }
+    }
+
+    /// Resends overdue `sync`s to each peer that hasn't acked them, leaving
+    /// out keys the peer has since acked an equal or later offset for.
+    fn retry_syncs(&mut self, out: &mut Output) -> io::Result<()> {
+        let Some(retry) = self.config.sync_retry else {
+            return Ok(());
+        };
+        let mut result = Ok(());
+        for (&msg_id, sync) in &mut self.unacked_syncs {
+            if self.clock.elapsed(sync.sent_at) < retry {
+                continue;
             }
-            RequestBody::Send { msg_id, key, msg } => {
-                // need offset calulation that doesn't collide and checks offsets_ready_to_commit
-                let current = node.uncommited_msgs.entry(key.to_owned()).or_default();
-                let offset = current.last().map(|x| x[0] + 1).unwrap_or(0);
-                current.push([offset, msg]);
-                ResponseBody::SendOk {
-                    in_reply_to: msg_id,
-                    offset,
+            sync.sent_at = self.clock.now();
+            sync.peers.retain(|peer| {
+                let synced = self.peer_synced.get(peer);
+                let lacks = |k: &str| {
+                    let acked = synced.and_then(|s| s.get(k));
+                    let generation = sync.generations.get(k).copied().unwrap_or(0);
+                    match (acked, sync.offsets.get(k)) {
+                        (Some(&acked), Some(&offset)) => acked < (generation, offset),
+                        _ => true,
+                    }
+                };
+                let offsets: HashMap<&str, i64> = sync
+                    .offsets
+                    .iter()
+                    .filter(|(k, _)| lacks(k))
+                    .map(|(k, &v)| (k.as_str(), v))
+                    .collect();
+                let updates: HashMap<&str, &[[i64; 2]]> = sync
+                    .updates
+                    .iter()
+                    .filter(|(k, _)| lacks(k))
+                    .map(|(k, v)| (k.as_str(), v.as_slice()))
+                    .collect();
+                if offsets.is_empty() && updates.is_empty() {
+                    return false;
                 }
-            }
-            RequestBody::Poll { msg_id, offsets } => {
-                let msgs = offsets
+                let generations: HashMap<&str, u64> = sync
+                    .generations
                     .iter()
-                    .map(|(&k, v)| {
-                        let logs = node.commited_msgs.get(k).unwrap_or(&EMPTY);
-                        (k, &logs[logs.partition_point(|probe| probe[0] < *v)..])
-                    })
+                    .filter(|(k, _)| offsets.contains_key(k.as_str()))
+                    .map(|(k, &g)| (k.as_str(), g))
                     .collect();
-                ResponseBody::PollOk {
-                    in_reply_to: msg_id,
-                    msgs,
+                if !self.retry_budget.as_mut().is_none_or(RetryBudget::try_take) {
+                    return true;
                 }
-            }
-            RequestBody::CommitOffsets { msg_id, offsets } => {
-                for node_id in &node.node_ids {
-                    let response = Response {
-                        src: request.dest,
-                        dest: &node_id,
-                        body: ResponseBody::GetUpdates { msg_id, offsets: &offsets },
-                    };
-                    println!("{}", serde_json::to_string(&response)?);
+                let sent = out.send(&Response {
+                    src: &self.node_id,
+                    dest: peer,
+                    body: ResponseBody::Sync {
+                        msg_id,
+                        offsets: &offsets,
+                        updates: &updates,
+                        generations: &generations,
+                        stamps: stamps_for(&self.sent_at, &updates),
+                    },
+                });
+                if let Err(err) = sent {
+                    result = Err(err);
                 }
-                continue;
+                true
+            });
+        }
+        self.unacked_syncs.retain(|_, sync| !sync.peers.is_empty());
+        result
+    }
+
+    /// Moves keys gossiped less than `commit_gossip_interval` ago out of
+    /// `offsets` and into `throttled_offsets`; the rest count as gossiped now.
+    fn throttle_commit(&mut self, offsets: &mut HashMap<&str, i64>) {
+        let Some(interval) = self.config.commit_gossip_interval else {
+            return;
+        };
+        offsets.retain(|&k, &mut v| {
+            let recent = self
+                .last_offset_gossip
+                .get(k)
+                .is_some_and(|at| self.clock.elapsed(*at) < interval);
+            if recent {
+                let held = self.throttled_offsets.entry(k.to_owned()).or_insert(v);
+                *held = v.max(*held);
+            } else {
+                self.last_offset_gossip
+                    .insert(k.to_owned(), self.clock.now());
             }
-            RequestBody::ListCommittedOffsets { msg_id, keys } => {
-                ResponseBody::ListCommittedOffsetsOk {
-                    in_reply_to: msg_id,
-                    offsets: keys
-                        .iter()
-                        .filter_map(|&k| node.commited_offsets.get(k).map(|&v| (k, v)))
-                        .collect(),
-                }
+            !recent
+        });
+    }
+
+    /// Gossips the throttled commits whose interval has passed.
+    fn flush_throttled_offsets(&mut self, out: &mut Output) -> io::Result<()> {
+        let Some(interval) = self.config.commit_gossip_interval else {
+            return Ok(());
+        };
+        let due: Vec<String> = self
+            .throttled_offsets
+            .keys()
+            .filter(|&k| {
+                self.last_offset_gossip
+                    .get(k)
+                    .is_none_or(|at| self.clock.elapsed(*at) >= interval)
+            })
+            .cloned()
+            .collect();
+        if due.is_empty() {
+            return Ok(());
+        }
+        let mut offsets = HashMap::new();
+        for k in due {
+            let v = self.throttled_offsets.remove(&k).unwrap();
+            self.last_offset_gossip.insert(k.clone(), self.clock.now());
+            offsets.insert(k, v);
+        }
+        let offsets: HashMap<&str, i64> = offsets.iter().map(|(k, &v)| (k.as_str(), v)).collect();
+        let generations = self.generations_of(&offsets, false);
+        let msg_id = self.next_msg_id();
+        for held in &mut self.held_commits {
+            let before = held.throttled.len();
+            held.throttled.retain(|k| !offsets.contains_key(k.as_str()));
+            if held.throttled.len() < before {
+                held.syncs.insert(msg_id);
             }
-            RequestBody::Error { .. } => ResponseBody::Error {
-                in_reply_to: 0,
-                code: 10,
-                text: "Boo Not Supported",
-            },
-            RequestBody::GetUpdates { msg_id, offsets } => {
-                let msgs = offsets
-                    .iter()
-                    .map(|(&k, v)| {
-                        let logs = node.uncommited_msgs.get(k).unwrap_or(&EMPTY);
-                        (k, &logs[..logs.partition_point(|probe| probe[0] <= *v)])
-                    })
-                    .collect();
-                ResponseBody::PollOk {
-                    in_reply_to: msg_id,
-                    msgs,
-                }
+        }
+        self.gather_updates(msg_id, &offsets, generations, out)
+    }
+
+    /// Answers the held commits that were waiting on `sync_id` last, now
+    /// that every node has acked it.
+    fn release_held_commits(&mut self, sync_id: i64, out: &mut Output) -> io::Result<()> {
+        for held in &mut self.held_commits {
+            held.syncs.remove(&sync_id);
+        }
+        let (done, held): (Vec<_>, Vec<_>) = std::mem::take(&mut self.held_commits)
+            .into_iter()
+            .partition(|held| held.throttled.is_empty() && held.syncs.is_empty());
+        self.held_commits = held;
+        for held in done {
+            out.send(&Response {
+                src: &self.node_id,
+                dest: &held.client,
+                body: ResponseBody::CommitOffsetsOk {
+                    in_reply_to: held.msg_id,
+                    failed: held.failed,
+                },
+            })?;
+        }
+        Ok(())
+    }
+
+    /// The generation a commit of `offsets` goes out with for each key that
+    /// isn't at 0: the current one, or with `reset` the one after it.
+    fn generations_of(&self, offsets: &HashMap<&str, i64>, reset: bool) -> HashMap<String, u64> {
+        offsets
+            .keys()
+            .filter_map(|&k| {
+                let current = self.offset_generations.get(k).copied().unwrap_or(0);
+                let generation = current + u64::from(reset);
+                (generation > 0).then(|| (k.to_owned(), generation))
+            })
+            .collect()
+    }
+
+    /// Asks every node for its uncommitted messages up to `offsets`. The
+    /// answers are collected in `gathering` and the last one sends the `sync`.
+    fn gather_updates(
+        &mut self,
+        msg_id: i64,
+        offsets: &HashMap<&str, i64>,
+        generations: HashMap<String, u64>,
+        out: &mut Output,
+    ) -> io::Result<()> {
+        for node_id in &self.node_ids {
+            out.send(&Response {
+                src: &self.node_id,
+                dest: node_id,
+                body: ResponseBody::GetUpdates { msg_id, offsets },
+            })?;
+        }
+        let gathering = Gathering {
+            offsets: offsets.iter().map(|(&k, &v)| (k.to_owned(), v)).collect(),
+            generations,
+            updates: HashMap::new(),
+            waiting: self.node_ids.iter().cloned().collect(),
+        };
+        self.gathering.insert(msg_id, gathering);
+        Ok(())
+    }
+
+    /// The node that owns `key` if it isn't this one.
+    fn remote_owner(&self, key: &str) -> Option<String> {
+        let partition = self.config.partition.as_ref()?;
+        let owner = partition.owner(key, &self.node_ids);
+        (owner != self.node_id).then_some(owner)
+    }
+
+    /// Commits still gathering updates or waiting on `sync` acks.
+    fn pending_syncs(&self) -> usize {
+        self.gathering.len() + self.ongoing_syncs.len()
+    }
+
+    fn next_msg_id(&mut self) -> i64 {
+        self.next_msg_id += 1;
+        self.next_msg_id
+    }
+
+    /// Returns `token` if it names a known cursor, otherwise opens a new one.
+    fn open_cursor(&mut self, token: String) -> String {
+        if self.cursors.contains_key(&token) {
+            return token;
+        }
+        self.next_cursor += 1;
+        let token = format!("{}-{}", self.node_id, self.next_cursor);
+        self.cursors.insert(token.clone(), HashMap::new());
+        token
+    }
+
+    fn snapshot_offsets(&mut self) {
+        self.offsets_version += 1;
+        if self.offset_snapshots.len() == OFFSET_SNAPSHOTS {
+            self.offset_snapshots.pop_front();
+        }
+        self.offset_snapshots
+            .push_back((self.offsets_version, self.commited_offsets.clone()));
+    }
+
+    /// Drops `key`'s oldest committed messages beyond `retain_committed`,
+    /// though never one above its committed offset, appending them to
+    /// `snapshot_path` if there is one. Messages merged back in at or below
+    /// what was already compacted are dropped again.
+    fn compact(&mut self, key: &str) -> io::Result<()> {
+        let Some(retain) = self.config.retain_committed else {
+            return Ok(());
+        };
+        let Some(log) = self.commited_msgs.get_mut(key) else {
+            return Ok(());
+        };
+        let through = self.compacted_through.get(key).copied().unwrap_or(-1);
+        let stale = log.partition_point(|x| x[0] <= through);
+        log.drain(..stale);
+        let committed = self.commited_offsets.get(key).copied().unwrap_or(-1);
+        let excess = log.len().saturating_sub(retain);
+        let end = log[..excess].partition_point(|x| x[0] <= committed);
+        if end == 0 {
+            return Ok(());
+        }
+        let msgs: Vec<[i64; 2]> = log.drain(..end).collect();
+        self.compacted_through
+            .insert(key.to_owned(), msgs[end - 1][0]);
+        let Some(path) = &self.config.snapshot_path else {
+            return Ok(());
+        };
+        self.snapshot_earliest
+            .entry(key.to_owned())
+            .or_insert(msgs[0][0]);
+        let entry = SnapshotEntry {
+            key: key.to_owned(),
+            msgs,
+        };
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        serde_json::to_writer(&mut file, &entry)?;
+        writeln!(file)
+    }
+
+    /// `key`'s messages at `offset` or later that compaction moved to the
+    /// snapshot file. A snapshot that can't be read counts as empty.
+    fn snapshot_messages(&self, key: &str, offset: i64) -> Vec<[i64; 2]> {
+        let mut msgs = Vec::new();
+        let Some(path) = &self.config.snapshot_path else {
+            return msgs;
+        };
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) => {
+                eprintln!("can't read snapshot {path:?}: {err}");
+                return msgs;
             }
-            RequestBody::Sync { msg_id, offsets, updates } => {
-                offsets.iter().for_each(|(&k, &v)| {
-                    if let Some(logs) = node.uncommited_msgs.get_mut(k) {
-                    let partition_point = logs.partition_point(|probe| probe[0] <= v);
-                    logs.drain(..partition_point);
-                    }
-                    node.commited_offsets
-                        .entry(k.to_owned())
-                        .and_modify(|x| *x = v.max(*x))
-                        .or_insert(v);
-                });
+        };
+        for line in BufReader::new(file).lines().map_while(Result::ok) {
+            let Ok(entry) = serde_json::from_str::<SnapshotEntry>(&line) else {
+                continue;
+            };
+            if entry.key == key {
+                let from = entry.msgs.partition_point(|x| x[0] < offset);
+                merge_into(&mut msgs, &entry.msgs[from..]);
+            }
+        }
+        msgs
+    }
 
-                updates.iter().for_each(|(&k, v)| {
-                    let stuff = node.commited_msgs.entry(k.to_owned()).or_default();
-                    stuff.extend_from_slice(&v);
-                    stuff.sort_by(|a, b| a[0].cmp(&b[0]));
-                });
-                
-                ResponseBody::SyncOk {
+    fn keys_kv_key(&self) -> String {
+        format!("{}/keys", self.node_id)
+    }
+
+    fn log_kv_key(&self, key: &str) -> String {
+        format!("{}/log/{key}", self.node_id)
+    }
+
+    fn offset_kv_key(&self, key: &str) -> String {
+        format!("{}/offset/{key}", self.node_id)
+    }
+
+    /// Writes `key`'s committed log and offset to lin-kv, plus the key index
+    /// the first time `key` is seen.
+    fn persist(&mut self, key: &str, out: &mut Output) -> anyhow::Result<()> {
+        if !self.config.persist {
+            return Ok(());
+        }
+        let log = self.commited_msgs.get(key).unwrap_or(&EMPTY);
+        let mut writes = vec![(self.log_kv_key(key), serde_json::to_value(log)?)];
+        if let Some(&offset) = self.commited_offsets.get(key) {
+            writes.push((self.offset_kv_key(key), offset.into()));
+        }
+        if self.persisted_keys.insert(key.to_owned()) {
+            writes.push((
+                self.keys_kv_key(),
+                serde_json::to_value(&self.persisted_keys)?,
+            ));
+        }
+        for (key, value) in writes {
+            let msg_id = self.next_msg_id();
+            self.lin_kv
+                .write(out, msg_id, &key, value, LinKvOp::Persist)?;
+        }
+        Ok(())
+    }
+
+    /// Tries to claim the offset after `last` for pending send `send`.
+    fn allocate_offset(&mut self, send: i64, last: i64, out: &mut Output) -> anyhow::Result<()> {
+        let Some(pending) = self.pending_sends.get_mut(&send) else {
+            return Ok(());
+        };
+        if last >= MAX_OFFSET {
+            let key = pending.key.clone();
+            return self.finish_send(
+                send,
+                |msg_id| ResponseBody::Error {
                     in_reply_to: msg_id,
+                    code: 14,
+                    text: "no offsets left for this key",
+                    details: error_details(serde_json::json!({ "key": key })),
+                },
+                out,
+            );
+        }
+        pending.offset = last + 1;
+        let key = counter_kv_key(&pending.key);
+        let msg_id = self.next_msg_id();
+        let op = LinKvOp::Allocate(send);
+        self.lin_kv
+            .cas(out, msg_id, &key, last, last + 1, true, op)?;
+        Ok(())
+    }
+
+    /// Runs the continuation `op` registered for a lin-kv request on the
+    /// reply to it. An allocation that lost its `cas` to another node reads
+    /// where the counter is now and tries the offset after it; a missing
+    /// counter can't have been raced for, so it starts over. Nothing having
+    /// been persisted yet, or a key having vanished, leaves nothing to
+    /// recover, and a failed write is superseded by the key's next one.
+    fn on_lin_kv_reply(
+        &mut self,
+        op: LinKvOp,
+        reply: KvReply<'_, serde_json::Value>,
+        out: &mut Output,
+    ) -> anyhow::Result<()> {
+        match (op, reply) {
+            (LinKvOp::Keys, KvReply::ReadOk(value)) => {
+                let keys: Vec<String> = serde_json::from_value(value)?;
+                for key in keys {
+                    self.recover(&self.log_kv_key(&key), LinKvOp::Log(key.clone()), out)?;
+                    self.recover(&self.offset_kv_key(&key), LinKvOp::Offset(key.clone()), out)?;
+                    self.persisted_keys.insert(key);
                 }
             }
-            RequestBody::SyncOk { in_reply_to } => {
-                let sync = node.ongoing_syncs.get_mut(&in_reply_to).unwrap();
-                *sync -= 1;
-                if *sync == 0 {
-                    ResponseBody::CommitOffsetsOk { in_reply_to }
-                } else {
-                    continue;
-                }
-            },
-            RequestBody::GetUpdatesOk { in_reply_to, updates } => {
-                for node_id in &node.node_ids {
-                    let response = Response {
-                        src: request.dest,
-                        dest: &node_id,
-                        body: ResponseBody::Sync { msg_id: (), offsets: (), updates: () } { msg_id, offsets: &offsets },
-                    };
-                    println!("{}", serde_json::to_string(&response)?);
-                }
-                continue;
+            (LinKvOp::Log(key), KvReply::ReadOk(value)) => {
+                let log: Vec<[i64; 2]> = serde_json::from_value(value)?;
+                let conflicts =
+                    merge_into(self.commited_msgs.entry(key.clone()).or_default(), &log);
+                warn_conflicts(&key, "lin-kv", &conflicts);
             }
-        };
-        let response = Response {
-            src: request.dest,
-            dest: request.src,
-            body: response_body,
-        };
-        println!("{}", serde_json::to_string(&response)?);
+            (LinKvOp::Offset(key), KvReply::ReadOk(value)) => {
+                let offset: i64 = serde_json::from_value(value)?;
+                let committed = self.commited_offsets.entry(key).or_insert(offset);
+                *committed = offset.max(*committed);
+            }
+            (LinKvOp::Counter(send), KvReply::ReadOk(value)) => {
+                let last: i64 = serde_json::from_value(value)?;
+                self.allocate_offset(send, last, out)?;
+            }
+            (LinKvOp::Allocate(send), KvReply::CasOk) => {
+                let Some(pending) = self.pending_sends.get(&send) else {
+                    return Ok(());
+                };
+                let (key, offset, msg) = (pending.key.clone(), pending.offset, pending.msg);
+                self.store_send(&key, offset, msg, out)?;
+                self.finish_send(
+                    send,
+                    |msg_id| ResponseBody::SendOk {
+                        in_reply_to: msg_id,
+                        offset,
+                    },
+                    out,
+                )?;
+            }
+            (LinKvOp::Allocate(send) | LinKvOp::Counter(send), KvReply::Error { code: 20, .. }) => {
+                self.allocate_offset(send, -1, out)?;
+            }
+            (LinKvOp::Allocate(send) | LinKvOp::Counter(send), KvReply::Error { code: 22, .. }) => {
+                let Some(pending) = self.pending_sends.get(&send) else {
+                    return Ok(());
+                };
+                let key = counter_kv_key(&pending.key);
+                let msg_id = self.next_msg_id();
+                self.lin_kv
+                    .read(out, msg_id, &key, LinKvOp::Counter(send))?;
+            }
+            (
+                LinKvOp::Allocate(send) | LinKvOp::Counter(send),
+                KvReply::Error {
+                    code,
+                    text,
+                    details,
+                },
+            ) => {
+                self.finish_send(
+                    send,
+                    |msg_id| ResponseBody::Error {
+                        in_reply_to: msg_id,
+                        code,
+                        text,
+                        details,
+                    },
+                    out,
+                )?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Answers pending send `send` with the body `reply` builds from the
+    /// client's `msg_id`, and forgets it.
+    fn finish_send<'a>(
+        &mut self,
+        send: i64,
+        reply: impl FnOnce(i64) -> ResponseBody<'a>,
+        out: &mut Output,
+    ) -> anyhow::Result<()> {
+        let Some(pending) = self.pending_sends.remove(&send) else {
+            return Ok(());
+        };
+        let response = Response {
+            src: &self.node_id,
+            dest: &pending.client,
+            body: reply(pending.msg_id),
+        };
+        out.send(&response)?;
+        Ok(())
+    }
+
+    /// Sends a lin-kv read for recovery and remembers what it is for.
+    fn recover(&mut self, key: &str, what: LinKvOp, out: &mut Output) -> anyhow::Result<()> {
+        let msg_id = self.next_msg_id();
+        self.lin_kv.read(out, msg_id, key, what)?;
+        Ok(())
+    }
+
+    /// The retained snapshot whose version is nearest to `version`.
+    fn offsets_at(&self, version: u64) -> Option<&(u64, HashMap<String, i64>)> {
+        self.offset_snapshots
+            .iter()
+            .min_by_key(|(v, _)| v.abs_diff(version))
+    }
+
+    /// The range of offsets still stored for `key`, counting those
+    /// compaction moved to the snapshot file.
+    fn watermark(&self, key: &str) -> Option<Watermark> {
+        let first = |logs: &HashMap<String, Vec<[i64; 2]>>| {
+            logs.get(key).and_then(|log| log.first()).map(|x| x[0])
+        };
+        let kept = first(&self.commited_msgs).or_else(|| first(&self.uncommited_msgs));
+        let snapshot = self.snapshot_earliest.get(key).copied();
+        let earliest = match (snapshot, kept) {
+            (Some(snapshot), Some(kept)) => snapshot.min(kept),
+            (earliest, None) | (None, earliest) => earliest?,
+        };
+        Some(Watermark {
+            earliest,
+            latest: self.tail_offset(key)?,
+        })
+    }
+
+    /// The highest offset this node knows of for `key`, committed or not.
+    fn tail_offset(&self, key: &str) -> Option<i64> {
+        let last = |logs: &HashMap<String, Vec<[i64; 2]>>| {
+            logs.get(key).and_then(|log| log.last()).map(|x| x[0])
+        };
+        last(&self.commited_msgs).max(last(&self.uncommited_msgs))
+    }
+
+    /// The offset a `send` to `key` without one gets: one past anything
+    /// stored, so sends handled back to back are numbered in the order they
+    /// arrived and never reuse a committed offset. With interleaved
+    /// allocation, the first such offset in this node's slot.
+    fn next_offset(&self, key: &str) -> i64 {
+        let next = self.tail_offset(key).map_or(0, |tail| tail + 1);
+        if self.config.offset_allocation != OffsetAllocation::Interleaved {
+            return next;
+        }
+        let Some(slot) = self.node_ids.iter().position(|id| *id == self.node_id) else {
+            return next;
+        };
+        let n = self.node_ids.len() as i64;
+        next.checked_add((slot as i64 - next).rem_euclid(n))
+            .unwrap_or(i64::MAX)
+    }
+
+    /// Whether logs are expected to have holes that no node will fill.
+    fn sparse_offsets(&self) -> bool {
+        self.config.offset_allocation == OffsetAllocation::Interleaved
+    }
+
+    /// Stores a client's `send` in `key`'s uncommitted log and replicates it
+    /// if configured to.
+    fn store_send(
+        &mut self,
+        key: &str,
+        offset: i64,
+        msg: i64,
+        out: &mut Output,
+    ) -> anyhow::Result<()> {
+        let log = self.uncommited_msgs.entry(key.to_owned()).or_default();
+        let i = log.partition_point(|x| x[0] < offset);
+        log.insert(i, [offset, msg]);
+        let sent_at = self.stamp(key, offset);
+        if !self.config.replicate_sends {
+            return Ok(());
+        }
+        for peer in self.node_ids.iter().filter(|&id| *id != self.node_id) {
+            let response = Response {
+                src: &self.node_id,
+                dest: peer,
+                body: ResponseBody::Replicate {
+                    key,
+                    offset,
+                    msg,
+                    sent_at: Some(sent_at),
+                },
+            };
+            out.send(&response)?;
+        }
+        Ok(())
+    }
+
+    /// Records that the message at `offset` in `key` was sent just now, and
+    /// returns when that was.
+    fn stamp(&mut self, key: &str, offset: i64) -> u64 {
+        let now = self
+            .clock
+            .system_now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        self.sent_at
+            .entry(key.to_owned())
+            .or_default()
+            .insert(offset, now);
+        now
+    }
+
+    /// Records send times learned from a peer, keeping any already known.
+    fn learn_stamps(&mut self, key: &str, stamps: &[(i64, u64)]) {
+        if stamps.is_empty() {
+            return;
+        }
+        let known = self.sent_at.entry(key.to_owned()).or_default();
+        for &(offset, at) in stamps {
+            known.entry(offset).or_insert(at);
+        }
+    }
+
+    /// The message stored at `offset` in `key`'s log, committed or not.
+    fn message_at(&self, key: &str, offset: i64) -> Option<i64> {
+        let find = |logs: &HashMap<String, Vec<[i64; 2]>>| {
+            let log = logs.get(key)?;
+            let i = log.binary_search_by_key(&offset, |x| x[0]).ok()?;
+            Some(log[i][1])
+        };
+        find(&self.commited_msgs).or_else(|| find(&self.uncommited_msgs))
+    }
+
+    /// The uncommitted messages in `key` a poll may hand out. Unless offsets
+    /// are sparse, only those carrying on from the committed log without a
+    /// hole, so a consumer can't move past offsets another node still holds.
+    fn pollable_uncommitted(&self, key: &str) -> &[[i64; 2]] {
+        let log = self.uncommited_msgs.get(key).unwrap_or(&EMPTY);
+        if self.sparse_offsets() {
+            return log;
+        }
+        let tail = self
+            .commited_msgs
+            .get(key)
+            .and_then(|log| log.last())
+            .map_or(-1, |x| x[0]);
+        let log = &log[log.partition_point(|x| x[0] <= tail)..];
+        let run = log
+            .iter()
+            .zip(tail + 1..)
+            .take_while(|(x, want)| x[0] == *want)
+            .count();
+        &log[..run]
+    }
+
+    /// `key`'s pollable messages at `offset` or later, committed or not,
+    /// sorted with one per offset. The committed message wins a clash.
+    /// Reaching below what compaction kept reads the snapshot file.
+    fn messages_from(&self, key: &str, offset: i64) -> Vec<[i64; 2]> {
+        let from = |log: &[[i64; 2]]| log[log.partition_point(|x| x[0] < offset)..].to_vec();
+        let mut msgs = from(self.commited_msgs.get(key).unwrap_or(&EMPTY));
+        if self
+            .compacted_through
+            .get(key)
+            .is_some_and(|&through| offset <= through)
+        {
+            merge_into(&mut msgs, &self.snapshot_messages(key, offset));
+        }
+        merge_into(&mut msgs, &from(self.pollable_uncommitted(key)));
+        msgs
+    }
+}
+
+static EMPTY: Vec<[i64; 2]> = Vec::new();
+
+/// The highest offset a message can be stored at, so the next offset after
+/// any stored message still fits in an `i64`.
+const MAX_OFFSET: i64 = i64::MAX - 1;
+
+/// How many versions of the committed offsets are kept for debugging.
+const OFFSET_SNAPSHOTS: usize = 16;
+
+/// The lin-kv key holding the last offset allocated for `key`. Shared by
+/// every node, unlike the persisted state.
+fn counter_kv_key(key: &str) -> String {
+    format!("offset-counter/{key}")
+}
+
+/// The number in a node id such as "n3", or `None` if it isn't one.
+fn node_number(node_id: &str) -> Option<i64> {
+    node_id.strip_prefix('n')?.parse().ok()
+}
+
+/// Inclusive offset ranges missing between consecutive entries of a sorted log.
+fn gaps(log: &[[i64; 2]]) -> Vec<(i64, i64)> {
+    log.windows(2)
+        .filter(|pair| pair[1][0] > pair[0][0] + 1)
+        .map(|pair| (pair[0][0] + 1, pair[1][0] - 1))
+        .collect()
+}
+
+/// The prefix of `log` with no missing offsets, so a poll never hands out
+/// messages past a hole that is still being repaired.
+fn contiguous(log: &[[i64; 2]]) -> &[[i64; 2]] {
+    let end = log
+        .windows(2)
+        .position(|pair| pair[1][0] > pair[0][0] + 1)
+        .map_or(log.len(), |i| i + 1);
+    &log[..end]
+}
+
+/// Merges `msgs` into a log, keeping it sorted with one entry per offset.
+/// Where `msgs` has a different message at an offset the log already holds,
+/// the log's stays; those offsets are returned.
+fn merge_into(log: &mut Vec<[i64; 2]>, msgs: &[[i64; 2]]) -> Vec<i64> {
+    log.extend_from_slice(msgs);
+    // Stable, so the log's own entry comes first at each offset.
+    log.sort_by(|a, b| a[0].cmp(&b[0]));
+    let conflicts = log
+        .windows(2)
+        .filter(|pair| pair[0][0] == pair[1][0] && pair[0][1] != pair[1][1])
+        .map(|pair| pair[0][0])
+        .collect();
+    log.dedup_by_key(|x| x[0]);
+    conflicts
+}
+
+/// The send times known for the messages in `msgs`.
+fn stamps_of(
+    sent_at: &HashMap<String, BTreeMap<i64, u64>>,
+    key: &str,
+    msgs: &[[i64; 2]],
+) -> Vec<(i64, u64)> {
+    let Some(known) = sent_at.get(key) else {
+        return Vec::new();
+    };
+    msgs.iter()
+        .filter_map(|x| known.get(&x[0]).map(|&at| (x[0], at)))
+        .collect()
+}
+
+/// `stamps_of` for each key of `updates` that has any.
+fn stamps_for<'a>(
+    sent_at: &HashMap<String, BTreeMap<i64, u64>>,
+    updates: &HashMap<&'a str, &[[i64; 2]]>,
+) -> Stamps<'a> {
+    updates
+        .iter()
+        .map(|(&k, msgs)| (k, stamps_of(sent_at, k, msgs)))
+        .filter(|(_, stamps)| !stamps.is_empty())
+        .collect()
+}
+
+/// Logs the offsets of `key` where `src` had a different message than the
+/// one kept, which only happens when two nodes allocated the same offset.
+fn warn_conflicts(key: &str, src: &str, conflicts: &[i64]) {
+    if !conflicts.is_empty() {
+        eprintln!("{src} has other messages for {key} at offsets {conflicts:?}; keeping ours");
+    }
+}
+
+/// A `poll` offset meaning "only messages after the current tail", for
+/// consumers that want to start from new messages only. It is answered with
+/// no messages and the tail in `poll_ok`'s `latest`, which a cursor also
+/// stores as its position.
+const LATEST_OFFSET: i64 = -1;
+
+/// How often the runtime wakes up without input to retry `sync`s.
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+
+fn main() -> anyhow::Result<()> {
+    let config = Config::from_env();
+    let runtime = Runtime {
+        abort_on_parse_error: config.abort_on_parse_error,
+        trace: config.trace.as_deref().and_then(|level| level.parse().ok()),
+        ..Default::default()
+    };
+    let mut node = Node {
+        config,
+        ..Default::default()
+    };
+    runtime.run(&mut node)
+}
+
+impl maelstrom_core::Node for Node {
+    type Body<'a> = RequestBody<'a>;
+
+    fn timeout(&self) -> Duration {
+        TICK_INTERVAL
+    }
+
+    fn tick(&mut self, out: &mut Output) -> anyhow::Result<()> {
+        self.retry_syncs(out)?;
+        self.flush_throttled_offsets(out)?;
+        Ok(())
+    }
+
+    fn handle(&mut self, request: Request<'_>, out: &mut Output) -> anyhow::Result<()> {
+        let envelope = request.envelope();
+        // Replies to lin-kv requests go to the continuation they registered.
+        if let RequestBody::ReadOk { in_reply_to, .. }
+        | RequestBody::WriteOk { in_reply_to }
+        | RequestBody::CasOk { in_reply_to }
+        | RequestBody::Error { in_reply_to, .. } = request.body
+        {
+            if self.lin_kv.get(in_reply_to).is_some() {
+                let reply = match request.body {
+                    RequestBody::ReadOk { value, .. } => KvReply::ReadOk(value),
+                    RequestBody::WriteOk { .. } => KvReply::WriteOk,
+                    RequestBody::Error {
+                        code,
+                        text,
+                        details,
+                        ..
+                    } => KvReply::Error {
+                        code,
+                        text,
+                        details,
+                    },
+                    _ => KvReply::CasOk,
+                };
+                let (op, reply) = self.lin_kv.dispatch(in_reply_to, reply).unwrap();
+                return self.on_lin_kv_reply(op, reply, out);
+            }
+        }
+
+        let response_body = match request.body {
+            RequestBody::Init {
+                msg_id,
+                ref node_id,
+                ..
+            } if node_number(node_id).is_none() => ResponseBody::Error {
+                in_reply_to: msg_id,
+                code: 12,
+                text: "node ids must be n followed by a number",
+                details: error_details(serde_json::json!({ "node_id": node_id })),
+            },
+            RequestBody::Init {
+                msg_id,
+                node_id,
+                node_ids,
+            } => {
+                // An init repeating this node's id, after state has been
+                // recovered or sends taken, only updates the membership:
+                // the logs stay and outstanding lin-kv requests, recovery
+                // reads included, still get their replies handled.
+                let repeated = self.node_id == node_id;
+                self.node_id_i64 = node_number(&node_id).expect("checked by the arm above");
+                self.node_id = node_id;
+                self.node_ids = node_ids;
+                if !repeated {
+                    let clock = &self.clock;
+                    let budget = |per_sec| RetryBudget::new(per_sec, clock.clone());
+                    self.retry_budget = self.config.retry_budget.map(budget);
+                    self.lin_kv = KvClient::new("lin-kv", self.node_id.clone(), clock.clone());
+                    if self.config.persist {
+                        let key = self.keys_kv_key();
+                        self.recover(&key, LinKvOp::Keys, out)?;
+                    }
+                }
+                ResponseBody::InitOk {
+                    in_reply_to: msg_id,
+                }
+            }
+            RequestBody::Topology { msg_id, topology } => {
+                self.topology = topology.get(self.node_id.as_str()).unwrap().clone();
+                ResponseBody::TopologyOk {
+                    in_reply_to: msg_id,
+                }
+            }
+            RequestBody::Send {
+                msg_id,
+                key,
+                msg,
+                offset,
+            } if self.remote_owner(key).is_some() => {
+                let owner = self.remote_owner(key).unwrap();
+                let forward_id = self.next_msg_id();
+                self.forwarded_sends
+                    .insert(forward_id, (request.src.to_owned(), msg_id));
+                let response = Response {
+                    src: request.dest,
+                    dest: &owner,
+                    body: ResponseBody::Send {
+                        msg_id: forward_id,
+                        key,
+                        msg,
+                        offset,
+                    },
+                };
+                out.send(&response)?;
+                return Ok(());
+            }
+            RequestBody::SendOk {
+                in_reply_to,
+                offset,
+            } => {
+                let Some((client, msg_id)) = self.forwarded_sends.remove(&in_reply_to) else {
+                    return Ok(());
+                };
+                let response = Response {
+                    src: &self.node_id,
+                    dest: &client,
+                    body: ResponseBody::SendOk {
+                        in_reply_to: msg_id,
+                        offset,
+                    },
+                };
+                out.send(&response)?;
+                return Ok(());
+            }
+            RequestBody::Send {
+                msg_id,
+                offset: Some(offset),
+                ..
+            } if !(0..=MAX_OFFSET).contains(&offset) => ResponseBody::Error {
+                in_reply_to: msg_id,
+                code: 12,
+                text: "offset out of range",
+                details: error_details(serde_json::json!({ "offset": offset, "max": MAX_OFFSET })),
+            },
+            RequestBody::Send {
+                msg_id,
+                key,
+                msg,
+                offset: Some(offset),
+            } => match self.message_at(key, offset) {
+                Some(existing) if existing != msg => ResponseBody::Error {
+                    in_reply_to: msg_id,
+                    code: 22,
+                    text: "a different message is already stored at that offset",
+                    details: error_details(serde_json::json!({
+                        "key": key,
+                        "offset": offset,
+                        "existing": existing,
+                    })),
+                },
+                Some(_) => ResponseBody::SendOk {
+                    in_reply_to: msg_id,
+                    offset,
+                },
+                None => {
+                    self.store_send(key, offset, msg, out)?;
+                    ResponseBody::SendOk {
+                        in_reply_to: msg_id,
+                        offset,
+                    }
+                }
+            },
+            RequestBody::Send {
+                msg_id, key, msg, ..
+            } if self.config.offset_allocation == OffsetAllocation::LinKv => {
+                let send = self.next_msg_id();
+                let pending = PendingSend {
+                    client: request.src.to_owned(),
+                    msg_id,
+                    key: key.to_owned(),
+                    msg,
+                    offset: 0,
+                };
+                self.pending_sends.insert(send, pending);
+                // Our own tail is a good guess at the counter; a wrong one
+                // costs a read.
+                let last = self.tail_offset(key).unwrap_or(-1);
+                self.allocate_offset(send, last, out)?;
+                return Ok(());
+            }
+            RequestBody::Send {
+                msg_id, key, msg, ..
+            } => {
+                let offset = self.next_offset(key);
+                if offset > MAX_OFFSET {
+                    ResponseBody::Error {
+                        in_reply_to: msg_id,
+                        code: 14,
+                        text: "no offsets left for this key",
+                        details: error_details(serde_json::json!({ "key": key })),
+                    }
+                } else {
+                    self.store_send(key, offset, msg, out)?;
+                    ResponseBody::SendOk {
+                        in_reply_to: msg_id,
+                        offset,
+                    }
+                }
+            }
+            RequestBody::Poll {
+                msg_id,
+                offsets,
+                cursor,
+                prefix,
+            } => {
+                let implicit = cursor.is_none() && self.config.poll_mode == PollMode::AutoAdvance;
+                let cursor = match cursor {
+                    Some(token) => Some(self.open_cursor(token)),
+                    None if implicit => {
+                        let token = format!("{}/auto", request.src);
+                        self.cursors.entry(token.clone()).or_default();
+                        Some(token)
+                    }
+                    None => None,
+                };
+                let positions = cursor.as_ref().map(|token| &self.cursors[token]);
+                let sparse = self.sparse_offsets();
+                let mut offsets: HashMap<&str, i64> = offsets;
+                if let Some(prefix) = &prefix {
+                    let known = self.commited_msgs.keys().chain(self.uncommited_msgs.keys());
+                    for k in known.filter(|k| k.starts_with(prefix.as_str())) {
+                        offsets.entry(k).or_insert(0);
+                    }
+                }
+                let mut keys: Vec<&str> = offsets.keys().copied().collect();
+                let mut more = Vec::new();
+                if let Some(max) = self.config.poll_max_keys.filter(|&max| keys.len() > max) {
+                    keys.sort_unstable();
+                    more = keys.split_off(max);
+                }
+                let polled: Vec<_> = keys
+                    .iter()
+                    .map(|&k| {
+                        let v = positions
+                            .and_then(|p| p.get(k))
+                            .copied()
+                            .unwrap_or(offsets[k]);
+                        let last = |log: &[[i64; 2]]| log.last().map(|x| x[0] + 1);
+                        if v == LATEST_OFFSET {
+                            let committed = self.commited_msgs.get(k).unwrap_or(&EMPTY);
+                            let next = last(committed).max(last(self.pollable_uncommitted(k)));
+                            return (k, None, next.unwrap_or(0));
+                        }
+                        let mut msgs = self.messages_from(k, v);
+                        let len = if sparse {
+                            msgs.len()
+                        } else {
+                            contiguous(&msgs).len()
+                        };
+                        msgs.truncate(self.config.poll_batch(len));
+                        let next = last(&msgs).unwrap_or(v);
+                        (k, Some(msgs), next)
+                    })
+                    .collect();
+                if let Some(token) = &cursor {
+                    let positions = self.cursors.get_mut(token).unwrap();
+                    for &(k, _, next) in &polled {
+                        positions.insert(k.to_owned(), next);
+                    }
+                }
+                let latest = polled
+                    .iter()
+                    .filter(|(_, msgs, _)| msgs.is_none())
+                    .map(|&(k, _, next)| (k, next))
+                    .collect();
+                ResponseBody::PollOk {
+                    in_reply_to: msg_id,
+                    msgs: polled
+                        .into_iter()
+                        .map(|(k, msgs, _)| (k, msgs.unwrap_or_default()))
+                        .collect(),
+                    cursor: cursor.filter(|_| !implicit),
+                    more,
+                    latest,
+                }
+            }
+            RequestBody::CommitOffsets { msg_id, .. }
+                if self
+                    .config
+                    .max_pending_syncs
+                    .is_some_and(|max| self.pending_syncs() >= max) =>
+            {
+                self.shed_commits += 1;
+                ResponseBody::Error {
+                    in_reply_to: msg_id,
+                    code: 11,
+                    text: "too many commits in flight",
+                    details: error_details(serde_json::json!({
+                        "pending": self.pending_syncs(),
+                        "shed": self.shed_commits,
+                    })),
+                }
+            }
+            RequestBody::CommitOffsets {
+                msg_id,
+                mut offsets,
+                expected,
+                reset,
+            } => {
+                let failed: Vec<String> = offsets
+                    .keys()
+                    .filter(|&&k| {
+                        expected.get(k).is_some_and(|&want| {
+                            self.commited_offsets.get(k).copied().unwrap_or(-1) != want
+                        })
+                    })
+                    .map(|&k| k.to_owned())
+                    .collect();
+                offsets.retain(|k, _| !failed.iter().any(|f| f == k));
+                if offsets.is_empty() && !failed.is_empty() {
+                    ResponseBody::CommitOffsetsOk {
+                        in_reply_to: msg_id,
+                        failed,
+                    }
+                } else if self.config.strict_commits
+                    && offsets
+                        .iter()
+                        .any(|(&k, &v)| self.tail_offset(k).is_none_or(|tail| v > tail))
+                {
+                    ResponseBody::Error {
+                        in_reply_to: msg_id,
+                        code: 14,
+                        text: "commit offset is beyond the end of the log",
+                        details: Default::default(),
+                    }
+                } else {
+                    let keys: Vec<String> = offsets.keys().map(|&k| k.to_owned()).collect();
+                    // Throttling keeps the highest offset, which a reset
+                    // mustn't be coalesced into.
+                    if !reset {
+                        self.throttle_commit(&mut offsets);
+                    }
+                    let throttled: HashSet<String> = keys
+                        .into_iter()
+                        .filter(|k| !offsets.contains_key(k.as_str()))
+                        .collect();
+                    if offsets.is_empty() && throttled.is_empty() {
+                        ResponseBody::CommitOffsetsOk {
+                            in_reply_to: msg_id,
+                            failed,
+                        }
+                    } else {
+                        let sync_id = self.next_msg_id();
+                        if throttled.is_empty() {
+                            self.pending_commits
+                                .insert(sync_id, (request.src.to_owned(), msg_id));
+                            if !failed.is_empty() {
+                                self.commit_failures.insert(sync_id, failed);
+                            }
+                        } else {
+                            // Acked only once the throttled keys have
+                            // synced too.
+                            let syncs = (!offsets.is_empty()).then_some(sync_id);
+                            self.held_commits.push(HeldCommit {
+                                client: request.src.to_owned(),
+                                msg_id,
+                                failed,
+                                throttled,
+                                syncs: syncs.into_iter().collect(),
+                            });
+                        }
+                        if !offsets.is_empty() {
+                            let generations = self.generations_of(&offsets, reset);
+                            self.gather_updates(sync_id, &offsets, generations, out)?;
+                        }
+                        return Ok(());
+                    }
+                }
+            }
+            RequestBody::Watermarks { msg_id, keys } => ResponseBody::WatermarksOk {
+                in_reply_to: msg_id,
+                watermarks: keys
+                    .iter()
+                    .filter_map(|&k| self.watermark(k).map(|w| (k, w)))
+                    .collect(),
+            },
+            RequestBody::ListCommittedOffsets {
+                msg_id,
+                keys,
+                version,
+            } => {
+                let (version, offsets) = match version.and_then(|v| self.offsets_at(v)) {
+                    Some((v, offsets)) => (Some(*v), offsets),
+                    None => (
+                        version.map(|_| self.offsets_version),
+                        &self.commited_offsets,
+                    ),
+                };
+                ResponseBody::ListCommittedOffsetsOk {
+                    in_reply_to: msg_id,
+                    offsets: keys
+                        .iter()
+                        .filter_map(|&k| offsets.get(k).map(|&v| (k, v)))
+                        .collect(),
+                    version,
+                }
+            }
+            // Replies to lin-kv requests were dispatched above; these are
+            // strays.
+            RequestBody::ReadOk { .. }
+            | RequestBody::WriteOk { .. }
+            | RequestBody::CasOk { .. } => return Ok(()),
+            RequestBody::Error {
+                in_reply_to,
+                code,
+                text,
+                details,
+            } if self.forwarded_sends.contains_key(&in_reply_to) => {
+                let (client, msg_id) = self.forwarded_sends.remove(&in_reply_to).unwrap();
+                let response = Response {
+                    src: &self.node_id,
+                    dest: &client,
+                    body: ResponseBody::Error {
+                        in_reply_to: msg_id,
+                        code,
+                        text,
+                        details,
+                    },
+                };
+                out.send(&response)?;
+                return Ok(());
+            }
+            RequestBody::Error { .. } => ResponseBody::Error {
+                in_reply_to: 0,
+                code: 10,
+                text: "Boo Not Supported",
+                details: Default::default(),
+            },
+            RequestBody::GetUpdates { msg_id, offsets } => {
+                let msgs = offsets
+                    .iter()
+                    .map(|(&k, v)| {
+                        let logs = self.uncommited_msgs.get(k).unwrap_or(&EMPTY);
+                        (k, &logs[..logs.partition_point(|probe| probe[0] <= *v)])
+                    })
+                    .collect();
+                ResponseBody::GetUpdatesOk {
+                    in_reply_to: msg_id,
+                    stamps: stamps_for(&self.sent_at, &msgs),
+                    updates: msgs,
+                }
+            }
+            RequestBody::Sync {
+                msg_id,
+                mut offsets,
+                updates,
+                generations,
+                stamps,
+            } => {
+                // A sync that arrives after a newer one from the same peer
+                // carries stale commit points for the keys they share; skip
+                // those. Its messages still merge, which is idempotent.
+                let applied = self
+                    .applied_syncs
+                    .entry(request.src.to_owned())
+                    .or_default();
+                offsets.retain(|&k, _| applied.get(k).is_none_or(|&newest| msg_id >= newest));
+                for &k in offsets.keys() {
+                    applied.insert(k.to_owned(), msg_id);
+                }
+
+                let mut offsets_changed = false;
+                offsets.iter().for_each(|(&k, &v)| {
+                    if let Some(logs) = self.uncommited_msgs.get_mut(k) {
+                        let partition_point = logs.partition_point(|probe| probe[0] <= v);
+                        logs.drain(..partition_point);
+                    }
+                    let generation = generations.get(k).copied().unwrap_or(0);
+                    let current = self.offset_generations.get(k).copied().unwrap_or(0);
+                    let newer = self
+                        .commited_offsets
+                        .get(k)
+                        .is_none_or(|&x| (generation, v) > (current, x));
+                    if newer {
+                        self.commited_offsets.insert(k.to_owned(), v);
+                        if generation > 0 {
+                            self.offset_generations.insert(k.to_owned(), generation);
+                        }
+                        offsets_changed = true;
+                    }
+                });
+                if offsets_changed {
+                    self.snapshot_offsets();
+                }
+
+                let touched: HashSet<&str> =
+                    offsets.keys().chain(updates.keys()).copied().collect();
+                let sparse = self.sparse_offsets();
+                for (&k, v) in &updates {
+                    let stuff = self.commited_msgs.entry(k.to_owned()).or_default();
+                    warn_conflicts(k, request.src, &merge_into(stuff, v));
+                    // Before looking for gaps, so nothing already compacted
+                    // is fetched again.
+                    self.compact(k)?;
+                    let stuff = &self.commited_msgs[k];
+                    let gaps = if sparse { Vec::new() } else { gaps(stuff) };
+                    for (from, to) in gaps {
+                        let response = envelope.reply(ResponseBody::FetchRange {
+                            msg_id: self.next_msg_id(),
+                            key: k,
+                            from,
+                            to,
+                        });
+                        out.send(&response)?;
+                    }
+                }
+
+                for (&k, stamps) in &stamps {
+                    if updates.contains_key(k) {
+                        self.learn_stamps(k, stamps);
+                    }
+                }
+                for k in touched {
+                    self.compact(k)?;
+                    self.persist(k, out)?;
+                }
+
+                ResponseBody::SyncOk {
+                    in_reply_to: msg_id,
+                }
+            }
+            RequestBody::FetchRange {
+                msg_id,
+                key,
+                from,
+                to,
+            } => {
+                let in_range = |x: &&[i64; 2]| (from..=to).contains(&x[0]);
+                let msgs = [&self.commited_msgs, &self.uncommited_msgs]
+                    .iter()
+                    .filter_map(|logs| logs.get(key))
+                    .flat_map(|log| log.iter().filter(in_range).copied())
+                    .collect::<Vec<_>>();
+                ResponseBody::FetchRangeOk {
+                    in_reply_to: msg_id,
+                    key,
+                    stamps: stamps_of(&self.sent_at, key, &msgs),
+                    msgs,
+                }
+            }
+            RequestBody::Replicate {
+                key,
+                offset,
+                msg,
+                sent_at,
+            } => {
+                // The first message stored at an offset wins, as in `send`.
+                let committed = self.commited_offsets.get(key).is_some_and(|&c| offset <= c);
+                if !committed && self.message_at(key, offset).is_none() {
+                    let log = self.uncommited_msgs.entry(key.to_owned()).or_default();
+                    let i = log.partition_point(|x| x[0] < offset);
+                    log.insert(i, [offset, msg]);
+                    if let Some(sent_at) = sent_at {
+                        self.learn_stamps(key, &[(offset, sent_at)]);
+                    }
+                }
+                return Ok(());
+            }
+            RequestBody::PollSince {
+                msg_id,
+                keys,
+                since,
+            } => {
+                let msgs = keys
+                    .into_iter()
+                    .map(|k| {
+                        let stamps = self.sent_at.get(k);
+                        let newer = |offset: &i64| {
+                            stamps
+                                .and_then(|s| s.get(offset))
+                                .is_some_and(|&t| t > since)
+                        };
+                        let log = self.commited_msgs.get(k).unwrap_or(&EMPTY);
+                        (k, log.iter().filter(|x| newer(&x[0])).copied().collect())
+                    })
+                    .collect();
+                ResponseBody::PollSinceOk {
+                    in_reply_to: msg_id,
+                    msgs,
+                }
+            }
+            RequestBody::ReadUncommitted { msg_id, key } => {
+                if self.config.debug {
+                    ResponseBody::ReadUncommittedOk {
+                        in_reply_to: msg_id,
+                        msgs: self.uncommited_msgs.get(key).unwrap_or(&EMPTY),
+                    }
+                } else {
+                    ResponseBody::Error {
+                        in_reply_to: msg_id,
+                        code: 10,
+                        text: "read_uncommitted requires KAFKA_DEBUG",
+                        details: Default::default(),
+                    }
+                }
+            }
+            RequestBody::DumpConfig { msg_id } => ResponseBody::DumpConfigOk {
+                in_reply_to: msg_id,
+                config: serde_json::to_value(&self.config)?,
+            },
+            RequestBody::FetchRangeOk { key, msgs, stamps } => {
+                let log = self.commited_msgs.entry(key.to_owned()).or_default();
+                warn_conflicts(key, request.src, &merge_into(log, &msgs));
+                self.learn_stamps(key, &stamps);
+                self.persist(key, out)?;
+                return Ok(());
+            }
+            RequestBody::SyncOk { in_reply_to } => {
+                self.ack_sync(in_reply_to, request.src);
+                // Retried syncs can be acked twice, and late acks can arrive
+                // after the commit has completed.
+                let Some(waiting) = self.ongoing_syncs.get_mut(&in_reply_to) else {
+                    return Ok(());
+                };
+                if !waiting.remove(request.src) || !waiting.is_empty() {
+                    return Ok(());
+                }
+                self.ongoing_syncs.remove(&in_reply_to);
+                self.release_held_commits(in_reply_to, out)?;
+                let failed = self
+                    .commit_failures
+                    .remove(&in_reply_to)
+                    .unwrap_or_default();
+                let Some((client, msg_id)) = self.pending_commits.remove(&in_reply_to) else {
+                    return Ok(());
+                };
+                let response = Response {
+                    src: &self.node_id,
+                    dest: &client,
+                    body: ResponseBody::CommitOffsetsOk {
+                        in_reply_to: msg_id,
+                        failed,
+                    },
+                };
+                out.send(&response)?;
+                return Ok(());
+            }
+            RequestBody::GetUpdatesOk {
+                in_reply_to,
+                updates,
+                stamps,
+            } => {
+                let Some(gathering) = self.gathering.get_mut(&in_reply_to) else {
+                    return Ok(());
+                };
+                if !gathering.waiting.remove(request.src) {
+                    return Ok(());
+                }
+                for (k, msgs) in updates {
+                    let log = gathering.updates.entry(k.to_owned()).or_default();
+                    warn_conflicts(k, request.src, &merge_into(log, &msgs));
+                }
+                let done = gathering.waiting.is_empty();
+                for (k, stamps) in &stamps {
+                    self.learn_stamps(k, stamps);
+                }
+                if !done {
+                    return Ok(());
+                }
+                let gathering = self.gathering.remove(&in_reply_to).unwrap();
+                let offsets: HashMap<&str, i64> = gathering
+                    .offsets
+                    .iter()
+                    .map(|(k, &v)| (k.as_str(), v))
+                    .collect();
+                let updates: HashMap<&str, &[[i64; 2]]> = gathering
+                    .updates
+                    .iter()
+                    .map(|(k, v)| (k.as_str(), v.as_slice()))
+                    .collect();
+                let generations: HashMap<&str, u64> = gathering
+                    .generations
+                    .iter()
+                    .map(|(k, &g)| (k.as_str(), g))
+                    .collect();
+                for node_id in &self.node_ids {
+                    let response = Response {
+                        src: request.dest,
+                        dest: node_id,
+                        body: ResponseBody::Sync {
+                            msg_id: in_reply_to,
+                            offsets: &offsets,
+                            updates: &updates,
+                            generations: &generations,
+                            stamps: stamps_for(&self.sent_at, &updates),
+                        },
+                    };
+                    out.send(&response)?;
+                }
+                let peers = self.node_ids.clone();
+                self.track_sync(in_reply_to, &offsets, &updates, &generations, &peers);
+                self.ongoing_syncs
+                    .insert(in_reply_to, peers.into_iter().collect());
+                return Ok(());
+            }
+        };
+        out.send(&envelope.reply(response_body))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maelstrom_core::testing::{Cluster, Fate, TestNode};
+    use serde_json::{json, Value};
+
+    /// An initialised `id` in a cluster of `ids`, on a manual clock.
+    fn test_node(id: &str, ids: &[&str], config: Config) -> TestNode<Node> {
+        let node = Node {
+            config,
+            clock: Clock::manual(),
+            ..Default::default()
+        };
+        TestNode::new(node, id, ids)
+    }
+
+    /// Initialised nodes `ids`.
+    fn cluster(ids: &[&str], config: impl Fn() -> Config) -> Cluster<Node> {
+        Cluster::new(ids, |clock| Node {
+            config: config(),
+            clock,
+            ..Default::default()
+        })
+    }
+
+    fn send(node: &mut TestNode<Node>, key: &str, msg: i64) -> Value {
+        node.request(json!({"type": "send", "msg_id": 1, "key": key, "msg": msg}))
+    }
+
+    fn poll(node: &mut TestNode<Node>, offsets: Value) -> Value {
+        node.request(json!({"type": "poll", "msg_id": 1, "offsets": offsets}))
+    }
+
+    /// Starts a commit of `offsets` on `node` and answers its `get_updates`
+    /// from every node, returning the `sync`s sent.
+    fn commit_until_sync(node: &mut TestNode<Node>, ids: &[&str], offsets: Value) -> Vec<Value> {
+        let commit = json!({"type": "commit_offsets", "msg_id": 100, "offsets": offsets});
+        let sent = node.handle("c1", commit);
+        let gather_id = sent[0]["body"]["msg_id"].clone();
+        let mut sent = Vec::new();
+        for &peer in ids {
+            let reply = json!({"type": "get_updates_ok", "in_reply_to": gather_id, "updates": {}});
+            sent = node.handle(peer, reply);
+        }
+        sent
+    }
+
+    #[test]
+    fn poll_at_the_latest_offset_returns_the_tail() {
+        let mut n1 = test_node("n1", &["n1"], Config::default());
+        send(&mut n1, "k", 10);
+        send(&mut n1, "k", 11);
+        let reply = poll(&mut n1, json!({"k": LATEST_OFFSET}));
+        assert_eq!(reply["msgs"], json!({"k": []}));
+        let tail = reply["latest"]["k"].clone();
+        assert_eq!(tail, 2);
+        send(&mut n1, "k", 12);
+        let reply = poll(&mut n1, json!({"k": tail}));
+        assert_eq!(reply["msgs"]["k"].as_array().unwrap().len(), 1);
+        assert_eq!(reply["msgs"]["k"][0][1], 12);
+        assert!(reply.get("latest").is_none());
+    }
+
+    #[test]
+    fn merge_into_keeps_its_own_message_on_a_conflict() {
+        let mut log = vec![[0, 10], [1, 11]];
+        let conflicts = merge_into(&mut log, &[[1, 21], [2, 12], [0, 10]]);
+        assert_eq!(log, [[0, 10], [1, 11], [2, 12]]);
+        assert_eq!(conflicts, [1]);
+    }
+
+    #[test]
+    fn poll_since_finds_messages_sent_through_another_node() {
+        let mut cluster = cluster(&["n1", "n2"], Config::default);
+        cluster.request(
+            "n1",
+            json!({"type": "send", "msg_id": 1, "key": "k", "msg": 5}),
+        );
+        let commit = json!({"type": "commit_offsets", "msg_id": 2, "offsets": {"k": 0}});
+        cluster.request("n1", commit);
+        let poll = json!({"type": "poll_since", "msg_id": 3, "keys": ["k"], "since": 0});
+        let replies = cluster.request("n2", poll);
+        assert_eq!(replies[0]["msgs"], json!({"k": [[0, 5]]}));
+    }
+
+    #[test]
+    fn send_at_an_explicit_offset_conflicts_only_with_a_different_message() {
+        // Interleaved logs may have holes, so offset 5 is pollable alone.
+        let config = Config {
+            offset_allocation: OffsetAllocation::Interleaved,
+            ..Default::default()
+        };
+        let mut n1 = test_node("n1", &["n1"], config);
+        let send = |msg| json!({"type": "send", "msg_id": 1, "key": "k", "msg": msg, "offset": 5});
+        let reply = n1.request(send(10));
+        assert_eq!(
+            (&reply["type"], &reply["offset"]),
+            (&json!("send_ok"), &json!(5))
+        );
+        let reply = n1.request(send(10));
+        assert_eq!(
+            (&reply["type"], &reply["offset"]),
+            (&json!("send_ok"), &json!(5))
+        );
+        let reply = n1.request(send(11));
+        assert_eq!(reply["code"], 22);
+        assert_eq!(reply["details"]["existing"], 10);
+        let reply = poll(&mut n1, json!({"k": 5}));
+        assert_eq!(reply["msgs"], json!({"k": [[5, 10]]}));
+    }
+
+    #[test]
+    fn conditional_commit_reports_the_keys_that_failed() {
+        let mut cluster = cluster(&["n1"], Config::default);
+        let commit = json!({"type": "commit_offsets", "msg_id": 1, "offsets": {"a": 0}});
+        cluster.request("n1", commit);
+        let commit = json!({
+            "type": "commit_offsets",
+            "msg_id": 2,
+            "offsets": {"a": 1, "b": 1},
+            "expected": {"a": 0, "b": 3},
+        });
+        let replies = cluster.request("n1", commit);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0]["type"], "commit_offsets_ok");
+        assert_eq!(replies[0]["failed"], json!(["b"]));
+        let list = json!({"type": "list_committed_offsets", "msg_id": 3, "keys": ["a", "b"]});
+        let replies = cluster.request("n1", list);
+        assert_eq!(replies[0]["offsets"], json!({"a": 1}));
+    }
+
+    #[test]
+    fn gathered_updates_are_synced_to_every_node() {
+        let mut n1 = test_node("n1", &["n1", "n2", "n3"], Config::default());
+        let commit = json!({"type": "commit_offsets", "msg_id": 1, "offsets": {"k": 1}});
+        let sent = n1.handle("c1", commit);
+        assert_eq!(sent.len(), 3);
+        let gather_id = sent[0]["body"]["msg_id"].clone();
+        let updates = [
+            ("n1", json!({"k": [[0, 10]]})),
+            ("n2", json!({"k": [[1, 11]]})),
+            ("n3", json!({})),
+        ];
+        let mut sent = Vec::new();
+        for (peer, updates) in updates {
+            assert!(sent.is_empty(), "synced before {peer} answered");
+            let reply =
+                json!({"type": "get_updates_ok", "in_reply_to": gather_id, "updates": updates});
+            sent = n1.handle(peer, reply);
+        }
+        let dests: Vec<&Value> = sent.iter().map(|msg| &msg["dest"]).collect();
+        assert_eq!(dests, ["n1", "n2", "n3"]);
+        for msg in &sent {
+            let sync = &msg["body"];
+            assert_eq!(sync["type"], "sync");
+            assert_eq!(sync["msg_id"], gather_id);
+            assert_eq!(sync["offsets"], json!({"k": 1}));
+            assert_eq!(sync["updates"], json!({"k": [[0, 10], [1, 11]]}));
+        }
+        assert_eq!(n1.node.ongoing_syncs[&gather_id.as_i64().unwrap()].len(), 3);
+    }
+
+    #[test]
+    fn commit_is_acked_once_every_node_has_synced() {
+        let ids = ["n1", "n2", "n3"];
+        let mut n1 = test_node("n1", &ids, Config::default());
+        let syncs = commit_until_sync(&mut n1, &ids, json!({"k": 0}));
+        let sync_id = syncs[0]["body"]["msg_id"].clone();
+        let ack = json!({"type": "sync_ok", "in_reply_to": sync_id});
+        assert!(n1.handle("n2", ack.clone()).is_empty());
+        assert!(n1.handle("n1", ack.clone()).is_empty());
+        // A repeated ack doesn't count twice.
+        assert!(n1.handle("n2", ack.clone()).is_empty());
+        let sent = n1.handle("n3", ack);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0]["body"]["type"], "commit_offsets_ok");
+        assert!(n1.node.ongoing_syncs.is_empty());
+    }
+
+    #[test]
+    fn commit_ack_goes_to_the_client() {
+        let ids = ["n1", "n2"];
+        let mut n1 = test_node("n1", &ids, Config::default());
+        let syncs = commit_until_sync(&mut n1, &ids, json!({"k": 0}));
+        let sync_id = syncs[0]["body"]["msg_id"].clone();
+        let ack = json!({"type": "sync_ok", "in_reply_to": sync_id});
+        n1.handle("n1", ack.clone());
+        let sent = n1.handle("n2", ack);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0]["src"], "n1");
+        assert_eq!(sent[0]["dest"], "c1");
+        assert_eq!(sent[0]["body"]["type"], "commit_offsets_ok");
+        assert_eq!(sent[0]["body"]["in_reply_to"], 100);
+    }
+
+    #[test]
+    fn a_late_older_sync_keeps_the_newer_commit_and_adds_its_messages() {
+        let mut n1 = test_node("n1", &["n1", "n2"], Config::default());
+        let sync = |msg_id, offset, updates| json!({"type": "sync", "msg_id": msg_id, "offsets": {"k": offset}, "updates": {"k": updates}});
+        n1.handle("n2", sync(7, 5, json!([[4, 14], [5, 15]])));
+        let late = sync(5, 3, json!([[0, 10], [1, 11], [2, 12], [3, 13]]));
+        let sent = n1.handle("n2", late);
+        assert_eq!(sent[0]["body"]["type"], "sync_ok");
+        assert_eq!(n1.node.commited_offsets["k"], 5);
+        let offsets: Vec<i64> = n1.node.commited_msgs["k"].iter().map(|m| m[0]).collect();
+        assert_eq!(offsets, [0, 1, 2, 3, 4, 5]);
+        let reply = poll(&mut n1, json!({"k": 0}));
+        assert_eq!(reply["msgs"]["k"].as_array().unwrap().len(), 6);
+    }
+
+    /// The offsets two nodes hand out for sends to one key that each takes
+    /// before hearing of the other's.
+    fn concurrent_send_offsets(offset_allocation: OffsetAllocation) -> Vec<i64> {
+        let mut cluster = cluster(&["n1", "n2"], || Config {
+            offset_allocation,
+            ..Default::default()
+        });
+        for i in 0..6 {
+            let id = ["n1", "n2"][i % 2];
+            cluster.client(
+                id,
+                json!({"type": "send", "msg_id": i, "key": "k", "msg": i}),
+            );
+        }
+        cluster.pump_until(Duration::ZERO, |_| false);
+        cluster
+            .replies
+            .iter()
+            .map(|reply| reply["body"]["offset"].as_i64().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn interleaved_nodes_sending_concurrently_never_repeat_an_offset() {
+        let mut offsets = concurrent_send_offsets(OffsetAllocation::Interleaved);
+        assert_eq!(offsets.len(), 6);
+        offsets.sort_unstable();
+        offsets.dedup();
+        assert_eq!(offsets.len(), 6, "{offsets:?}");
+        // The default makes no such promise.
+        let mut offsets = concurrent_send_offsets(OffsetAllocation::Local);
+        offsets.sort_unstable();
+        offsets.dedup();
+        assert!(offsets.len() < 6, "{offsets:?}");
+    }
+
+    #[test]
+    fn lin_kv_offsets_stay_unique_and_rise_in_ack_order_under_concurrent_sends() {
+        // The two nodes' CASes on the counter race, and the losers retry.
+        let offsets = concurrent_send_offsets(OffsetAllocation::LinKv);
+        assert_eq!(offsets.len(), 6);
+        assert!(offsets.windows(2).all(|w| w[0] < w[1]), "{offsets:?}");
+    }
+
+    #[test]
+    fn list_committed_offsets_leaves_out_keys_never_committed() {
+        let mut cluster = cluster(&["n1"], Config::default);
+        let commit = json!({"type": "commit_offsets", "msg_id": 1, "offsets": {"a": 0, "b": 3}});
+        cluster.request("n1", commit);
+        let keys = ["a", "never", "b", "also-never"];
+        let list = json!({"type": "list_committed_offsets", "msg_id": 2, "keys": keys});
+        let replies = cluster.request("n1", list);
+        // Committed to 0 is still there; never committed is absent.
+        assert_eq!(replies[0]["offsets"], json!({"a": 0, "b": 3}));
+    }
+
+    #[test]
+    fn sends_and_commits_on_one_key_leave_another_alone() {
+        let mut cluster = cluster(&["n1", "n2"], Config::default);
+        let send = |key, msg| json!({"type": "send", "msg_id": 1, "key": key, "msg": msg});
+        cluster.request("n2", send("b", 20));
+        let commit = json!({"type": "commit_offsets", "msg_id": 2, "offsets": {"b": 0}});
+        cluster.request("n2", commit);
+        for msg in 10..13 {
+            cluster.request("n1", send("a", msg));
+        }
+        let commit = json!({"type": "commit_offsets", "msg_id": 3, "offsets": {"a": 2}});
+        cluster.request("n1", commit);
+
+        for id in ["n1", "n2"] {
+            let poll = json!({"type": "poll", "msg_id": 4, "offsets": {"b": 0}});
+            assert_eq!(
+                cluster.request(id, poll)[0]["msgs"],
+                json!({"b": [[0, 20]]})
+            );
+            let list = json!({"type": "list_committed_offsets", "msg_id": 5, "keys": ["a", "b"]});
+            let replies = cluster.request(id, list);
+            assert_eq!(replies[0]["offsets"], json!({"a": 2, "b": 0}));
+        }
+        // b's offsets carry on from its own log, not a's.
+        let replies = cluster.request("n1", send("b", 21));
+        assert_eq!(replies[0]["offset"], 1);
+    }
+
+    #[test]
+    fn compacted_messages_go_to_the_snapshot_and_can_still_be_polled() {
+        let path = std::env::temp_dir().join(format!("kafka-snapshot-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = |snapshot_path| Config {
+            retain_committed: Some(2),
+            snapshot_path,
+            ..Default::default()
+        };
+        let snapshot = Some(path.to_str().unwrap().to_owned());
+        for (snapshot_path, earliest) in [(None, 2), (snapshot, 0)] {
+            let mut cluster = cluster(&["n1"], || config(snapshot_path.clone()));
+            for msg in 10..15 {
+                cluster.request(
+                    "n1",
+                    json!({"type": "send", "msg_id": 1, "key": "k", "msg": msg}),
+                );
+            }
+            let commit = json!({"type": "commit_offsets", "msg_id": 2, "offsets": {"k": 3}});
+            cluster.request("n1", commit);
+            let n1 = &cluster.nodes["n1"].node;
+            let kept: Vec<i64> = n1.commited_msgs["k"].iter().map(|m| m[0]).collect();
+            assert_eq!(kept, [2, 3]);
+
+            let poll = json!({"type": "poll", "msg_id": 3, "offsets": {"k": 0}});
+            let polled = &cluster.request("n1", poll)[0]["msgs"]["k"];
+            let offsets: Vec<i64> = (earliest..5).collect();
+            let expected: Vec<[i64; 2]> = offsets.iter().map(|&o| [o, o + 10]).collect();
+            assert_eq!(*polled, json!(expected));
+            let watermarks = json!({"type": "watermarks", "msg_id": 4, "keys": ["k"]});
+            let reply = &cluster.request("n1", watermarks)[0];
+            assert_eq!(reply["watermarks"]["k"]["earliest"], earliest);
+        }
+        let snapshot = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(snapshot, "{\"key\":\"k\",\"msgs\":[[0,10],[1,11]]}\n");
+    }
+
+    #[test]
+    fn a_throttled_commit_is_acked_only_once_its_round_has_synced() {
+        let interval = Duration::from_millis(100);
+        let config = || Config {
+            commit_gossip_interval: Some(interval),
+            ..Default::default()
+        };
+        let mut cluster = cluster(&["n1", "n2"], config);
+        let commit = |msg_id, offset| json!({"type": "commit_offsets", "msg_id": msg_id, "offsets": {"k": offset}});
+        let replies = cluster.request("n1", commit(1, 0));
+        assert_eq!(replies[0]["type"], "commit_offsets_ok");
+        // Both land inside the interval, so they coalesce into one round.
+        assert!(cluster.request("n1", commit(2, 1)).is_empty());
+        assert!(cluster.request("n1", commit(3, 2)).is_empty());
+        assert_eq!(cluster.nodes["n2"].node.commited_offsets.get("k"), Some(&0));
+        cluster.log.clear();
+
+        assert!(cluster.pump_until(interval, |cluster| cluster.replies.len() == 2));
+        let acked: Vec<&Value> = cluster
+            .replies
+            .iter()
+            .map(|reply| &reply["body"]["in_reply_to"])
+            .collect();
+        assert_eq!(acked, [2, 3]);
+        assert_eq!(cluster.nodes["n2"].node.commited_offsets.get("k"), Some(&2));
+        let syncs = cluster
+            .log
+            .iter()
+            .filter(|(_, msg)| msg["body"]["type"] == "sync");
+        assert_eq!(syncs.count(), 2, "one round, to each node");
+    }
+
+    #[test]
+    fn dump_config_shows_what_the_node_runs_with() {
+        let config = || Config {
+            partition: Some(Box::new(ConsistentHash { vnodes: 64 })),
+            offset_allocation: OffsetAllocation::LinKv,
+            poll_max_batch: Some(5),
+            ..Default::default()
+        };
+        let mut n1 = test_node("n1", &["n1"], config());
+        let config = n1.request(json!({"type": "dump_config", "msg_id": 1}))["config"].take();
+        assert_eq!(config["partition"], "consistent-hash");
+        assert_eq!(config["offset_allocation"], "lin-kv");
+        assert_eq!(config["poll_max_batch"], 5);
+        // Defaults are filled in, as `from_env` would leave them.
+        assert_eq!(config["poll_min_batch"], 1);
+        assert_eq!(config["poll_mode"], "offsets");
+    }
+
+    #[test]
+    fn a_gap_left_by_a_lost_sync_is_fetched_back() {
+        // Local offsets leave no holes of their own, so a hole is a loss.
+        let config = || Config {
+            offset_allocation: OffsetAllocation::Local,
+            ..Default::default()
+        };
+        let mut cluster = cluster(&["n1", "n2"], config);
+        let send = |msg| json!({"type": "send", "msg_id": 1, "key": "k", "msg": msg});
+        let commit =
+            |offset| json!({"type": "commit_offsets", "msg_id": 2, "offsets": {"k": offset}});
+        let lost = |msg: &Value| msg["dest"] == "n2" && msg["body"]["type"] == "sync";
+        for (offset, drop) in [(0, false), (1, true), (2, false)] {
+            cluster.schedule = Box::new(move |msg| match drop && lost(msg) {
+                true => Fate::Drop,
+                false => Fate::Deliver,
+            });
+            cluster.request("n1", send(10 + offset));
+            cluster.request("n1", commit(offset));
+        }
+        let fetches = cluster
+            .log
+            .iter()
+            .filter(|(_, msg)| msg["body"]["type"] == "fetch_range");
+        assert_eq!(fetches.count(), 1);
+        assert_eq!(
+            cluster.nodes["n2"].node.commited_msgs["k"],
+            [[0, 10], [1, 11], [2, 12]]
+        );
+        let poll = json!({"type": "poll", "msg_id": 3, "offsets": {"k": 0}});
+        assert_eq!(
+            cluster.request("n2", poll)[0]["msgs"],
+            json!({"k": [[0, 10], [1, 11], [2, 12]]})
+        );
+    }
+
+    #[test]
+    fn a_restarted_node_recovers_its_logs_from_lin_kv_and_serves_polls() {
+        let config = || Config {
+            persist: true,
+            ..Default::default()
+        };
+        let mut cluster = cluster(&["n1", "n2"], config);
+        let send = |msg| json!({"type": "send", "msg_id": 1, "key": "k", "msg": msg});
+        cluster.request("n1", send(10));
+        cluster.request("n1", send(11));
+        let commit = json!({"type": "commit_offsets", "msg_id": 2, "offsets": {"k": 1}});
+        cluster.request("n1", commit);
+
+        let node = Node {
+            config: config(),
+            clock: cluster.clock.clone(),
+            ..Default::default()
+        };
+        cluster.start("n1", node);
+        assert!(cluster.pump_until(Duration::ZERO, Cluster::quiet));
+        cluster.replies.clear();
+        let poll = json!({"type": "poll", "msg_id": 3, "offsets": {"k": 0}});
+        let replies = cluster.request("n1", poll);
+        assert_eq!(replies[0]["msgs"], json!({"k": [[0, 10], [1, 11]]}));
+        let list = json!({"type": "list_committed_offsets", "msg_id": 4, "keys": ["k"]});
+        assert_eq!(cluster.request("n1", list)[0]["offsets"], json!({"k": 1}));
+        assert_eq!(cluster.request("n1", send(12))[0]["offset"], 2);
+    }
+
+    #[test]
+    fn a_repeated_init_updates_membership_and_keeps_the_logs() {
+        let mut node = test_node("n1", &["n1", "n2"], Config::default());
+        send(&mut node, "k", 10);
+        let init =
+            json!({"type": "init", "msg_id": 2, "node_id": "n1", "node_ids": ["n1", "n2", "n3"]});
+        let sent = node.handle("c0", init);
+        assert_eq!(sent[0]["body"]["type"], "init_ok");
+        assert_eq!(node.node.node_ids, ["n1", "n2", "n3"]);
+        let polled = poll(&mut node, json!({"k": 0}));
+        assert_eq!(polled["msgs"], json!({"k": [[0, 10]]}));
+        assert_eq!(send(&mut node, "k", 11)["offset"], 1);
+    }
+
+    #[test]
+    fn an_init_with_an_unnumbered_node_id_is_refused() {
+        let (_, sent) = TestNode::start(Node::default(), "x", &["x"]);
+        assert_eq!(sent[0]["body"]["type"], "error");
+        assert_eq!(sent[0]["body"]["code"], 12);
+        assert_eq!(sent[0]["body"]["details"]["node_id"], "x");
+    }
+
+    #[test]
+    fn strict_commits_refuse_an_offset_past_the_log() {
+        let mut node = test_node("n1", &["n1"], Config::default());
+        send(&mut node, "k", 10);
+        let commit = json!({"type": "commit_offsets", "msg_id": 2, "offsets": {"k": 5}});
+        let sent = node.handle("c1", commit.clone());
+        assert!(
+            sent.iter().all(|msg| msg["body"]["type"] != "error"),
+            "lenient by default"
+        );
+
+        let config = Config {
+            strict_commits: true,
+            ..Default::default()
+        };
+        let mut node = test_node("n1", &["n1"], config);
+        send(&mut node, "k", 10);
+        let reply = node.request(commit);
+        assert_eq!(reply["type"], "error");
+        assert_eq!(reply["code"], 14);
+        let list = json!({"type": "list_committed_offsets", "msg_id": 3, "keys": ["k"]});
+        assert_eq!(node.request(list)["offsets"], json!({}));
+    }
+
+    #[test]
+    fn a_cursor_poll_carries_on_where_the_last_one_stopped() {
+        let mut node = test_node("n1", &["n1"], Config::default());
+        send(&mut node, "k", 10);
+        send(&mut node, "k", 11);
+        let first =
+            node.request(json!({"type": "poll", "msg_id": 2, "offsets": {"k": 0}, "cursor": ""}));
+        assert_eq!(first["msgs"], json!({"k": [[0, 10], [1, 11]]}));
+        let cursor = first["cursor"].as_str().unwrap().to_owned();
+        assert!(!cursor.is_empty());
+
+        send(&mut node, "k", 12);
+        // The cursor, not the offset asked for, says where to start.
+        let poll = json!({"type": "poll", "msg_id": 3, "offsets": {"k": 0}, "cursor": cursor});
+        let second = node.request(poll);
+        assert_eq!(second["msgs"], json!({"k": [[2, 12]]}));
+        assert_eq!(second["cursor"], cursor.as_str());
+    }
+
+    #[test]
+    fn committed_offsets_can_be_read_as_of_an_earlier_version() {
+        let mut cluster = cluster(&["n1", "n2"], Config::default);
+        for msg in [10, 11, 12] {
+            cluster.request(
+                "n1",
+                json!({"type": "send", "msg_id": 1, "key": "k", "msg": msg}),
+            );
+        }
+        let list = |cluster: &mut Cluster<Node>, version: Value| {
+            let body = json!({"type": "list_committed_offsets", "msg_id": 3, "keys": ["k"], "version": version});
+            cluster.request("n1", body).remove(0)
+        };
+        cluster.request(
+            "n1",
+            json!({"type": "commit_offsets", "msg_id": 2, "offsets": {"k": 0}}),
+        );
+        let first = list(&mut cluster, json!(u64::MAX))["version"].clone();
+        cluster.request(
+            "n1",
+            json!({"type": "commit_offsets", "msg_id": 2, "offsets": {"k": 2}}),
+        );
+        let latest = list(&mut cluster, json!(u64::MAX));
+        assert!(latest["version"].as_u64() > first.as_u64());
+        assert_eq!(latest["offsets"], json!({"k": 2}));
+
+        let past = list(&mut cluster, first.clone());
+        assert_eq!(
+            (&past["version"], &past["offsets"]),
+            (&first, &json!({"k": 0}))
+        );
+        let now = list(&mut cluster, Value::Null);
+        assert_eq!(now["offsets"], json!({"k": 2}));
+        assert!(now.get("version").is_none());
+    }
+
+    #[test]
+    fn a_consumer_far_behind_gets_bigger_poll_batches() {
+        let config = Config {
+            poll_max_batch: Some(8),
+            poll_min_batch: 2,
+            ..Default::default()
+        };
+        let mut node = test_node("n1", &["n1"], config);
+        for msg in 0..20 {
+            send(&mut node, "k", msg);
+        }
+        let polled = |node: &mut TestNode<Node>, from| {
+            let msgs = poll(node, json!({"k": from}))["msgs"]["k"].clone();
+            msgs.as_array().unwrap().len()
+        };
+        assert_eq!(polled(&mut node, 0), 8, "half the lag, capped");
+        assert_eq!(polled(&mut node, 8), 6, "half the lag");
+        assert_eq!(polled(&mut node, 17), 2, "at least the minimum");
+        assert_eq!(polled(&mut node, 19), 1, "all that is left");
+    }
+
+    #[test]
+    fn a_prefix_poll_returns_only_the_matching_keys() {
+        let mut node = test_node("n1", &["n1"], Config::default());
+        for (key, msg) in [("log-1", 1), ("log-2", 2), ("other", 3)] {
+            send(&mut node, key, msg);
+        }
+        let polled =
+            node.request(json!({"type": "poll", "msg_id": 2, "offsets": {}, "prefix": "log-"}));
+        assert_eq!(
+            polled["msgs"],
+            json!({"log-1": [[0, 1]], "log-2": [[0, 2]]})
+        );
+        // An explicit offset still applies to a matching key.
+        let poll = json!({"type": "poll", "msg_id": 3, "offsets": {"log-1": 1}, "prefix": "log-"});
+        assert_eq!(
+            node.request(poll)["msgs"],
+            json!({"log-1": [], "log-2": [[0, 2]]})
+        );
+    }
+
+    #[test]
+    fn offsets_at_the_top_of_the_range_never_overflow() {
+        // Interleaved offsets, so the lone message at the top isn't held
+        // back behind the hole below it.
+        let config = Config {
+            offset_allocation: OffsetAllocation::Interleaved,
+            ..Default::default()
+        };
+        let mut node = test_node("n1", &["n1"], config);
+        let send_at = |node: &mut TestNode<Node>, offset| {
+            node.request(
+                json!({"type": "send", "msg_id": 1, "key": "k", "msg": 7, "offset": offset}),
+            )
+        };
+        assert_eq!(send_at(&mut node, MAX_OFFSET)["offset"], MAX_OFFSET);
+        assert_eq!(send_at(&mut node, i64::MAX)["code"], 12);
+        assert_eq!(send(&mut node, "k", 8)["code"], 14, "no offset left");
+
+        let polled = poll(&mut node, json!({"k": MAX_OFFSET}));
+        assert_eq!(polled["msgs"], json!({"k": [[MAX_OFFSET, 7]]}));
+        assert_eq!(
+            poll(&mut node, json!({"k": i64::MAX}))["msgs"],
+            json!({"k": []})
+        );
+        let latest = poll(&mut node, json!({"k": LATEST_OFFSET}));
+        assert_eq!(latest["latest"], json!({"k": i64::MAX}));
+    }
+
+    #[test]
+    fn each_partition_strategy_sends_a_key_to_its_owner_only() {
+        let ids = ["n1", "n2", "n3"];
+        let nodes: Vec<String> = ids.iter().map(|&id| id.to_owned()).collect();
+        let keys: Vec<String> = (0..30).map(|i| format!("key-{i}")).collect();
+        let strategies: [fn() -> Box<dyn PartitionStrategy>; 3] = [
+            || Box::new(HashMod),
+            || Box::new(ConsistentHash { vnodes: 64 }),
+            || Box::new(Range),
+        ];
+        for strategy in strategies {
+            let name = strategy().name();
+            let mut cluster = cluster(&ids, || Config {
+                partition: Some(strategy()),
+                ..Default::default()
+            });
+            for key in &keys {
+                let send = json!({"type": "send", "msg_id": 1, "key": key, "msg": 1});
+                let replies = cluster.request("n1", send);
+                assert_eq!(replies[0]["type"], "send_ok", "{name}");
+            }
+            let mut owners = BTreeMap::new();
+            for key in &keys {
+                let holders: Vec<&String> = cluster
+                    .nodes
+                    .iter()
+                    .filter(|(_, node)| node.node.uncommited_msgs.contains_key(key.as_str()))
+                    .map(|(id, _)| id)
+                    .collect();
+                assert_eq!(holders, [&strategy().owner(key, &nodes)], "{name} {key}");
+                *owners.entry(holders[0].clone()).or_insert(0) += 1;
+            }
+            // Range goes by the first byte, and every key here starts "k".
+            let spread = if name == "range" { 1 } else { 3 };
+            assert_eq!(owners.len(), spread, "{name}: {owners:?}");
+        }
+        let range = |key: &str| Range.owner(key, &nodes);
+        assert_eq!(
+            [range("A"), range("a"), range("\u{e9}")],
+            ["n1", "n2", "n3"]
+        );
+    }
+
+    #[test]
+    fn an_unacked_sync_is_retried_only_to_its_peer_and_only_for_unsynced_keys() {
+        let retry = Duration::from_millis(100);
+        let config = move || Config {
+            sync_retry: Some(retry),
+            ..Default::default()
+        };
+        let mut cluster = cluster(&["n1", "n2", "n3"], config);
+        for (key, msg) in [("a", 10), ("b", 20), ("b", 21)] {
+            cluster.request(
+                "n1",
+                json!({"type": "send", "msg_id": 1, "key": key, "msg": msg}),
+            );
+        }
+        let is_sync_to_n3 = |msg: &Value| msg["dest"] == "n3" && msg["body"]["type"] == "sync";
+        cluster.schedule = Box::new(move |msg| match is_sync_to_n3(msg) {
+            true => Fate::Drop,
+            false => Fate::Deliver,
+        });
+        let commit = |offsets| json!({"type": "commit_offsets", "msg_id": 2, "offsets": offsets});
+        assert!(cluster
+            .request("n1", commit(json!({"a": 0, "b": 0})))
+            .is_empty());
+        cluster.schedule = Box::new(|_| Fate::Deliver);
+        let replies = cluster.request("n1", commit(json!({"b": 1})));
+        assert_eq!(replies[0]["type"], "commit_offsets_ok");
+
+        cluster.log.clear();
+        cluster.pump_until(retry, |_| false);
+        let retried: Vec<&Value> = cluster
+            .log
+            .iter()
+            .map(|(_, msg)| msg)
+            .filter(|msg| msg["body"]["type"] == "sync")
+            .collect();
+        assert_eq!(retried.len(), 1, "{retried:?}");
+        assert_eq!(retried[0]["dest"], "n3");
+        // n3 has acked b at 1 since, so only a is left to resend.
+        assert_eq!(retried[0]["body"]["offsets"], json!({"a": 0}));
+        assert_eq!(retried[0]["body"]["updates"], json!({"a": [[0, 10]]}));
+        assert_eq!(cluster.replies[0]["body"]["type"], "commit_offsets_ok");
+        assert!(cluster.nodes["n1"].node.unacked_syncs.is_empty());
+    }
+
+    #[test]
+    fn read_uncommitted_shows_what_is_still_waiting_on_a_commit() {
+        let mut cluster = cluster(&["n1", "n2"], || Config {
+            debug: true,
+            ..Default::default()
+        });
+        for msg in [10, 11] {
+            cluster.request(
+                "n1",
+                json!({"type": "send", "msg_id": 1, "key": "k", "msg": msg}),
+            );
+        }
+        let read = json!({"type": "read_uncommitted", "msg_id": 2, "key": "k"});
+        assert_eq!(
+            cluster.request("n1", read.clone())[0]["msgs"],
+            json!([[0, 10], [1, 11]])
+        );
+        assert_eq!(cluster.request("n2", read.clone())[0]["msgs"], json!([]));
+        cluster.request(
+            "n1",
+            json!({"type": "commit_offsets", "msg_id": 3, "offsets": {"k": 0}}),
+        );
+        assert_eq!(
+            cluster.request("n1", read.clone())[0]["msgs"],
+            json!([[1, 11]])
+        );
+
+        let mut node = test_node("n1", &["n1"], Config::default());
+        assert_eq!(node.request(read)["code"], 10);
+    }
+
+    #[test]
+    fn watermarks_give_each_keys_pollable_range() {
+        let mut cluster = cluster(&["n1"], || Config {
+            retain_committed: Some(2),
+            ..Default::default()
+        });
+        for (key, msg) in [("a", 10), ("a", 11), ("a", 12), ("a", 13), ("b", 20)] {
+            cluster.request(
+                "n1",
+                json!({"type": "send", "msg_id": 1, "key": key, "msg": msg}),
+            );
+        }
+        let watermarks = json!({"type": "watermarks", "msg_id": 2, "keys": ["a", "b", "never"]});
+        let range = |earliest, latest| json!({"earliest": earliest, "latest": latest});
+        let reply = cluster.request("n1", watermarks.clone()).remove(0);
+        assert_eq!(
+            reply["watermarks"],
+            json!({"a": range(0, 3), "b": range(0, 0)})
+        );
+
+        // Compaction keeps the last two committed messages of a.
+        cluster.request(
+            "n1",
+            json!({"type": "commit_offsets", "msg_id": 3, "offsets": {"a": 3}}),
+        );
+        let reply = cluster.request("n1", watermarks).remove(0);
+        assert_eq!(reply["watermarks"]["a"], range(2, 3));
+    }
+
+    #[test]
+    fn a_batch_of_sends_to_one_key_is_numbered_in_arrival_order() {
+        let mut cluster = cluster(&["n1", "n2"], Config::default);
+        let at = json!({"type": "send", "msg_id": 0, "key": "k", "msg": 5, "offset": 5});
+        assert_eq!(cluster.request("n1", at)[0]["offset"], 5);
+        for msg_id in 1..=5 {
+            let send = json!({"type": "send", "msg_id": msg_id, "key": "k", "msg": 100 + msg_id});
+            cluster.client("n1", send);
+        }
+        cluster.pump_until(Duration::ZERO, |_| false);
+        let mut acked: Vec<(i64, i64)> = cluster
+            .replies
+            .drain(..)
+            .map(|reply| {
+                (
+                    reply["body"]["in_reply_to"].as_i64().unwrap(),
+                    reply["body"]["offset"].as_i64().unwrap(),
+                )
+            })
+            .collect();
+        acked.sort();
+        // Past the stored offset 5, and in the order the sends came in.
+        assert_eq!(acked, [(1, 6), (2, 7), (3, 8), (4, 9), (5, 10)]);
+        let log = &cluster.nodes["n1"].node.uncommited_msgs["k"];
+        assert_eq!(
+            log[1..],
+            [[6, 101], [7, 102], [8, 103], [9, 104], [10, 105]]
+        );
+    }
+
+    #[test]
+    fn commits_past_the_pending_sync_cap_are_shed() {
+        let config = Config {
+            max_pending_syncs: Some(2),
+            ..Default::default()
+        };
+        let mut n1 = test_node("n1", &["n1", "n2"], config);
+        send(&mut n1, "k", 1);
+        let replies: Vec<Vec<Value>> = (1..=4)
+            .map(|id| {
+                let commit = json!({"type": "commit_offsets", "msg_id": id, "offsets": {"k": 0}});
+                n1.handle("c1", commit)
+            })
+            .collect();
+        for sent in &replies[..2] {
+            assert!(sent.iter().all(|msg| msg["dest"] != "c1"), "{sent:?}");
+        }
+        for (sent, id) in replies[2..].iter().zip(3..) {
+            assert_eq!(sent.len(), 1, "{sent:?}");
+            let body = &sent[0]["body"];
+            assert_eq!(body["type"], "error");
+            assert_eq!(body["in_reply_to"], id);
+            assert_eq!(body["code"], 11, "temporarily unavailable");
+        }
+        assert_eq!(n1.node.shed_commits, 2);
+        assert_eq!(n1.node.pending_syncs(), 2);
+    }
+
+    #[test]
+    fn a_reset_commit_propagates_despite_its_lower_offset() {
+        let ids = ["n1", "n2"];
+        let mut cluster = cluster(&ids, Config::default);
+        for msg in 0..6 {
+            let body = json!({"type": "send", "msg_id": msg, "key": "k", "msg": msg});
+            cluster.request("n1", body);
+        }
+        let commit = json!({"type": "commit_offsets", "msg_id": 10, "offsets": {"k": 5}});
+        cluster.request("n1", commit);
+        let reset =
+            json!({"type": "commit_offsets", "msg_id": 11, "offsets": {"k": 2}, "reset": true});
+        let replies = cluster.request("n2", reset);
+        assert_eq!(replies[0]["type"], "commit_offsets_ok", "{replies:?}");
+        for id in ids {
+            let node = &cluster.nodes[id].node;
+            assert_eq!(node.commited_offsets["k"], 2, "{id}");
+            assert_eq!(node.offset_generations["k"], 1, "{id}");
+        }
+
+        // A max-merge would let this late sync from before the reset win.
+        let stale = json!({"type": "sync", "msg_id": 20, "offsets": {"k": 5}, "updates": {}});
+        cluster.nodes.get_mut("n2").unwrap().handle("n1", stale);
+        assert_eq!(cluster.nodes["n2"].node.commited_offsets["k"], 2);
+        let list = json!({"type": "list_committed_offsets", "msg_id": 12, "keys": ["k"]});
+        let replies = cluster.request("n2", list);
+        assert_eq!(replies[0]["offsets"], json!({"k": 2}));
+    }
+
+    #[test]
+    fn a_poll_past_the_key_cap_returns_the_first_keys_and_lists_the_rest() {
+        let config = Config {
+            poll_max_keys: Some(2),
+            ..Default::default()
+        };
+        let mut n1 = test_node("n1", &["n1"], config);
+        for (i, key) in ["d", "b", "a", "c"].into_iter().enumerate() {
+            send(&mut n1, key, i as i64);
+        }
+        let reply = poll(&mut n1, json!({"a": 0, "b": 0, "c": 0, "d": 0}));
+        assert_eq!(reply["msgs"], json!({"a": [[0, 2]], "b": [[0, 1]]}));
+        assert_eq!(reply["more"], json!(["c", "d"]));
+
+        let reply = poll(&mut n1, json!({"c": 0, "d": 0}));
+        assert_eq!(reply["msgs"], json!({"c": [[0, 3]], "d": [[0, 0]]}));
+        assert!(reply.get("more").is_none(), "{reply}");
+    }
+
+    #[test]
+    fn a_replicated_send_is_pollable_from_a_peer_without_a_commit() {
+        let ids = ["n1", "n2", "n3"];
+        let poll_n2 = |replicate_sends| {
+            let mut cluster = cluster(&ids, || Config {
+                replicate_sends,
+                ..Default::default()
+            });
+            for msg in [10, 11] {
+                let body = json!({"type": "send", "msg_id": msg, "key": "k", "msg": msg});
+                cluster.request("n1", body);
+            }
+            let body = json!({"type": "poll", "msg_id": 1, "offsets": {"k": 0}});
+            let replies = cluster.request("n2", body);
+            assert!(cluster.nodes["n2"].node.commited_offsets.is_empty());
+            replies[0]["msgs"].clone()
+        };
+        assert_eq!(poll_n2(true), json!({"k": [[0, 10], [1, 11]]}));
+        assert_eq!(poll_n2(false), json!({"k": []}));
+
+        let mut n2 = test_node("n2", &ids, Config::default());
+        let replicate = json!({"type": "replicate", "key": "k", "offset": 0, "msg": 10});
+        assert!(n2.handle("n1", replicate).is_empty(), "unacked");
+        let late = json!({"type": "replicate", "key": "k", "offset": 0, "msg": 99});
+        n2.handle("n3", late);
+        assert_eq!(
+            n2.node.uncommited_msgs["k"],
+            [[0, 10]],
+            "deduplicated by offset"
+        );
+    }
+
+    #[test]
+    fn auto_advance_polls_never_return_a_message_twice() {
+        let config = |poll_mode| Config {
+            poll_mode,
+            ..Default::default()
+        };
+        let mut n1 = test_node("n1", &["n1"], config(PollMode::AutoAdvance));
+        send(&mut n1, "k", 10);
+        send(&mut n1, "k", 11);
+        assert_eq!(
+            poll(&mut n1, json!({"k": 0}))["msgs"],
+            json!({"k": [[0, 10], [1, 11]]})
+        );
+        assert_eq!(poll(&mut n1, json!({"k": 0}))["msgs"], json!({"k": []}));
+        send(&mut n1, "k", 12);
+        assert_eq!(
+            poll(&mut n1, json!({"k": 0}))["msgs"],
+            json!({"k": [[2, 12]]})
+        );
+        // Each client has a cursor of its own.
+        let sent = n1.handle(
+            "c2",
+            json!({"type": "poll", "msg_id": 1, "offsets": {"k": 1}}),
+        );
+        assert_eq!(sent[0]["body"]["msgs"], json!({"k": [[1, 11], [2, 12]]}));
+
+        let mut n1 = test_node("n1", &["n1"], config(PollMode::Offsets));
+        send(&mut n1, "k", 10);
+        for _ in 0..2 {
+            assert_eq!(
+                poll(&mut n1, json!({"k": 0}))["msgs"],
+                json!({"k": [[0, 10]]})
+            );
+        }
+    }
+
+    #[test]
+    fn a_forwarded_send_is_answered_from_the_node_id() {
+        let ids = ["n1", "n2"];
+        let nodes: Vec<String> = ids.iter().map(|&id| id.to_owned()).collect();
+        let mut n1 = test_node(
+            "n1",
+            &ids,
+            Config {
+                partition: Some(Box::new(HashMod)),
+                ..Default::default()
+            },
+        );
+        let key = (0..)
+            .map(|i| format!("key-{i}"))
+            .find(|key| HashMod.owner(key, &nodes) == "n2")
+            .unwrap();
+        let sent = n1.handle(
+            "c7",
+            json!({"type": "send", "msg_id": 3, "key": key, "msg": 1}),
+        );
+        assert_eq!(
+            (&sent[0]["src"], &sent[0]["dest"]),
+            (&json!("n1"), &json!("n2"))
+        );
+        let forwarded = &sent[0]["body"];
+        let send_ok = json!({"type": "send_ok", "in_reply_to": forwarded["msg_id"], "offset": 0});
+        let sent = n1.handle("n2", send_ok);
+        // Not a swap of the owner's envelope, which would answer the owner.
+        assert_eq!(
+            (&sent[0]["src"], &sent[0]["dest"]),
+            (&json!("n1"), &json!("c7"))
+        );
+        assert_eq!(
+            sent[0]["body"],
+            json!({"type": "send_ok", "in_reply_to": 3, "offset": 0})
+        );
+    }
+
+    #[test]
+    fn a_poll_reads_through_the_committed_log_into_the_uncommitted_one() {
+        let ids = ["n1", "n2"];
+        let mut cluster = cluster(&ids, Config::default);
+        let send = |cluster: &mut Cluster<Node>, msg: i64| {
+            let body = json!({"type": "send", "msg_id": msg, "key": "k", "msg": msg});
+            cluster.request("n1", body)
+        };
+        let poll = json!({"type": "poll", "msg_id": 1, "offsets": {"k": 0}});
+        send(&mut cluster, 10);
+        assert!(cluster.nodes["n1"].node.commited_msgs.is_empty());
+        let replies = cluster.request("n1", poll.clone());
+        assert_eq!(
+            replies[0]["msgs"],
+            json!({"k": [[0, 10]]}),
+            "sent, not committed"
+        );
+
+        send(&mut cluster, 11);
+        let commit = json!({"type": "commit_offsets", "msg_id": 2, "offsets": {"k": 1}});
+        cluster.request("n1", commit);
+        send(&mut cluster, 12);
+        let node = &cluster.nodes["n1"].node;
+        assert_eq!(node.commited_msgs["k"], [[0, 10], [1, 11]]);
+        assert_eq!(node.uncommited_msgs["k"].last(), Some(&[2, 12]));
+        let replies = cluster.request("n1", poll);
+        assert_eq!(
+            replies[0]["msgs"],
+            json!({"k": [[0, 10], [1, 11], [2, 12]]})
+        );
     }
-    Ok(())
 }
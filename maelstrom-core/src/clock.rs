@@ -0,0 +1,68 @@
+//! The time as a node sees it, so tests can move it by hand.
+
+use std::{
+    cell::Cell,
+    rc::Rc,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Either the real clock, the default, or a manual one that stands still
+/// until `advance`d. Clones of a manual clock share its time, so a test can
+/// keep one and hand the others to the nodes it drives.
+#[derive(Clone, Default)]
+pub struct Clock {
+    manual: Option<Rc<ManualTime>>,
+}
+
+struct ManualTime {
+    /// The real time when the clock was made, which it starts from.
+    start: Instant,
+    wall_start: SystemTime,
+    advanced: Cell<Duration>,
+}
+
+impl Clock {
+    /// A clock stopped at the current time.
+    pub fn manual() -> Self {
+        Clock {
+            manual: Some(Rc::new(ManualTime {
+                start: Instant::now(),
+                wall_start: SystemTime::now(),
+                advanced: Cell::new(Duration::ZERO),
+            })),
+        }
+    }
+
+    pub fn now(&self) -> Instant {
+        match &self.manual {
+            Some(time) => time.start + time.advanced.get(),
+            None => Instant::now(),
+        }
+    }
+
+    /// Wall-clock time, which a manual clock moves along with `now`.
+    pub fn system_now(&self) -> SystemTime {
+        match &self.manual {
+            Some(time) => time.wall_start + time.advanced.get(),
+            None => SystemTime::now(),
+        }
+    }
+
+    /// How long it has been since `earlier`, or zero if that is still ahead.
+    pub fn elapsed(&self, earlier: Instant) -> Duration {
+        self.now().saturating_duration_since(earlier)
+    }
+
+    /// Moves a manual clock forward by `by`.
+    ///
+    /// # Panics
+    ///
+    /// On the real clock, which no one can move.
+    pub fn advance(&self, by: Duration) {
+        let time = self
+            .manual
+            .as_ref()
+            .expect("only a manual clock can be advanced");
+        time.advanced.set(time.advanced.get() + by);
+    }
+}
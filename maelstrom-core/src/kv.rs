@@ -0,0 +1,190 @@
+//! Requests to Maelstrom's KV services (`seq-kv`, `lin-kv`, `lww-kv`).
+
+use crate::{Clock, ErrorDetails, Message, Output};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    io,
+    time::{Duration, Instant},
+};
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+enum Body<'a, V> {
+    Read {
+        msg_id: i64,
+        key: &'a str,
+    },
+    Write {
+        msg_id: i64,
+        key: &'a str,
+        value: V,
+    },
+    Cas {
+        msg_id: i64,
+        key: &'a str,
+        from: V,
+        to: V,
+        create_if_not_exists: bool,
+    },
+}
+
+/// A KV service's reply to one request, taken apart by the node since value
+/// types differ, or the lack of one. `V` is the type of the values read.
+#[derive(Clone, Debug, PartialEq)]
+pub enum KvReply<'a, V> {
+    ReadOk(V),
+    WriteOk,
+    CasOk,
+    Error {
+        code: i8,
+        text: &'a str,
+        details: ErrorDetails,
+    },
+    /// Nothing came back within the timeout given to `take_expired`.
+    Timeout,
+}
+
+/// Sends requests to one KV service and keeps a continuation for each
+/// outstanding one, a `T` saying what its reply is for, until the node hands
+/// the reply to `dispatch`, or `take`s the continuation by `in_reply_to`.
+pub struct KvClient<T> {
+    service: String,
+    node_id: String,
+    /// Each outstanding operation, with when its request was sent.
+    pending: HashMap<i64, (Instant, T)>,
+    clock: Clock,
+}
+
+impl<T> Default for KvClient<T> {
+    fn default() -> Self {
+        KvClient {
+            service: String::new(),
+            node_id: String::new(),
+            pending: HashMap::new(),
+            clock: Clock::default(),
+        }
+    }
+}
+
+impl<T> KvClient<T> {
+    /// A client timing its requests by `clock`.
+    pub fn new(service: impl Into<String>, node_id: impl Into<String>, clock: Clock) -> Self {
+        KvClient {
+            service: service.into(),
+            node_id: node_id.into(),
+            pending: HashMap::new(),
+            clock,
+        }
+    }
+
+    /// The KV service's node id.
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
+    pub fn read(&mut self, out: &mut Output, msg_id: i64, key: &str, op: T) -> io::Result<()> {
+        self.send::<()>(out, msg_id, op, Body::Read { msg_id, key })
+    }
+
+    pub fn write<V: Serialize>(
+        &mut self,
+        out: &mut Output,
+        msg_id: i64,
+        key: &str,
+        value: V,
+        op: T,
+    ) -> io::Result<()> {
+        self.send(out, msg_id, op, Body::Write { msg_id, key, value })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn cas<V: Serialize>(
+        &mut self,
+        out: &mut Output,
+        msg_id: i64,
+        key: &str,
+        from: V,
+        to: V,
+        create_if_not_exists: bool,
+        op: T,
+    ) -> io::Result<()> {
+        let body = Body::Cas {
+            msg_id,
+            key,
+            from,
+            to,
+            create_if_not_exists,
+        };
+        self.send(out, msg_id, op, body)
+    }
+
+    fn send<V: Serialize>(
+        &mut self,
+        out: &mut Output,
+        msg_id: i64,
+        op: T,
+        body: Body<'_, V>,
+    ) -> io::Result<()> {
+        let _span = tracing::debug_span!("kv_request", service = %self.service, msg_id).entered();
+        self.pending.insert(msg_id, (self.clock.now(), op));
+        out.send(&Message {
+            src: &self.node_id,
+            dest: &self.service,
+            body,
+        })
+    }
+
+    /// The continuation registered for the reply to `in_reply_to`, which
+    /// is then forgotten, with the reply to run it on. `None` for a stray
+    /// the node should look at itself.
+    pub fn dispatch<'a, V>(
+        &mut self,
+        in_reply_to: i64,
+        reply: KvReply<'a, V>,
+    ) -> Option<(T, KvReply<'a, V>)> {
+        Some((self.take(in_reply_to)?, reply))
+    }
+
+    /// The operation a reply to `msg_id` belongs to, which is then forgotten.
+    pub fn take(&mut self, msg_id: i64) -> Option<T> {
+        let (_, op) = self.pending.remove(&msg_id)?;
+        tracing::debug!(service = %self.service, msg_id, "kv reply");
+        Some(op)
+    }
+
+    pub fn get(&self, msg_id: i64) -> Option<&T> {
+        self.pending.get(&msg_id).map(|(_, op)| op)
+    }
+
+    /// Outstanding operations.
+    pub fn pending(&self) -> impl Iterator<Item = &T> {
+        self.pending.values().map(|(_, op)| op)
+    }
+
+    /// When the longest-outstanding request was sent.
+    pub fn oldest(&self) -> Option<Instant> {
+        self.pending.values().map(|&(sent_at, _)| sent_at).min()
+    }
+
+    /// Forgets the operations whose request has gone unanswered for at
+    /// least `timeout` and returns them. A reply that turns up later is
+    /// then unknown to `take`, like any other stray.
+    pub fn take_expired(&mut self, timeout: Duration) -> Vec<T> {
+        let clock = &self.clock;
+        let expired: Vec<i64> = self
+            .pending
+            .iter()
+            .filter(|(_, (sent_at, _))| clock.elapsed(*sent_at) >= timeout)
+            .map(|(&msg_id, _)| msg_id)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|msg_id| {
+                tracing::debug!(service = %self.service, msg_id, "kv request timed out");
+                self.pending.remove(&msg_id).map(|(_, op)| op)
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,999 @@
+//! Plumbing shared by the Maelstrom nodes: message framing, the stdin loop
+//! and buffered stdout.
+
+mod clock;
+mod kv;
+mod retry;
+#[cfg(any(test, feature = "test-support"))]
+pub mod testing;
+mod trace;
+
+pub use clock::Clock;
+pub use kv::{KvClient, KvReply};
+pub use retry::RetryBudget;
+pub use trace::LineSubscriber;
+
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufWriter, Write},
+    rc::Rc,
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// One line of Maelstrom traffic. `body` is the workload's
+/// `#[serde(tag = "type")]` enum.
+#[derive(Deserialize, Serialize)]
+pub struct Message<'a, B> {
+    pub src: &'a str,
+    pub dest: &'a str,
+    pub body: B,
+}
+
+impl<'a, B> Message<'a, B> {
+    pub fn envelope(&self) -> Envelope<'a> {
+        Envelope {
+            src: self.src,
+            dest: self.dest,
+        }
+    }
+}
+
+/// A message's addressing without its body, so a node can still build its
+/// reply after taking the body apart.
+#[derive(Clone, Copy)]
+pub struct Envelope<'a> {
+    pub src: &'a str,
+    pub dest: &'a str,
+}
+
+impl<'a> Envelope<'a> {
+    /// A reply from the node the message was addressed to.
+    pub fn reply<B>(self, body: B) -> Message<'a, B> {
+        self.reply_from(self.dest, body)
+    }
+
+    /// A reply from `src`, normally this node's own id, for answering on
+    /// behalf of whichever node the message was addressed to.
+    pub fn reply_from<B>(self, src: &'a str, body: B) -> Message<'a, B> {
+        Message {
+            src,
+            dest: self.src,
+            body,
+        }
+    }
+}
+
+/// Structured context for an error body, e.g. the key involved.
+pub type ErrorDetails = serde_json::Map<String, serde_json::Value>;
+
+/// `details` for an error response, from a `json!` object.
+pub fn error_details(value: serde_json::Value) -> ErrorDetails {
+    match value {
+        serde_json::Value::Object(details) => details,
+        _ => ErrorDetails::new(),
+    }
+}
+
+/// Whether the environment variable `name` is set to `1` or `true`.
+pub fn env_flag(name: &str) -> bool {
+    matches!(std::env::var(name).as_deref(), Ok("1" | "true"))
+}
+
+/// The environment variable `name` parsed as a `T`, if it is set and parses.
+pub fn env_parse<T: std::str::FromStr>(name: &str) -> Option<T> {
+    std::env::var(name).ok()?.parse().ok()
+}
+
+/// A body field some Maelstrom versions spell differently, renamed to the
+/// spelling the node parses before a message reaches it.
+pub struct FieldAlias {
+    /// The `type` of the messages the field appears in.
+    pub kind: &'static str,
+    pub alias: &'static str,
+    pub canonical: &'static str,
+}
+
+/// A workload the `Runtime` feeds messages to.
+pub trait Node {
+    /// Bodies of the messages this node accepts.
+    type Body<'a>: Deserialize<'a>;
+
+    fn handle(&mut self, msg: Message<'_, Self::Body<'_>>, out: &mut Output) -> anyhow::Result<()>;
+
+    /// Longest the runtime waits for input before calling `tick` anyway.
+    fn timeout(&self) -> Duration;
+
+    /// Runs timers. Called before every wait for input, so after each
+    /// message and whenever a wait times out.
+    fn tick(&mut self, _out: &mut Output) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Sees each input line before it is parsed.
+    fn received(&mut self, _line: &str) {}
+}
+
+/// When `Output` fsyncs the tee file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FsyncPolicy {
+    /// Whenever output is flushed, i.e. after every input or timer.
+    Always,
+    /// At most this often, at the first flush once it has passed. A timer
+    /// wakes the runtime for it, so the last lines don't wait on input.
+    Interval(Duration),
+    /// Never, leaving it to the OS.
+    #[default]
+    Never,
+}
+
+/// Owns stdin and stdout and drives a `Node` until stdin closes.
+#[derive(Default)]
+pub struct Runtime {
+    /// Also append every outgoing message to this NDJSON file.
+    pub tee_path: Option<String>,
+    /// How the tee file is made durable.
+    pub fsync: FsyncPolicy,
+    /// Add `internal_latency_us` to every outgoing body, measured from when
+    /// the input being handled was received.
+    pub trace_latency: bool,
+    /// Hold `Output::reply`s until the node's next `tick` has run.
+    pub hold_replies: bool,
+    /// Stop on an input line that isn't a valid message instead of logging
+    /// it to stderr and moving on.
+    pub abort_on_parse_error: bool,
+    /// Field renames applied to every input before it is parsed.
+    pub aliases: &'static [FieldAlias],
+    /// Write spans and events at this level and above to stderr, unless a
+    /// `tracing` subscriber is already installed.
+    pub trace: Option<tracing::Level>,
+}
+
+impl Runtime {
+    pub fn run<N: Node>(&self, node: &mut N) -> anyhow::Result<()> {
+        self.run_with_sink(node, StdoutSink)
+    }
+
+    /// Like `run`, but writes output to `sink` instead of stdout.
+    pub fn run_with_sink<N: Node>(
+        &self,
+        node: &mut N,
+        sink: impl OutputSink + 'static,
+    ) -> anyhow::Result<()> {
+        if let Some(level) = self.trace {
+            let subscriber = LineSubscriber::new(level, io::stderr());
+            // Fails only if there already is one, which then stays.
+            let _ = tracing::subscriber::set_global_default(subscriber);
+        }
+        self.run_lines(node, spawn_stdin_reader(), sink)
+    }
+
+    /// Drives `node` with the input lines `lines` delivers until it closes.
+    fn run_lines<N: Node>(
+        &self,
+        node: &mut N,
+        lines: Receiver<io::Result<String>>,
+        sink: impl OutputSink + 'static,
+    ) -> anyhow::Result<()> {
+        let mut out = Output::new(self, Box::new(sink))?;
+        loop {
+            {
+                let _span = tracing::debug_span!("tick").entered();
+                node.tick(&mut out)?;
+            }
+            out.release()?;
+            out.flush()?;
+            let wait = match out.fsync_wait() {
+                Some(fsync) => fsync.min(node.timeout()),
+                None => node.timeout(),
+            };
+            let line = match lines.recv_timeout(wait) {
+                Ok(line) => line?,
+                Err(RecvTimeoutError::Timeout) => {
+                    out.begin(Instant::now());
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            out.begin(Instant::now());
+            node.received(&line);
+            let line = if self.aliases.is_empty() {
+                line
+            } else {
+                apply_aliases(&line, self.aliases).unwrap_or(line)
+            };
+            let msg: Message<N::Body<'_>> = match serde_json::from_str(&line) {
+                Ok(msg) => msg,
+                Err(err) if !self.abort_on_parse_error => {
+                    eprintln!("skipping unparseable input {line:?}: {err}");
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+            let span = tracing::info_span!(
+                "handle",
+                src = msg.src,
+                dest = msg.dest,
+                kind = tracing::field::Empty,
+            );
+            // Only pay for a second parse when a subscriber wants the span.
+            if !span.is_disabled() {
+                if let Some(kind) = message_kind(&line) {
+                    span.record("kind", kind.as_str());
+                }
+            }
+            let _span = span.entered();
+            node.handle(msg, &mut out)?;
+        }
+        out.release()?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// `line` with the aliased body fields renamed, or `None` if nothing needed
+/// renaming. A field already present under its canonical name wins.
+fn apply_aliases(line: &str, aliases: &[FieldAlias]) -> Option<String> {
+    let mut msg: serde_json::Value = serde_json::from_str(line).ok()?;
+    let body = msg.get_mut("body")?.as_object_mut()?;
+    let kind = body.get("type")?.as_str()?.to_owned();
+    let mut renamed = false;
+    for alias in aliases.iter().filter(|alias| alias.kind == kind) {
+        if body.contains_key(alias.canonical) {
+            continue;
+        }
+        if let Some(value) = body.remove(alias.alias) {
+            body.insert(alias.canonical.to_owned(), value);
+            renamed = true;
+        }
+    }
+    renamed.then(|| msg.to_string())
+}
+
+/// The body `type` of an input line.
+fn message_kind(line: &str) -> Option<String> {
+    let msg: serde_json::Value = serde_json::from_str(line).ok()?;
+    Some(msg.get("body")?.get("type")?.as_str()?.to_owned())
+}
+
+/// Reads stdin on its own thread so the main loop can wake up for timers
+/// even when no input arrives.
+fn spawn_stdin_reader() -> Receiver<io::Result<String>> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Where `Output` writes its lines.
+pub trait OutputSink {
+    /// Writes `msg`, one or more whole lines, each ending in a newline.
+    fn send(&mut self, msg: &str) -> io::Result<()>;
+}
+
+/// The sink `Runtime::run` uses.
+pub struct StdoutSink;
+
+impl OutputSink for StdoutSink {
+    fn send(&mut self, msg: &str) -> io::Result<()> {
+        io::stdout().lock().write_all(msg.as_bytes())
+    }
+}
+
+/// Collects output in memory, for tests. Clones share the same lines, so
+/// keep one to read what the `Output` holding the other has written.
+#[derive(Clone, Default)]
+pub struct VecSink {
+    lines: Rc<RefCell<Vec<String>>>,
+}
+
+impl VecSink {
+    /// The lines written since the last call, without their newlines.
+    pub fn take(&self) -> Vec<String> {
+        self.lines.take()
+    }
+}
+
+impl OutputSink for VecSink {
+    fn send(&mut self, msg: &str) -> io::Result<()> {
+        let mut lines = self.lines.borrow_mut();
+        lines.extend(msg.lines().map(str::to_owned));
+        Ok(())
+    }
+}
+
+/// What `Output` tees to: a file, or a stand-in that counts fsyncs.
+trait TeeFile: Write {
+    /// Makes everything written so far durable.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+impl TeeFile for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+
+/// The tee file and how far it has been fsynced.
+struct Tee {
+    file: BufWriter<Box<dyn TeeFile>>,
+    fsync: FsyncPolicy,
+    clock: Clock,
+    /// When the file was last fsynced, or opened.
+    synced: Instant,
+    /// Whether lines have been written since.
+    dirty: bool,
+}
+
+impl Tee {
+    fn new(file: Box<dyn TeeFile>, fsync: FsyncPolicy, clock: Clock) -> Self {
+        Tee {
+            file: BufWriter::new(file),
+            fsync,
+            synced: clock.now(),
+            clock,
+            dirty: false,
+        }
+    }
+
+    fn write(&mut self, lines: &[u8]) -> io::Result<()> {
+        self.dirty = true;
+        self.file.write_all(lines)
+    }
+
+    /// How long until an interval fsync is due, if one is waiting.
+    fn wait(&self) -> Option<Duration> {
+        match self.fsync {
+            FsyncPolicy::Interval(interval) if self.dirty => {
+                Some(interval.saturating_sub(self.clock.elapsed(self.synced)))
+            }
+            _ => None,
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let due = match self.fsync {
+            FsyncPolicy::Always => self.dirty,
+            FsyncPolicy::Interval(_) => self.wait() == Some(Duration::ZERO),
+            FsyncPolicy::Never => false,
+        };
+        if due {
+            self.file.get_mut().sync()?;
+            self.synced = self.clock.now();
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+/// Writes outgoing messages to a sink, stdout unless the `Runtime` was given
+/// another, one per line, through a buffer that is reused across messages.
+/// Optionally tees the same lines to a file.
+pub struct Output {
+    sink: Box<dyn OutputSink>,
+    buf: Vec<u8>,
+    tee: Option<Tee>,
+    trace_latency: bool,
+    /// When the current input arrived, or the current timer fired.
+    started: Instant,
+    hold_replies: bool,
+    /// Replies waiting for `release`.
+    held: Vec<u8>,
+}
+
+impl Output {
+    /// An `Output` writing only to `sink`, for driving `Node::handle`
+    /// without a `Runtime`.
+    pub fn with_sink(sink: impl OutputSink + 'static) -> Self {
+        Output {
+            sink: Box::new(sink),
+            buf: Vec::new(),
+            tee: None,
+            trace_latency: false,
+            started: Instant::now(),
+            hold_replies: false,
+            held: Vec::new(),
+        }
+    }
+
+    fn new(runtime: &Runtime, sink: Box<dyn OutputSink>) -> io::Result<Self> {
+        let tee = match &runtime.tee_path {
+            Some(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                Some(Tee::new(Box::new(file), runtime.fsync, Clock::default()))
+            }
+            None => None,
+        };
+        Ok(Output {
+            sink,
+            buf: Vec::new(),
+            tee,
+            trace_latency: runtime.trace_latency,
+            started: Instant::now(),
+            hold_replies: runtime.hold_replies,
+            held: Vec::new(),
+        })
+    }
+
+    /// Marks the start of handling an input or timer.
+    fn begin(&mut self, started: Instant) {
+        self.started = started;
+    }
+
+    /// When handling of the current input or timer began.
+    pub fn started(&self) -> Instant {
+        self.started
+    }
+
+    pub fn traces_latency(&self) -> bool {
+        self.trace_latency
+    }
+
+    pub fn send<T: Serialize>(&mut self, msg: &T) -> io::Result<()> {
+        if self.encode(msg) {
+            self.write_line()?;
+        }
+        Ok(())
+    }
+
+    /// Sends the reply to the current input, or with `hold_replies` holds it
+    /// until `release` so whatever the next `tick` sends goes first.
+    pub fn reply<T: Serialize>(&mut self, msg: &T) -> io::Result<()> {
+        if !self.hold_replies {
+            return self.send(msg);
+        }
+        if self.encode(msg) {
+            self.held.extend_from_slice(&self.buf);
+            self.held.push(b'\n');
+        }
+        Ok(())
+    }
+
+    fn release(&mut self) -> io::Result<()> {
+        if self.held.is_empty() {
+            return Ok(());
+        }
+        if let Some(tee) = &mut self.tee {
+            tee.write(&self.held)?;
+        }
+        self.sink.send(as_str(&self.held)?)?;
+        self.held.clear();
+        Ok(())
+    }
+
+    /// Sends a line the caller encodes into the buffer itself, for hot paths
+    /// that skip building a `Message`. Such lines carry no latency trace.
+    pub fn send_raw(
+        &mut self,
+        encode: impl FnOnce(&mut Vec<u8>) -> io::Result<()>,
+    ) -> io::Result<()> {
+        self.buf.clear();
+        encode(&mut self.buf)?;
+        self.write_line()
+    }
+
+    /// Serializes `msg` into the buffer. A message that fails to serialize
+    /// is logged and dropped, returning false, so it can't take the rest of
+    /// the output down with it.
+    fn encode<T: Serialize>(&mut self, msg: &T) -> bool {
+        self.buf.clear();
+        let encoded = if self.trace_latency {
+            serde_json::to_value(msg).and_then(|mut msg| {
+                if let Some(body) = msg.get_mut("body").and_then(|b| b.as_object_mut()) {
+                    let latency = self.started.elapsed().as_micros() as u64;
+                    body.insert("internal_latency_us".to_owned(), latency.into());
+                }
+                serde_json::to_writer(&mut self.buf, &msg)
+            })
+        } else {
+            serde_json::to_writer(&mut self.buf, msg)
+        };
+        if let Err(err) = encoded {
+            eprintln!("dropping a message that failed to serialize: {err}");
+            return false;
+        }
+        true
+    }
+
+    fn write_line(&mut self) -> io::Result<()> {
+        self.buf.push(b'\n');
+        if let Some(tee) = &mut self.tee {
+            tee.write(&self.buf)?;
+        }
+        self.sink.send(as_str(&self.buf)?)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.tee {
+            Some(tee) => tee.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// How long the runtime may wait before flushing again for the tee's
+    /// interval fsync, if one is waiting.
+    fn fsync_wait(&self) -> Option<Duration> {
+        self.tee.as_ref().and_then(Tee::wait)
+    }
+}
+
+/// Encoded output as text. serde_json only writes UTF-8, so this fails only
+/// if a `send_raw` caller wrote something else.
+fn as_str(buf: &[u8]) -> io::Result<&str> {
+    std::str::from_utf8(buf).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Deserialize, Serialize)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Echo { msg_id: i64, echo: String },
+        EchoOk { in_reply_to: i64, echo: String },
+    }
+
+    /// Echoes every message back, then sends one more line through
+    /// `send_raw` so both write paths are covered.
+    struct Echo;
+
+    impl Node for Echo {
+        type Body<'a> = Body;
+
+        fn handle(&mut self, msg: Message<'_, Body>, out: &mut Output) -> anyhow::Result<()> {
+            let envelope = msg.envelope();
+            let Body::Echo { msg_id, echo } = msg.body else {
+                return Ok(());
+            };
+            out.reply(&envelope.reply(Body::EchoOk {
+                in_reply_to: msg_id,
+                echo,
+            }))?;
+            out.send_raw(|buf| {
+                buf.extend_from_slice(b"raw");
+                Ok(())
+            })?;
+            Ok(())
+        }
+
+        fn timeout(&self) -> Duration {
+            Duration::from_secs(1)
+        }
+    }
+
+    #[test]
+    fn vec_sink_captures_what_a_node_sends() {
+        let sink = VecSink::default();
+        let mut out = Output::with_sink(sink.clone());
+        let msg = Message {
+            src: "c1",
+            dest: "n1",
+            body: Body::Echo {
+                msg_id: 7,
+                echo: "hi".to_owned(),
+            },
+        };
+        Echo.handle(msg, &mut out).unwrap();
+        assert_eq!(
+            sink.take(),
+            [
+                r#"{"src":"n1","dest":"c1","body":{"type":"echo_ok","in_reply_to":7,"echo":"hi"}}"#,
+                "raw",
+            ]
+        );
+        assert!(sink.take().is_empty());
+    }
+
+    #[test]
+    fn message_round_trips_through_serde() {
+        let line = r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1,"echo":"hi"}}"#;
+        let msg: Message<Body> = serde_json::from_str(line).unwrap();
+        assert_eq!((msg.src, msg.dest), ("c1", "n1"));
+        assert!(matches!(&msg.body, Body::Echo { msg_id: 1, echo } if echo == "hi"));
+        assert_eq!(serde_json::to_string(&msg).unwrap(), line);
+    }
+
+    /// A writer whose bytes can still be read after it is handed off.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuf {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    #[test]
+    fn line_subscriber_shows_each_messages_handle_span() {
+        let buf = SharedBuf::default();
+        let subscriber = LineSubscriber::new(tracing::Level::INFO, buf.clone());
+        let (tx, lines) = mpsc::channel();
+        for line in [
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1,"echo":"hi"}}"#,
+            r#"{"src":"n2","dest":"n1","body":{"type":"echo_ok","in_reply_to":1,"echo":"hi"}}"#,
+        ] {
+            tx.send(Ok(line.to_owned())).unwrap();
+        }
+        drop(tx);
+        let sink = VecSink::default();
+        tracing::subscriber::with_default(subscriber, || {
+            Runtime::default().run_lines(&mut Echo, lines, sink.clone())
+        })
+        .unwrap();
+        assert_eq!(sink.take().len(), 2);
+        assert_eq!(
+            buf.text(),
+            " INFO handle{src=c1 dest=n1 kind=echo}\n INFO handle{src=n2 dest=n1 kind=echo_ok}\n"
+        );
+    }
+
+    #[test]
+    fn line_subscriber_keeps_each_threads_spans_apart() {
+        let buf = SharedBuf::default();
+        let dispatch =
+            tracing::Dispatch::new(LineSubscriber::new(tracing::Level::INFO, buf.clone()));
+        let (entered_tx, entered) = mpsc::channel();
+        let (done_tx, done) = mpsc::channel::<()>();
+        let watchdog = thread::spawn({
+            let dispatch = dispatch.clone();
+            move || {
+                tracing::dispatcher::with_default(&dispatch, || {
+                    let _span = tracing::info_span!("watchdog").entered();
+                    entered_tx.send(()).unwrap();
+                    done.recv().unwrap();
+                })
+            }
+        });
+        entered.recv().unwrap();
+        tracing::dispatcher::with_default(&dispatch, || {
+            let _span = tracing::info_span!("handle", kind = "echo").entered();
+            tracing::info!("handled");
+        });
+        done_tx.send(()).unwrap();
+        watchdog.join().unwrap();
+        assert_eq!(
+            buf.text(),
+            " INFO watchdog{}\n INFO handle{kind=echo}\n INFO handle{kind=echo}: handled\n"
+        );
+    }
+
+    /// A tee file that keeps nothing and counts its fsyncs.
+    #[derive(Clone, Default)]
+    struct SyncCounter(Rc<RefCell<usize>>);
+
+    impl io::Write for SyncCounter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl TeeFile for SyncCounter {
+        fn sync(&mut self) -> io::Result<()> {
+            *self.0.borrow_mut() += 1;
+            Ok(())
+        }
+    }
+
+    /// An `Output` teeing to a `SyncCounter` under `fsync`, with the clock
+    /// the tee reads and the counter.
+    fn counting_tee(fsync: FsyncPolicy) -> (Output, Clock, SyncCounter) {
+        let (clock, syncs) = (Clock::manual(), SyncCounter::default());
+        let mut out = Output::with_sink(VecSink::default());
+        out.tee = Some(Tee::new(Box::new(syncs.clone()), fsync, clock.clone()));
+        (out, clock, syncs)
+    }
+
+    fn send_line(out: &mut Output) {
+        out.send_raw(|buf| {
+            buf.extend_from_slice(b"line");
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn the_tee_is_fsynced_as_its_policy_says() {
+        for (fsync, expected) in [(FsyncPolicy::Always, 3), (FsyncPolicy::Never, 0)] {
+            let (mut out, _, syncs) = counting_tee(fsync);
+            for _ in 0..3 {
+                send_line(&mut out);
+                out.flush().unwrap();
+            }
+            // Nothing new written, so nothing to sync.
+            out.flush().unwrap();
+            assert_eq!(*syncs.0.borrow(), expected, "{fsync:?}");
+            assert_eq!(out.fsync_wait(), None);
+        }
+
+        let interval = Duration::from_millis(100);
+        let (mut out, clock, syncs) = counting_tee(FsyncPolicy::Interval(interval));
+        assert_eq!(out.fsync_wait(), None);
+        send_line(&mut out);
+        out.flush().unwrap();
+        clock.advance(Duration::from_millis(60));
+        send_line(&mut out);
+        out.flush().unwrap();
+        assert_eq!(*syncs.0.borrow(), 0);
+        assert_eq!(out.fsync_wait(), Some(Duration::from_millis(40)));
+        // The timer's flush, with no new lines, is what syncs.
+        clock.advance(Duration::from_millis(40));
+        out.flush().unwrap();
+        assert_eq!(*syncs.0.borrow(), 1);
+        assert_eq!(out.fsync_wait(), None);
+        send_line(&mut out);
+        out.flush().unwrap();
+        assert_eq!(*syncs.0.borrow(), 1);
+        assert_eq!(out.fsync_wait(), Some(interval));
+    }
+
+    #[test]
+    fn manual_clock_moves_only_when_advanced() {
+        let clock = Clock::manual();
+        let (start, wall_start) = (clock.now(), clock.system_now());
+        let shared = clock.clone();
+        thread::sleep(Duration::from_millis(2));
+        assert_eq!(clock.elapsed(start), Duration::ZERO);
+        shared.advance(Duration::from_secs(3));
+        assert_eq!(clock.elapsed(start), Duration::from_secs(3));
+        assert_eq!(clock.system_now(), wall_start + Duration::from_secs(3));
+    }
+
+    #[test]
+    fn kv_client_hands_each_reply_its_continuation() {
+        let sink = VecSink::default();
+        let mut out = Output::with_sink(sink.clone());
+        let mut kv = KvClient::new("seq-kv", "n1", Clock::manual());
+        kv.read(&mut out, 1, "k", "after read").unwrap();
+        kv.cas(&mut out, 2, "k", 0, 1, true, "after cas").unwrap();
+        let mut store = testing::KvStore::default();
+        let replies: Vec<serde_json::Value> = sink
+            .take()
+            .iter()
+            .map(|line| {
+                store.reply(&serde_json::from_str::<serde_json::Value>(line).unwrap()["body"])
+            })
+            .collect();
+        // The read went first, before the CAS created the key.
+        assert_eq!(replies[0]["code"], 20);
+        assert_eq!(replies[1]["type"], "cas_ok");
+
+        let error: KvReply<i64> = KvReply::Error {
+            code: 20,
+            text: "",
+            details: ErrorDetails::new(),
+        };
+        assert_eq!(
+            kv.dispatch(2, KvReply::<i64>::CasOk),
+            Some(("after cas", KvReply::CasOk))
+        );
+        assert_eq!(kv.dispatch(1, error.clone()), Some(("after read", error)));
+        assert_eq!(kv.dispatch(1, KvReply::<i64>::ReadOk(1)), None);
+    }
+
+    #[test]
+    fn kv_client_forgets_a_request_once_answered_or_timed_out() {
+        let clock = Clock::manual();
+        let mut out = Output::with_sink(VecSink::default());
+        let mut kv = KvClient::new("lin-kv", "n1", clock.clone());
+        kv.read(&mut out, 1, "k", "answered").unwrap();
+        kv.write(&mut out, 2, "k", 5, "timed out").unwrap();
+        assert_eq!(kv.pending().count(), 2);
+        assert_eq!(kv.oldest(), Some(clock.now()));
+
+        assert_eq!(
+            kv.dispatch(1, KvReply::<i64>::ReadOk(5)),
+            Some(("answered", KvReply::ReadOk(5)))
+        );
+        assert_eq!(kv.pending().collect::<Vec<_>>(), [&"timed out"]);
+        let timeout = Duration::from_secs(1);
+        assert!(kv.take_expired(timeout).is_empty());
+        clock.advance(timeout);
+        assert_eq!(kv.take_expired(timeout), ["timed out"]);
+        assert_eq!(kv.pending().count(), 0);
+        assert_eq!(kv.oldest(), None);
+        assert_eq!(kv.dispatch(2, KvReply::<i64>::WriteOk), None);
+    }
+
+    #[test]
+    fn the_tee_file_holds_every_line_sent() {
+        let path = std::env::temp_dir().join(format!("maelstrom-tee-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let runtime = Runtime {
+            tee_path: Some(path.to_str().unwrap().to_owned()),
+            ..Default::default()
+        };
+        let (tx, lines) = mpsc::channel();
+        for msg_id in [1, 2] {
+            let line = serde_json::json!({"src": "c1", "dest": "n1", "body": {"type": "echo", "msg_id": msg_id, "echo": "hi"}});
+            tx.send(Ok(line.to_string())).unwrap();
+        }
+        drop(tx);
+        let sink = VecSink::default();
+        runtime.run_lines(&mut Echo, lines, sink.clone()).unwrap();
+        let teed = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let sent = sink.take();
+        assert_eq!(sent.len(), 4);
+        assert_eq!(teed.lines().collect::<Vec<_>>(), sent);
+        assert!(teed.ends_with('\n'));
+    }
+
+    #[test]
+    fn trace_latency_adds_each_bodys_handling_time() {
+        for trace_latency in [true, false] {
+            let runtime = Runtime {
+                trace_latency,
+                ..Default::default()
+            };
+            let (tx, lines) = mpsc::channel();
+            let line = r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1,"echo":"hi"}}"#;
+            tx.send(Ok(line.to_owned())).unwrap();
+            drop(tx);
+            let sink = VecSink::default();
+            runtime.run_lines(&mut Echo, lines, sink.clone()).unwrap();
+            let reply: serde_json::Value = serde_json::from_str(&sink.take()[0]).unwrap();
+            let latency = reply["body"].get("internal_latency_us");
+            if trace_latency {
+                let latency = latency.and_then(|latency| latency.as_u64()).unwrap();
+                assert!(latency < 1_000_000, "{latency}us to echo");
+            } else {
+                assert_eq!(latency, None);
+            }
+            assert_eq!(reply["body"]["echo"], "hi");
+        }
+    }
+
+    #[test]
+    fn an_unparseable_line_is_skipped_unless_told_to_abort() {
+        for abort_on_parse_error in [false, true] {
+            let runtime = Runtime {
+                abort_on_parse_error,
+                ..Default::default()
+            };
+            let (tx, lines) = mpsc::channel();
+            for line in [
+                "not json",
+                r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1,"echo":"hi"}}"#,
+            ] {
+                tx.send(Ok(line.to_owned())).unwrap();
+            }
+            drop(tx);
+            let sink = VecSink::default();
+            let run = runtime.run_lines(&mut Echo, lines, sink.clone());
+            if abort_on_parse_error {
+                assert!(run.is_err());
+                assert!(sink.take().is_empty());
+            } else {
+                run.unwrap();
+                assert!(sink.take()[0].contains("echo_ok"));
+            }
+        }
+    }
+
+    #[test]
+    fn held_replies_go_out_after_what_the_input_sent_otherwise_first() {
+        for (hold_replies, order) in [(false, ["echo_ok", "raw"]), (true, ["raw", "echo_ok"])] {
+            let runtime = Runtime {
+                hold_replies,
+                ..Default::default()
+            };
+            let (tx, lines) = mpsc::channel();
+            let line = r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1,"echo":"hi"}}"#;
+            tx.send(Ok(line.to_owned())).unwrap();
+            drop(tx);
+            let sink = VecSink::default();
+            runtime.run_lines(&mut Echo, lines, sink.clone()).unwrap();
+            let sent = sink.take();
+            let kinds: Vec<&str> = sent
+                .iter()
+                .flat_map(|sent| sent.lines())
+                .map(|line| {
+                    if line.contains("echo_ok") {
+                        "echo_ok"
+                    } else {
+                        line
+                    }
+                })
+                .collect();
+            assert_eq!(kinds, order, "hold_replies: {hold_replies}");
+        }
+    }
+
+    /// A message that always fails to serialize.
+    struct Unserializable;
+
+    impl Serialize for Unserializable {
+        fn serialize<S: serde::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("can't be serialized"))
+        }
+    }
+
+    #[test]
+    fn a_message_that_fails_to_serialize_is_dropped_and_the_rest_still_go() {
+        let sink = VecSink::default();
+        let mut out = Output::with_sink(sink.clone());
+        let echo_ok = |in_reply_to| Message {
+            src: "n1",
+            dest: "c1",
+            body: Body::EchoOk {
+                in_reply_to,
+                echo: "hi".to_owned(),
+            },
+        };
+        out.send(&echo_ok(1)).unwrap();
+        out.send(&Unserializable).unwrap();
+        out.reply(&Unserializable).unwrap();
+        out.send(&echo_ok(2)).unwrap();
+        let sent = sink.take();
+        assert_eq!(sent.len(), 2);
+        assert!(sent[0].contains(r#""in_reply_to":1"#) && sent[1].contains(r#""in_reply_to":2"#));
+    }
+
+    #[test]
+    fn an_aliased_field_is_read_under_its_canonical_name() {
+        let runtime = Runtime {
+            aliases: &[FieldAlias {
+                kind: "echo",
+                alias: "text",
+                canonical: "echo",
+            }],
+            ..Default::default()
+        };
+        let (tx, lines) = mpsc::channel();
+        for line in [
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1,"text":"aliased"}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":2,"echo":"wins","text":"no"}}"#,
+        ] {
+            tx.send(Ok(line.to_owned())).unwrap();
+        }
+        drop(tx);
+        let sink = VecSink::default();
+        runtime.run_lines(&mut Echo, lines, sink.clone()).unwrap();
+        let echoed: Vec<String> = sink
+            .take()
+            .iter()
+            .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+            .map(|msg| msg["body"]["echo"].as_str().unwrap().to_owned())
+            .collect();
+        assert_eq!(echoed, ["aliased", "wins"]);
+    }
+
+    #[test]
+    fn a_reply_swaps_the_envelope_unless_given_a_src() {
+        let line = r#"{"src":"c1","dest":"proxy","body":{"type":"echo","msg_id":1,"echo":"x"}}"#;
+        let msg: Message<Body> = serde_json::from_str(line).unwrap();
+        let envelope = msg.envelope();
+        let reply = envelope.reply(());
+        assert_eq!((reply.src, reply.dest), ("proxy", "c1"));
+        let relayed = envelope.reply_from("n1", ());
+        assert_eq!((relayed.src, relayed.dest), ("n1", "c1"));
+    }
+}
@@ -0,0 +1,37 @@
+//! A rate limit on retries, shared by whatever a node retries.
+
+use crate::Clock;
+use std::time::Instant;
+
+/// Token bucket shared by every kind of retry, refilled continuously.
+pub struct RetryBudget {
+    per_sec: f64,
+    tokens: f64,
+    refilled: Instant,
+    clock: Clock,
+}
+
+impl RetryBudget {
+    /// A full bucket of `per_sec` retries a second, with bursts of up to a
+    /// second's worth, refilling by `clock`.
+    pub fn new(per_sec: u32, clock: Clock) -> Self {
+        RetryBudget {
+            per_sec: per_sec.into(),
+            tokens: per_sec.into(),
+            refilled: clock.now(),
+            clock,
+        }
+    }
+
+    /// Takes a token if one is left.
+    pub fn try_take(&mut self) -> bool {
+        let refill = self.clock.elapsed(self.refilled).as_secs_f64() * self.per_sec;
+        self.tokens = (self.tokens + refill).min(self.per_sec);
+        self.refilled = self.clock.now();
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
@@ -0,0 +1,289 @@
+//! An in-process harness for the nodes' tests, behind the `test-support`
+//! feature: `TestNode` drives one node the way `Runtime` would, and
+//! `Cluster` routes several nodes' output to each other on a manual `Clock`,
+//! with stand-ins for Maelstrom's KV services.
+
+use crate::{Clock, Message, Node, Output, VecSink};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    time::Duration,
+};
+
+/// The services `Cluster` answers for itself.
+const KV_SERVICES: [&str; 3] = ["seq-kv", "lin-kv", "lww-kv"];
+
+/// A node driven in-process, the way `Runtime` would drive it.
+pub struct TestNode<N> {
+    pub node: N,
+    id: String,
+    out: Output,
+    sink: VecSink,
+    /// Bytes of every line the node has sent, newlines left out.
+    pub bytes_sent: usize,
+}
+
+impl<N: Node> TestNode<N> {
+    /// `node`, initialised as `id` in a cluster of `ids`. Whatever the init
+    /// sent is dropped; `start` keeps it.
+    pub fn new(node: N, id: &str, ids: &[&str]) -> Self {
+        Self::start(node, id, ids).0
+    }
+
+    /// Like `new`, also returning everything the init sent.
+    pub fn start(node: N, id: &str, ids: &[&str]) -> (Self, Vec<Value>) {
+        let sink = VecSink::default();
+        let mut test = TestNode {
+            node,
+            id: id.to_owned(),
+            out: Output::with_sink(sink.clone()),
+            sink,
+            bytes_sent: 0,
+        };
+        let init = json!({"type": "init", "msg_id": 0, "node_id": id, "node_ids": ids});
+        let sent = test.handle("c0", init);
+        (test, sent)
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Handles `body` from `src`, runs the tick that follows, and returns
+    /// everything sent.
+    pub fn handle(&mut self, src: &str, body: Value) -> Vec<Value> {
+        let line = json!({"src": src, "dest": self.id, "body": body}).to_string();
+        self.node.received(&line);
+        let msg: Message<N::Body<'_>> = serde_json::from_str(&line).unwrap();
+        self.node.handle(msg, &mut self.out).unwrap();
+        self.tick()
+    }
+
+    /// The body of the reply to `body` from a client, which must be the
+    /// only message it causes.
+    pub fn request(&mut self, body: Value) -> Value {
+        let mut sent = self.handle("c1", body);
+        assert_eq!(sent.len(), 1, "{sent:?}");
+        sent.remove(0)["body"].take()
+    }
+
+    pub fn tick(&mut self) -> Vec<Value> {
+        self.node.tick(&mut self.out).unwrap();
+        self.take()
+    }
+
+    /// Everything sent since the last call.
+    pub fn take(&mut self) -> Vec<Value> {
+        let lines = self.sink.take();
+        self.bytes_sent += lines.iter().map(String::len).sum::<usize>();
+        lines
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+}
+
+/// What becomes of a message `Cluster` is given to deliver.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fate {
+    Deliver,
+    /// Go in flight only after this many other deliveries.
+    Delay(u64),
+    Drop,
+}
+
+/// A stand-in for one of Maelstrom's KV services, linearizable and
+/// answering at once.
+#[derive(Default)]
+pub struct KvStore {
+    /// Each key's value, keys that aren't strings by their JSON.
+    pub values: HashMap<String, Value>,
+}
+
+impl KvStore {
+    /// Applies a `read`, `write` or `cas` body, returning the reply's body.
+    pub fn reply(&mut self, body: &Value) -> Value {
+        let id = body["msg_id"].clone();
+        let key = match &body["key"] {
+            Value::String(key) => key.clone(),
+            key => key.to_string(),
+        };
+        let error = |code| json!({"type": "error", "in_reply_to": id, "code": code, "text": ""});
+        match (body["type"].as_str().unwrap(), self.values.get(&key)) {
+            ("read", Some(value)) => json!({"type": "read_ok", "in_reply_to": id, "value": value}),
+            ("read", None) => error(20),
+            ("write", _) => {
+                self.values.insert(key, body["value"].clone());
+                json!({"type": "write_ok", "in_reply_to": id})
+            }
+            ("cas", current) => {
+                match current {
+                    None if body["create_if_not_exists"] != true => return error(20),
+                    Some(current) if *current != body["from"] => return error(22),
+                    _ => {}
+                }
+                self.values.insert(key, body["to"].clone());
+                json!({"type": "cas_ok", "in_reply_to": id})
+            }
+            (kind, _) => panic!("a KV service can't {kind}"),
+        }
+    }
+}
+
+/// Nodes whose output is routed to each other's input in-process, one
+/// message at a time in the order they were sent, so a run is the same
+/// every time. Messages to a KV service are answered by its `KvStore`;
+/// messages for anyone else, i.e. clients, are kept in `replies`.
+pub struct Cluster<N> {
+    pub nodes: BTreeMap<String, TestNode<N>>,
+    ids: Vec<String>,
+    /// The time every node is built with, moved only by `pump_until`, or
+    /// by hand.
+    pub clock: Clock,
+    /// Messages sent but not yet delivered, oldest first.
+    pub in_flight: VecDeque<Value>,
+    /// Delayed messages, with the step after which they go in flight.
+    pub held: Vec<(u64, Value)>,
+    pub replies: Vec<Value>,
+    /// Decides each message's fate as it is sent.
+    pub schedule: Box<dyn FnMut(&Value) -> Fate>,
+    /// Messages delivered so far.
+    pub steps: u64,
+    /// Every message sent, with what became of it, in order.
+    pub log: Vec<(Fate, Value)>,
+    /// The KV services, by name, each made at its first request.
+    pub kv: BTreeMap<String, KvStore>,
+}
+
+impl<N: Node> Cluster<N> {
+    /// Nodes `ids`, each built by `node` given the cluster's clock, then
+    /// initialised, with what their inits sent delivered. The `init_ok`s
+    /// are dropped, and the log starts after them.
+    pub fn new(ids: &[&str], node: impl Fn(Clock) -> N) -> Self {
+        let clock = Clock::manual();
+        let mut cluster = Cluster {
+            nodes: BTreeMap::new(),
+            ids: ids.iter().map(|&id| id.to_owned()).collect(),
+            clock: clock.clone(),
+            in_flight: VecDeque::new(),
+            held: Vec::new(),
+            replies: Vec::new(),
+            schedule: Box::new(|_| Fate::Deliver),
+            steps: 0,
+            log: Vec::new(),
+            kv: BTreeMap::new(),
+        };
+        for &id in ids {
+            cluster.start(id, node(clock.clone()));
+        }
+        cluster.pump_until(Duration::ZERO, |_| false);
+        cluster.replies.clear();
+        cluster.log.clear();
+        cluster
+    }
+
+    /// Initialises `node` as `id`, replacing any node already there, as a
+    /// restart would, and puts what the init sent in flight.
+    pub fn start(&mut self, id: &str, node: N) {
+        let ids: Vec<&str> = self.ids.iter().map(String::as_str).collect();
+        let (node, sent) = TestNode::start(node, id, &ids);
+        self.nodes.insert(id.to_owned(), node);
+        self.send(sent);
+    }
+
+    /// Queues `body` from a client to `id`.
+    pub fn client(&mut self, id: &str, body: Value) {
+        self.send(vec![json!({"src": "c1", "dest": id, "body": body})]);
+    }
+
+    /// Sends `body` from a client to `id`, delivers what that causes until
+    /// the cluster is quiet, and returns the bodies of every client reply
+    /// that came back meanwhile.
+    pub fn request(&mut self, id: &str, body: Value) -> Vec<Value> {
+        self.client(id, body);
+        self.pump_until(Duration::ZERO, |_| false);
+        self.replies
+            .drain(..)
+            .map(|mut reply| reply["body"].take())
+            .collect()
+    }
+
+    /// Puts `sent` in flight, held back or dropped as `schedule` says.
+    pub fn send(&mut self, sent: Vec<Value>) {
+        for msg in sent {
+            let fate = (self.schedule)(&msg);
+            match fate {
+                Fate::Deliver => self.in_flight.push_back(msg.clone()),
+                Fate::Delay(steps) => self.held.push((self.steps + steps, msg.clone())),
+                Fate::Drop => {}
+            }
+            self.log.push((fate, msg));
+        }
+    }
+
+    /// Delivers the oldest message in flight, returning false if there is
+    /// none. Held messages go in flight once due, or all at once when
+    /// nothing else is.
+    pub fn step(&mut self) -> bool {
+        let (steps, idle) = (self.steps, self.in_flight.is_empty());
+        let (due, held): (Vec<_>, Vec<_>) = std::mem::take(&mut self.held)
+            .into_iter()
+            .partition(|&(at, _)| idle || at <= steps);
+        self.held = held;
+        self.in_flight.extend(due.into_iter().map(|(_, msg)| msg));
+        let Some(mut msg) = self.in_flight.pop_front() else {
+            return false;
+        };
+        self.steps += 1;
+        let dest = msg["dest"].as_str().unwrap().to_owned();
+        if let Some(node) = self.nodes.get_mut(&dest) {
+            let src = msg["src"].as_str().unwrap().to_owned();
+            let sent = node.handle(&src, msg["body"].take());
+            self.send(sent);
+        } else if KV_SERVICES.contains(&dest.as_str()) {
+            let reply = self.kv.entry(dest.clone()).or_default().reply(&msg["body"]);
+            let src = msg["src"].clone();
+            self.send(vec![json!({"src": dest, "dest": src, "body": reply})]);
+        } else {
+            self.replies.push(msg);
+        }
+        true
+    }
+
+    /// Ticks every node, putting what they send in flight.
+    pub fn tick(&mut self) {
+        let sent: Vec<Value> = self.nodes.values_mut().flat_map(TestNode::tick).collect();
+        self.send(sent);
+    }
+
+    /// Whether nothing is in flight or held.
+    pub fn quiet(&self) -> bool {
+        self.in_flight.is_empty() && self.held.is_empty()
+    }
+
+    /// Delivers messages until `done` holds or, once nothing is in flight,
+    /// `timeout` has passed on the clock. Whenever nothing is in flight the
+    /// clock jumps to the soonest node timer, which is then run by ticking
+    /// every node. Returns whether `done` held.
+    pub fn pump_until(&mut self, timeout: Duration, done: impl Fn(&Self) -> bool) -> bool {
+        let deadline = self.clock.now() + timeout;
+        loop {
+            if done(self) {
+                return true;
+            }
+            if self.step() {
+                continue;
+            }
+            let left = deadline.saturating_duration_since(self.clock.now());
+            if left.is_zero() {
+                return false;
+            }
+            let soonest = self.nodes.values().map(|node| node.node.timeout()).min();
+            // A node that wants ticking right away gets it without the
+            // clock moving, but the clock can't stand still for good.
+            let wait = soonest.unwrap_or(left).max(Duration::from_millis(1));
+            self.clock.advance(wait.min(left));
+            self.tick();
+        }
+    }
+}
@@ -0,0 +1,193 @@
+//! A small `tracing` subscriber that writes spans and events as text lines,
+//! so the nodes' spans can be seen without pulling in `tracing-subscriber`.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Write as _},
+    io::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    thread::{self, ThreadId},
+};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Level, Metadata, Subscriber,
+};
+
+/// Writes a line for every span entered and every event, each prefixed with
+/// the spans it happened in, e.g.
+/// `DEBUG handle{src=c1 dest=n1 kind=add} kv_request{service=seq-kv msg_id=2}`.
+pub struct LineSubscriber {
+    level: Level,
+    writer: Mutex<Box<dyn Write + Send>>,
+    next_id: AtomicU64,
+    spans: Mutex<HashMap<u64, SpanData>>,
+    /// Each thread's spans entered and not yet exited, innermost last, so
+    /// the watchdog's spans stay out of the handler's context.
+    stacks: Mutex<HashMap<ThreadId, Vec<u64>>>,
+}
+
+struct SpanData {
+    name: &'static str,
+    level: Level,
+    /// `field=value ...`, updated as fields are recorded.
+    fields: String,
+    refs: usize,
+}
+
+impl LineSubscriber {
+    /// Records spans and events at `level` and above to `writer`.
+    pub fn new(level: Level, writer: impl Write + Send + 'static) -> Self {
+        LineSubscriber {
+            level,
+            writer: Mutex::new(Box::new(writer)),
+            next_id: AtomicU64::new(1),
+            spans: Mutex::new(HashMap::new()),
+            stacks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The spans this thread has entered, outermost first, as `name{fields}`.
+    fn context(&self) -> String {
+        let spans = self.spans.lock().unwrap();
+        let stacks = self.stacks.lock().unwrap();
+        let stack = stacks.get(&thread::current().id());
+        let mut context = String::new();
+        for span in stack.into_iter().flatten().filter_map(|id| spans.get(id)) {
+            if !context.is_empty() {
+                context.push(' ');
+            }
+            let _ = write!(context, "{}{{{}}}", span.name, span.fields);
+        }
+        context
+    }
+
+    fn write_line(&self, level: &Level, line: &str) {
+        let mut writer = self.writer.lock().unwrap();
+        let _ = writeln!(writer, "{level:>5} {line}");
+    }
+}
+
+impl Subscriber for LineSubscriber {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        *metadata.level() <= self.level
+    }
+
+    fn new_span(&self, attrs: &span::Attributes<'_>) -> span::Id {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        let span = SpanData {
+            name: attrs.metadata().name(),
+            level: *attrs.metadata().level(),
+            fields: fields.0,
+            refs: 1,
+        };
+        self.spans.lock().unwrap().insert(id, span);
+        span::Id::from_u64(id)
+    }
+
+    fn record(&self, id: &span::Id, values: &span::Record<'_>) {
+        if let Some(span) = self.spans.lock().unwrap().get_mut(&id.into_u64()) {
+            let mut fields = Fields(std::mem::take(&mut span.fields));
+            values.record(&mut fields);
+            span.fields = fields.0;
+        }
+    }
+
+    fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let context = self.context();
+        let line = if context.is_empty() {
+            fields.0
+        } else {
+            format!("{context}: {}", fields.0)
+        };
+        self.write_line(event.metadata().level(), &line);
+    }
+
+    fn enter(&self, id: &span::Id) {
+        self.stacks
+            .lock()
+            .unwrap()
+            .entry(thread::current().id())
+            .or_default()
+            .push(id.into_u64());
+        let level = self
+            .spans
+            .lock()
+            .unwrap()
+            .get(&id.into_u64())
+            .map(|span| span.level);
+        if let Some(level) = level {
+            self.write_line(&level, &self.context());
+        }
+    }
+
+    fn exit(&self, id: &span::Id) {
+        let mut stacks = self.stacks.lock().unwrap();
+        let thread = thread::current().id();
+        let Some(stack) = stacks.get_mut(&thread) else {
+            return;
+        };
+        if let Some(i) = stack.iter().rposition(|&entered| entered == id.into_u64()) {
+            stack.remove(i);
+        }
+        if stack.is_empty() {
+            stacks.remove(&thread);
+        }
+    }
+
+    fn clone_span(&self, id: &span::Id) -> span::Id {
+        if let Some(span) = self.spans.lock().unwrap().get_mut(&id.into_u64()) {
+            span.refs += 1;
+        }
+        id.clone()
+    }
+
+    fn try_close(&self, id: span::Id) -> bool {
+        let mut spans = self.spans.lock().unwrap();
+        let Some(span) = spans.get_mut(&id.into_u64()) else {
+            return false;
+        };
+        span.refs -= 1;
+        if span.refs > 0 {
+            return false;
+        }
+        spans.remove(&id.into_u64());
+        true
+    }
+}
+
+/// Formats recorded fields as `name=value`, except an event's message,
+/// which is written as is.
+#[derive(Default)]
+struct Fields(String);
+
+impl Fields {
+    fn push(&mut self, field: &Field, value: fmt::Arguments<'_>) {
+        if !self.0.is_empty() {
+            self.0.push(' ');
+        }
+        if field.name() == "message" {
+            let _ = self.0.write_fmt(value);
+        } else {
+            let _ = write!(self.0, "{}={value}", field.name());
+        }
+    }
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field, format_args!("{value}"));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.push(field, format_args!("{value:?}"));
+    }
+}
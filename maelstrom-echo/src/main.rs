@@ -1,22 +1,21 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use maelstrom_core::{
+    env_flag, env_parse, error_details, Clock, ErrorDetails, FieldAlias, FsyncPolicy, KvClient,
+    KvReply, Message, Output, RetryBudget, Runtime,
+};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{HashMap, HashSet},
-    io::{self, BufRead}, time::Duration,
+    collections::{hash_map::RandomState, BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    hash::{BuildHasher, DefaultHasher, Hash, Hasher},
+    io::{self, Write},
+    ops::Bound,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant, UNIX_EPOCH},
 };
 
-#[derive(Deserialize, Serialize)]
-struct Request<'a> {
-    src: &'a str,
-    dest: &'a str,
-    body: RequestBody<'a>,
-}
-
-#[derive(Deserialize, Serialize)]
-struct Response<'a> {
-    src: &'a str,
-    dest: &'a str,
-    body: ResponseBody<'a>,
-}
+type Request<'a> = Message<'a, RequestBody<'a>>;
+type Response<'a> = Message<'a, ResponseBody<'a>>;
 
 #[derive(Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
@@ -26,11 +25,18 @@ enum RequestBody<'a> {
         msg_id: i64,
         node_id: String,
         node_ids: Vec<String>,
+        /// Overrides `Config::kv_service`.
+        #[serde(default)]
+        kv_service: Option<String>,
     },
     Echo {
         msg_id: i64,
         echo: &'a str,
     },
+    EchoBatch {
+        msg_id: i64,
+        echos: Vec<String>,
+    },
     Generate {
         msg_id: i64,
     },
@@ -40,11 +46,25 @@ enum RequestBody<'a> {
     },
     Read {
         msg_id: i64,
+        #[serde(default)]
+        consistency: Option<Consistency>,
+        #[serde(default)]
+        key: Option<String>,
     },
     ReadOk {
         in_reply_to: i64,
         value: i64,
     },
+    PeerRead {
+        msg_id: i64,
+        key: String,
+    },
+    PeerReadOk {
+        in_reply_to: i64,
+        value: i64,
+        #[serde(default)]
+        age_ms: Option<u64>,
+    },
     Topology {
         msg_id: i64,
         topology: HashMap<&'a str, Vec<String>>,
@@ -53,17 +73,100 @@ enum RequestBody<'a> {
         in_reply_to: i64,
         code: i8,
         text: &'a str,
+        #[serde(default)]
+        details: ErrorDetails,
     },
     Update {
-        messages: HashSet<i64>,
+        #[serde(default)]
+        msg_id: Option<i64>,
+        messages: BTreeSet<i64>,
+        /// The sender's `Node::generation` when it sent this set.
+        #[serde(default)]
+        generation: Option<u64>,
+        /// `messages` is only what the sender just learned, not its whole
+        /// set, so it's merged however old its generation is.
+        #[serde(default)]
+        delta: bool,
+        /// The node whose tree `origin-tree` gossip is following.
+        #[serde(default)]
+        origin: Option<String>,
+    },
+    /// An `update` whose set is bincode, base64-encoded; see `encode_compact`.
+    CompactUpdate {
+        #[serde(default)]
+        msg_id: Option<i64>,
+        messages: String,
+        #[serde(default)]
+        generation: Option<u64>,
+        #[serde(default)]
+        delta: bool,
+        #[serde(default)]
+        origin: Option<String>,
+    },
+    UpdateOk {
+        in_reply_to: i64,
     },
+    /// Sent to every peer at init to advertise optional wire features.
+    Capabilities {
+        compact: bool,
+    },
+    /// A counter increment, or with `element` a g-set add.
     Add {
         msg_id: i64,
-        delta: i64,
+        #[serde(default)]
+        key: Option<String>,
+        #[serde(default)]
+        delta: Option<i64>,
+        #[serde(default)]
+        element: Option<i64>,
     },
     CasOk {
         in_reply_to: i64,
     },
+    Contains {
+        msg_id: i64,
+        value: i64,
+    },
+    /// Up to `limit` messages greater than `after`, in ascending order. A
+    /// `limit` of 0 is rejected, since it could never make progress.
+    ReadPage {
+        msg_id: i64,
+        #[serde(default)]
+        after: Option<i64>,
+        limit: usize,
+    },
+    Stats {
+        msg_id: i64,
+        #[serde(default)]
+        reset: bool,
+    },
+    Health {
+        msg_id: i64,
+    },
+    DumpConfig {
+        msg_id: i64,
+    },
+    /// Exchanges full sets with `peer` right away instead of waiting for
+    /// anti-entropy. Debug only.
+    Reconcile {
+        msg_id: i64,
+        peer: String,
+    },
+    /// A peer's full set, answered with an `update` of whatever it lacks.
+    Digest {
+        messages: BTreeSet<i64>,
+    },
+    /// The fewest-hop route to `target` over the given topology.
+    Path {
+        msg_id: i64,
+        target: String,
+    },
+    /// A list-append transaction: `["r", key, null]` and
+    /// `["append", key, value]` micro-ops, applied in order.
+    Txn {
+        msg_id: i64,
+        txn: Vec<(&'a str, i64, Option<i64>)>,
+    },
 }
 
 #[derive(Deserialize, Serialize)]
@@ -78,6 +181,11 @@ enum ResponseBody<'a> {
         in_reply_to: i64,
         echo: &'a str,
     },
+    EchoBatchOk {
+        msg_id: i64,
+        in_reply_to: i64,
+        echos: Vec<String>,
+    },
     GenerateOk {
         msg_id: i64,
         in_reply_to: i64,
@@ -87,14 +195,30 @@ enum ResponseBody<'a> {
         msg_id: i64,
         in_reply_to: i64,
     },
-    Read {
+    /// A g-set `read_ok`: the whole message set.
+    #[serde(rename = "read_ok")]
+    ReadOkSet {
         msg_id: i64,
-        key: i64,
+        in_reply_to: i64,
+        value: Vec<i64>,
     },
     ReadOk {
         msg_id: i64,
         in_reply_to: i64,
         value: i64,
+        /// Milliseconds since `value` was last seen in seq-kv, if ever.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        age_ms: Option<u64>,
+    },
+    PeerRead {
+        msg_id: i64,
+        key: String,
+    },
+    PeerReadOk {
+        in_reply_to: i64,
+        value: i64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        age_ms: Option<u64>,
     },
     TopologyOk {
         msg_id: i64,
@@ -104,248 +228,2390 @@ enum ResponseBody<'a> {
         in_reply_to: i64,
         code: i8,
         text: &'a str,
+        #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+        details: ErrorDetails,
     },
     Update {
-        messages: HashSet<i64>,
+        msg_id: i64,
+        messages: BTreeSet<i64>,
+        generation: u64,
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        delta: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        origin: Option<String>,
+    },
+    CompactUpdate {
+        msg_id: i64,
+        messages: String,
+        generation: u64,
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        delta: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        origin: Option<String>,
+    },
+    Capabilities {
+        compact: bool,
+    },
+    UpdateOk {
+        in_reply_to: i64,
     },
     AddOk {
         msg_id: i64,
         in_reply_to: i64,
     },
-    Cas {
+    ContainsOk {
+        msg_id: i64,
+        in_reply_to: i64,
+        present: bool,
+    },
+    ReadPageOk {
+        msg_id: i64,
+        in_reply_to: i64,
+        messages: Vec<i64>,
+        /// Pass as `after` to fetch the next page; absent on the last one.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        next: Option<i64>,
+    },
+    StatsOk {
+        msg_id: i64,
+        in_reply_to: i64,
+        metrics: Metrics,
+    },
+    HealthOk {
+        msg_id: i64,
+        in_reply_to: i64,
+        status: Health,
+    },
+    /// The effective `Config`, init overrides included.
+    DumpConfigOk {
+        msg_id: i64,
+        in_reply_to: i64,
+        config: serde_json::Value,
+    },
+    ReconcileOk {
+        msg_id: i64,
+        in_reply_to: i64,
+    },
+    Digest {
+        messages: BTreeSet<i64>,
+    },
+    /// The hops to the target, ending with it; empty if it is unreachable.
+    PathOk {
+        msg_id: i64,
+        in_reply_to: i64,
+        path: Vec<String>,
+    },
+    /// The transaction's micro-ops, each read's null replaced by the list.
+    TxnOk {
         msg_id: i64,
-        key: i64,
-        from: i64,
-        to: i64,
-        create_if_not_exists: bool,
+        in_reply_to: i64,
+        txn: Vec<(&'a str, i64, serde_json::Value)>,
     },
 }
 
+/// Readiness as seen from this node's liveness tracking (see `Node::is_dead`).
+#[derive(Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Health {
+    /// Every peer and the KV service are answering.
+    Ok,
+    /// Some peers are unreachable, but a majority of nodes and the KV
+    /// service still are.
+    Degraded,
+    /// The KV service or a majority of nodes is unreachable.
+    Partitioned,
+}
+
+/// How gossip peers are chosen once `topology` arrives.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum TopologyStrategy {
+    /// Forward along a spanning tree of the given topology.
+    #[default]
+    SpanningTree,
+    /// Forward to the neighbours Maelstrom suggested.
+    Given,
+    /// Forward to a few randomly chosen peers each round, relying on
+    /// anti-entropy to fill the gaps.
+    RandomSubset,
+    /// Forward along a spanning tree of the given topology rooted at the
+    /// node the client broadcast to, so each new value crosses N-1 edges
+    /// from wherever it entered. Floods to the given neighbours where the
+    /// tree doesn't reach this node.
+    OriginTree,
+}
+
+/// Which alternative field names are accepted on input.
+#[derive(Default, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum FieldProfile {
+    /// Only the names the current Maelstrom workloads use.
+    #[default]
+    Canonical,
+    /// Also the spellings older workload versions used.
+    Legacy,
+}
+
+impl FieldProfile {
+    fn aliases(self) -> &'static [FieldAlias] {
+        match self {
+            FieldProfile::Canonical => &[],
+            FieldProfile::Legacy => &[
+                FieldAlias {
+                    kind: "broadcast",
+                    alias: "msg",
+                    canonical: "message",
+                },
+                FieldAlias {
+                    kind: "broadcast",
+                    alias: "value",
+                    canonical: "message",
+                },
+            ],
+        }
+    }
+}
+
+/// How a counter `read` is served.
+#[derive(Default, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Consistency {
+    /// Read the value from seq-kv.
+    #[default]
+    ReadOne,
+    /// Ask a majority of nodes for their last known value and take the max,
+    /// so an increment confirmed on another node is visible right away.
+    ReadQuorum,
+}
+
+/// What kind of counter `add` and `read` maintain in seq-kv.
+#[derive(Default, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum CounterMode {
+    /// Deltas are never negative, so the highest value seen is the newest.
+    #[default]
+    GCounter,
+    /// Deltas may be negative, so the most recently observed value wins.
+    PnCounter,
+}
+
+/// Which `PayloadHasher` `ECHO_DEDUP_HASH` picks.
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum DedupHash {
+    Fx,
+    Sip,
+}
+
+impl DedupHash {
+    fn hash(self, messages: &BTreeSet<i64>) -> u64 {
+        match self {
+            DedupHash::Fx => FxPayloadHasher.hash(messages),
+            DedupHash::Sip => SipPayloadHasher.hash(messages),
+        }
+    }
+}
+
+/// Hashes a gossiped set, to tell a peer's resent set from a new one.
+trait PayloadHasher {
+    fn hash(&self, messages: &BTreeSet<i64>) -> u64;
+}
+
+/// FxHash, rustc's hasher: fast, but easy to collide on purpose.
+struct FxPayloadHasher;
+
+impl PayloadHasher for FxPayloadHasher {
+    fn hash(&self, messages: &BTreeSet<i64>) -> u64 {
+        const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+        let mut hash = (messages.len() as u64).wrapping_mul(SEED);
+        for &value in messages {
+            hash = (hash.rotate_left(5) ^ value as u64).wrapping_mul(SEED);
+        }
+        hash
+    }
+}
+
+/// SipHash-1-3 through std's `DefaultHasher`, for when collisions matter
+/// more than speed.
+struct SipPayloadHasher;
+
+impl PayloadHasher for SipPayloadHasher {
+    fn hash(&self, messages: &BTreeSet<i64>) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        messages.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+/// Runtime options, read from the environment at startup. `Default` gives
+/// the documented defaults, as if no variable were set.
+#[derive(Serialize)]
+struct Config {
+    /// Enables test/debug-only requests such as `stats` resets (`ECHO_DEBUG=1`).
+    debug: bool,
+    /// Consistency for counter reads that don't ask for one
+    /// (`ECHO_READ_CONSISTENCY=read-one|read-quorum`).
+    read_consistency: Consistency,
+    /// `ECHO_COUNTER_MODE=g-counter|pn-counter`. A g-counter rejects
+    /// negative deltas.
+    counter_mode: CounterMode,
+    /// Also append every outgoing message to this NDJSON file (`ECHO_TEE_PATH`).
+    tee_path: Option<String>,
+    /// When the tee file is fsynced (`ECHO_FSYNC`): `always`, after every
+    /// input; `interval`, at most every `ECHO_FSYNC_INTERVAL_MS`, 1000 by
+    /// default; or `never`, the default.
+    fsync: FsyncPolicy,
+    /// Cap on gossip messages sent per loop iteration; the rest wait for the
+    /// next one (`ECHO_MAX_GOSSIP_PER_ITERATION`, unbounded by default).
+    max_gossip_per_iteration: Option<usize>,
+    /// Time one input may spend, gossip included, before the remaining gossip
+    /// is deferred to the next iteration (`ECHO_ITERATION_BUDGET_MS`).
+    iteration_budget: Option<Duration>,
+    /// Hold gossip for this long after the first change is queued, merging
+    /// later changes for the same peer into the same `update`
+    /// (`ECHO_GOSSIP_BATCH_MS`, sent at once by default).
+    gossip_batch_interval: Option<Duration>,
+    /// Send held gossip early once the batch for one peer has this many
+    /// values (`ECHO_GOSSIP_BATCH_SIZE`, no limit by default).
+    gossip_batch_size: Option<usize>,
+    /// Add `internal_latency_us` to every outgoing body, measured from when
+    /// the input being handled was received (`ECHO_TRACE_LATENCY=1`). Leave
+    /// it off for scored runs.
+    trace_latency: bool,
+    /// Stop the node on an input line that isn't a valid message instead of
+    /// logging it to stderr and moving on (`ECHO_ABORT_ON_PARSE_ERROR=1`).
+    abort_on_parse_error: bool,
+    /// Write spans and events at this level and above to stderr
+    /// (`ECHO_TRACE=error|warn|info|debug|trace`, off by default).
+    trace: Option<String>,
+    /// Gossip peer selection
+    /// (`ECHO_TOPOLOGY=spanning-tree|given|random-subset|origin-tree`).
+    topology: TopologyStrategy,
+    /// Clusters with more nodes than this use random-subset gossip whatever
+    /// `topology` asks for (`ECHO_MAX_TOPOLOGY_NODES`, no limit by default).
+    max_topology_nodes: Option<usize>,
+    /// Peers picked per round by random-subset gossip (`ECHO_GOSSIP_FANOUT`,
+    /// default 3).
+    gossip_fanout: usize,
+    /// Leave a peer out of broadcast gossip once it has gone this long
+    /// without sending anything after we last sent to it; anti-entropy keeps
+    /// probing it. The same timeout decides what `health` reports
+    /// (`ECHO_PEER_TIMEOUT_MS`, never by default).
+    peer_timeout: Option<Duration>,
+    /// Gossip to peers that also support it in the compact encoding instead
+    /// of a JSON array (`ECHO_COMPACT_GOSSIP=1`).
+    compact_gossip: bool,
+    /// Only compact sets of more than this many messages; smaller ones are
+    /// cheap enough as JSON (`ECHO_COMPACT_THRESHOLD`, default 0).
+    compact_threshold: usize,
+    /// KV service backing the counter and id generator: `seq-kv`, `lin-kv`
+    /// or `lww-kv` (`ECHO_KV_SERVICE`, default `seq-kv`, or the `kv_service`
+    /// field of `init`).
+    kv_service: String,
+    /// Give up on a KV request that has gone unanswered this long: a counter
+    /// operation fails back to its client with error 0 (timeout), a
+    /// `generate` falls back to a local id (`ECHO_KV_TIMEOUT_MS`, default
+    /// 1000).
+    kv_timeout: Duration,
+    /// Fail a `read-quorum` with error 11 once it has waited this long for a
+    /// majority, counting the peers that haven't answered as failed
+    /// (`ECHO_QUORUM_TIMEOUT_MS`, default 1000).
+    quorum_timeout: Duration,
+    /// Send the gossip an input causes before its reply rather than after,
+    /// so a peer can have the message by the time the client sees the ack
+    /// (`ECHO_GOSSIP_BEFORE_REPLY=1`). Gossip held back by
+    /// `max_gossip_per_iteration` or `iteration_budget` still goes later.
+    gossip_before_reply: bool,
+    /// Warn on stderr when the main loop goes this long without finishing
+    /// an input or timer (`ECHO_STALL_WARNING_MS`, off by default). Idle
+    /// waits last up to `ANTI_ENTROPY_INTERVAL`, so keep this above it.
+    stall_warning: Option<Duration>,
+    /// Test-only: keep at most this many broadcast messages, evicting the
+    /// least recently received. This loses data and breaks the broadcast
+    /// guarantees; it only exists so endless fuzz runs don't run out of
+    /// memory (`ECHO_MAX_MESSAGES`, unbounded by default).
+    max_messages: Option<usize>,
+    /// Retries per second allowed across seq-kv CAS/read retries and
+    /// anti-entropy gossip together, with bursts of up to a second's worth
+    /// (`ECHO_RETRY_BUDGET`, unlimited by default). Once it runs out, KV
+    /// operations fail back to the client and gossip waits for the next
+    /// anti-entropy round.
+    retry_budget: Option<u32>,
+    /// Field spellings to accept from clients (`ECHO_FIELD_PROFILE=canonical|legacy`).
+    field_profile: FieldProfile,
+    /// Ack every `update` and leave peers that acked the current set out of
+    /// anti-entropy (`ECHO_GOSSIP_ACKS=1`). Costs a reply per gossip message
+    /// but keeps anti-entropy quiet once the cluster has converged. An
+    /// unacked `update` is also resent on its own, with exponential backoff.
+    gossip_acks: bool,
+    /// Forget a broadcast value this long after this node first saw it, and
+    /// refuse it for as long again (`ECHO_MESSAGE_TTL_MS`, kept forever by
+    /// default). Each node times values from its own first sighting, so by
+    /// the time the refusal ends its peers have forgotten the value too.
+    message_ttl: Option<Duration>,
+    /// CASes an `add` may lose to concurrent writers before failing with
+    /// error 30 (`ECHO_MAX_CAS_ATTEMPTS`, default 10). Each retry also
+    /// spends `retry_budget`.
+    max_cas_attempts: u32,
+    /// Serve `read`s that name no counter key from the message set, for the
+    /// g-set workload (`ECHO_G_SET=1`). `add` with an `element` always adds
+    /// to the set.
+    g_set: bool,
+    /// Skip merging a peer's whole set when it hashes the same as the last
+    /// one that peer sent (`ECHO_DEDUP_HASH=fx|sip`, off by default). A
+    /// skipped set doesn't refresh its values for `max_messages`.
+    dedup_hash: Option<DedupHash>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            debug: false,
+            read_consistency: Consistency::ReadOne,
+            counter_mode: CounterMode::GCounter,
+            tee_path: None,
+            fsync: FsyncPolicy::Never,
+            max_gossip_per_iteration: None,
+            iteration_budget: None,
+            gossip_batch_interval: None,
+            gossip_batch_size: None,
+            trace_latency: false,
+            abort_on_parse_error: false,
+            trace: None,
+            topology: TopologyStrategy::SpanningTree,
+            max_topology_nodes: None,
+            gossip_fanout: 3,
+            peer_timeout: None,
+            compact_gossip: false,
+            compact_threshold: 0,
+            kv_service: "seq-kv".to_owned(),
+            kv_timeout: Duration::from_millis(1000),
+            quorum_timeout: Duration::from_millis(1000),
+            gossip_before_reply: false,
+            stall_warning: None,
+            max_messages: None,
+            retry_budget: None,
+            field_profile: FieldProfile::Canonical,
+            gossip_acks: false,
+            message_ttl: None,
+            max_cas_attempts: 10,
+            g_set: false,
+            dedup_hash: None,
+        }
+    }
+}
+
+impl Config {
+    fn from_env() -> Self {
+        let default = Config::default();
+        Config {
+            debug: env_flag("ECHO_DEBUG"),
+            read_consistency: match std::env::var("ECHO_READ_CONSISTENCY").as_deref() {
+                Ok("read-quorum") => Consistency::ReadQuorum,
+                Ok("read-one") => Consistency::ReadOne,
+                _ => default.read_consistency,
+            },
+            counter_mode: match std::env::var("ECHO_COUNTER_MODE").as_deref() {
+                Ok("pn-counter") => CounterMode::PnCounter,
+                Ok("g-counter") => CounterMode::GCounter,
+                _ => default.counter_mode,
+            },
+            tee_path: std::env::var("ECHO_TEE_PATH").ok(),
+            fsync: match std::env::var("ECHO_FSYNC").as_deref() {
+                Ok("always") => FsyncPolicy::Always,
+                Ok("interval") => FsyncPolicy::Interval(Duration::from_millis(
+                    env_parse("ECHO_FSYNC_INTERVAL_MS").unwrap_or(1000),
+                )),
+                Ok("never") => FsyncPolicy::Never,
+                _ => default.fsync,
+            },
+            max_gossip_per_iteration: env_parse("ECHO_MAX_GOSSIP_PER_ITERATION"),
+            iteration_budget: env_parse("ECHO_ITERATION_BUDGET_MS").map(Duration::from_millis),
+            gossip_batch_interval: env_parse("ECHO_GOSSIP_BATCH_MS").map(Duration::from_millis),
+            gossip_batch_size: env_parse("ECHO_GOSSIP_BATCH_SIZE"),
+            trace_latency: env_flag("ECHO_TRACE_LATENCY"),
+            abort_on_parse_error: env_flag("ECHO_ABORT_ON_PARSE_ERROR"),
+            trace: std::env::var("ECHO_TRACE").ok(),
+            topology: match std::env::var("ECHO_TOPOLOGY").as_deref() {
+                Ok("given") => TopologyStrategy::Given,
+                Ok("origin-tree") => TopologyStrategy::OriginTree,
+                Ok("random-subset") => TopologyStrategy::RandomSubset,
+                Ok("spanning-tree") => TopologyStrategy::SpanningTree,
+                _ => default.topology,
+            },
+            max_topology_nodes: env_parse("ECHO_MAX_TOPOLOGY_NODES"),
+            gossip_fanout: env_parse("ECHO_GOSSIP_FANOUT").unwrap_or(default.gossip_fanout),
+            peer_timeout: env_parse("ECHO_PEER_TIMEOUT_MS").map(Duration::from_millis),
+            compact_gossip: env_flag("ECHO_COMPACT_GOSSIP"),
+            compact_threshold: env_parse("ECHO_COMPACT_THRESHOLD")
+                .unwrap_or(default.compact_threshold),
+            kv_service: std::env::var("ECHO_KV_SERVICE").unwrap_or(default.kv_service),
+            kv_timeout: env_parse("ECHO_KV_TIMEOUT_MS")
+                .map_or(default.kv_timeout, Duration::from_millis),
+            quorum_timeout: env_parse("ECHO_QUORUM_TIMEOUT_MS")
+                .map_or(default.quorum_timeout, Duration::from_millis),
+            gossip_before_reply: env_flag("ECHO_GOSSIP_BEFORE_REPLY"),
+            stall_warning: env_parse("ECHO_STALL_WARNING_MS").map(Duration::from_millis),
+            max_messages: env_parse("ECHO_MAX_MESSAGES"),
+            retry_budget: env_parse("ECHO_RETRY_BUDGET"),
+            field_profile: match std::env::var("ECHO_FIELD_PROFILE").as_deref() {
+                Ok("legacy") => FieldProfile::Legacy,
+                Ok("canonical") => FieldProfile::Canonical,
+                _ => default.field_profile,
+            },
+            gossip_acks: env_flag("ECHO_GOSSIP_ACKS"),
+            message_ttl: env_parse("ECHO_MESSAGE_TTL_MS").map(Duration::from_millis),
+            max_cas_attempts: env_parse("ECHO_MAX_CAS_ATTEMPTS")
+                .unwrap_or(default.max_cas_attempts),
+            g_set: env_flag("ECHO_G_SET"),
+            dedup_hash: match std::env::var("ECHO_DEDUP_HASH").as_deref() {
+                Ok("fx") => Some(DedupHash::Fx),
+                Ok("sip") => Some(DedupHash::Sip),
+                _ => default.dedup_hash,
+            },
+        }
+    }
+}
+
+/// Counters reported by the `stats` request.
+#[derive(Default, Clone, Copy, Deserialize, Serialize)]
+struct Metrics {
+    messages_received: u64,
+    gossip_sent: u64,
+    /// JSON size of the sets sent as `compact_update`, and their size once
+    /// compacted, so `compact_threshold` can be tuned.
+    compact_bytes_before: u64,
+    compact_bytes_after: u64,
+    /// `compact_bytes_after / compact_bytes_before`, 0 until anything is
+    /// compacted.
+    compact_ratio: f64,
+    /// Retries skipped because `retry_budget` ran out.
+    retries_throttled: u64,
+    /// Updates merged into one already queued for the same peer.
+    gossip_coalesced: u64,
+    /// Peers that have gossiped this node at least one value. Well below
+    /// the cluster size suggests the node is cut off from part of it.
+    distinct_sources: u64,
+    /// Broadcast values received from clients and peers that this node
+    /// didn't have yet, and ones it already did.
+    values_new: u64,
+    values_duplicate: u64,
+    /// `values_duplicate` over all values received, 0 until any arrive. A
+    /// high ratio means peers gossip more than they need to.
+    duplicate_ratio: f64,
+    /// Whole sets left unmerged by `dedup_hash`.
+    gossip_deduped: u64,
+}
+
+impl Metrics {
+    /// Counts `received` incoming values, `new` of which were new.
+    fn count_values(&mut self, received: usize, new: usize) {
+        self.values_new += new as u64;
+        self.values_duplicate += (received - new) as u64;
+        let total = self.values_new + self.values_duplicate;
+        if total > 0 {
+            self.duplicate_ratio = self.values_duplicate as f64 / total as f64;
+        }
+    }
+}
+
+/// Written to stderr when input ends, to tell at a glance whether the run
+/// converged: every peer synced and nothing left pending.
+#[derive(Serialize)]
+struct ShutdownReport {
+    messages: usize,
+    peers: usize,
+    /// Peers known to have every message this node has.
+    peers_synced: usize,
+    pending_kv: usize,
+    pending_gossip: usize,
+    unacked_gossip: usize,
+    pending_quorum_reads: usize,
+    metrics: Metrics,
+}
+
 #[derive(Default)]
 struct Node {
+    config: Config,
+    /// The time as the node sees it, which tests move by hand.
+    clock: Clock,
+    metrics: Metrics,
     node_id: String,
     node_ids: Vec<String>,
     topology: Vec<String>,
+    /// Every node's neighbours, as the last `topology` message gave them.
+    adjacency: HashMap<String, Vec<String>>,
+    /// The strategy `topology` was built with.
+    strategy: TopologyStrategy,
+    /// xorshift state for random-subset gossip.
+    rng: u64,
+    /// Bumped every gossip round; see `rotate`.
+    gossip_cursor: usize,
     uuid_prefix: u32,
     uuid_count: u32,
-    messages: HashSet<i64>,
-    read_queue: HashMap<i64, String>,
-    add_queue: HashMap<i64, (String, i64)>,
+    messages: BTreeSet<i64>,
+    /// Lists of the `txn` workload, by key. Local to this node.
+    lists: HashMap<i64, Vec<i64>>,
+    /// Only kept up to date with `max_messages`.
+    recency: Recency,
+    /// Only kept up to date with `message_ttl`.
+    expiry: Expiry,
+    /// Counter and generate operations waiting on `Config::kv_service`.
+    /// Built at init.
+    kv: KvClient<KvOp>,
+    /// Last value this node read or wrote for each counter key, shared with
+    /// peers for quorum reads.
+    counter_cache: HashMap<String, i64>,
+    /// When each counter key's value was last read from or written to seq-kv.
+    last_refresh: HashMap<String, Instant>,
+    quorum_reads: HashMap<i64, QuorumRead>,
+    /// Gossip waiting to be sent.
+    pending_gossip: VecDeque<PendingGossip>,
+    /// Values each peer is known to have, from what it sent this node and
+    /// what this node sent it, so gossip can leave them out.
+    peer_known: HashMap<String, BTreeSet<i64>>,
+    /// Bumped every time `messages` changes, so a receiver can tell an old
+    /// set from this node that arrives after a newer one. Starts from the
+    /// wall clock at init so a restarted node isn't taken for a stale one.
+    generation: u64,
+    /// Peers counted in `Metrics::distinct_sources`.
+    sources: HashSet<String>,
+    /// The newest generation merged from each peer.
+    seen_generations: HashMap<String, u64>,
+    /// With `dedup_hash`, the hash of the last whole set from each peer.
+    last_payload: HashMap<String, u64>,
+    /// When each peer was first sent gossip it hasn't answered with a
+    /// message of its own.
+    unanswered_since: HashMap<String, Instant>,
+    /// Peers that can read `compact_update`, learned from `capabilities`.
+    compact_peers: HashSet<String>,
+    /// Built from `Config::retry_budget` at init.
+    retry_budget: Option<RetryBudget>,
+    /// With `gossip_acks`, peers that haven't acked the current generation.
+    dirty_peers: HashSet<String>,
+    /// With `gossip_acks`, each unacked `update` by `msg_id`, whatever its
+    /// generation, until it is acked or resent.
+    unacked_gossip: HashMap<i64, UnackedUpdate>,
+    /// With `gossip_acks`, how long to wait before resending to each peer
+    /// with an unacked `update`. Doubles per resend, reset by an ack.
+    gossip_backoff: HashMap<String, Duration>,
+    /// Unset until the first `tick`.
+    last_anti_entropy: Option<Instant>,
+    /// When the gossip held for `gossip_batch_interval` started queueing.
+    batch_started: Option<Instant>,
+    /// Running with `Config::stall_warning`.
+    watchdog: Option<Arc<Mutex<Activity>>>,
+    /// Last `msg_id` handed out by `next_msg_id`.
     next_msg_id: i64,
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let stdin = io::stdin();
-    let mut node = Node::default();
-    // let mut file = std::fs::File::create("/home/eman/gossip-glomers/maelstrom-echo/foo.txt")?;
-    for line in stdin.lock().lines() {
-        let line = line?;
-        // std::io::Write::write_all(&mut file, line.as_bytes())?;
-        let request: Request = serde_json::from_str(&line)?;
+/// What a request to the KV service is for: the continuation its reply
+/// runs, in `Node::on_kv_reply`.
+enum KvOp {
+    Read(PendingRead),
+    Add(PendingAdd),
+    Generate(PendingGenerate),
+}
 
-        let reply = match request.body {
-            RequestBody::Init {
-                msg_id,
-                node_id,
-                node_ids,
-            } => {
-                node.node_id = node_id;
-                node.node_ids = node_ids;
-                node.uuid_prefix = u32::from_str_radix(&node.node_id[1..], 10)?;
-                Response {
-                    src: request.dest,
-                    dest: request.src,
-                    body: ResponseBody::InitOk {
-                        in_reply_to: msg_id,
+impl KvOp {
+    /// The client waiting on the operation, and its `msg_id`.
+    fn client(&self) -> (&str, i64) {
+        match self {
+            KvOp::Read(read) => (&read.client, read.msg_id),
+            KvOp::Add(add) => (&add.client, add.msg_id),
+            KvOp::Generate(generate) => (&generate.client, generate.msg_id),
+        }
+    }
+}
+
+/// A counter `read` waiting on seq-kv.
+struct PendingRead {
+    client: String,
+    /// The client's `msg_id`, to reply to.
+    msg_id: i64,
+    key: String,
+}
+
+/// An `add` working through its read-then-CAS against seq-kv.
+struct PendingAdd {
+    client: String,
+    msg_id: i64,
+    key: String,
+    delta: i64,
+    /// The value being CAS'd in.
+    to: i64,
+    /// CASes sent so far, counting the one in flight.
+    attempts: u32,
+}
+
+/// A `generate` claiming the next value of this node's seq-kv counter.
+struct PendingGenerate {
+    client: String,
+    msg_id: i64,
+    /// The counter value being claimed.
+    count: i64,
+}
+
+/// An `update` queued for `flush_gossip`.
+struct PendingGossip {
+    dest: String,
+    messages: BTreeSet<i64>,
+    generation: u64,
+    /// `messages` is a delta rather than the whole set.
+    delta: bool,
+    /// The root of the `origin-tree` this gossip follows.
+    origin: Option<String>,
+}
+
+/// An `update` waiting for its `update_ok`.
+struct UnackedUpdate {
+    peer: String,
+    generation: u64,
+    /// What the update carried, so a resend doesn't lose a delta.
+    messages: BTreeSet<i64>,
+    /// Only an ack of the whole set shows the peer is caught up.
+    delta: bool,
+    sent_at: Instant,
+}
+
+/// Least-recently-received order of broadcast messages.
+#[derive(Default)]
+struct Recency {
+    clock: u64,
+    last_seen: HashMap<i64, u64>,
+    by_age: BTreeMap<u64, i64>,
+}
+
+impl Recency {
+    fn touch(&mut self, value: i64) {
+        self.clock += 1;
+        if let Some(old) = self.last_seen.insert(value, self.clock) {
+            self.by_age.remove(&old);
+        }
+        self.by_age.insert(self.clock, value);
+    }
+
+    fn pop_oldest(&mut self) -> Option<i64> {
+        let (_, value) = self.by_age.pop_first()?;
+        self.last_seen.remove(&value);
+        Some(value)
+    }
+}
+
+/// Broadcast values in the order this node first saw them, for
+/// `message_ttl` expiry.
+#[derive(Default)]
+struct Expiry {
+    queue: VecDeque<(Instant, i64)>,
+    /// Values that have expired, so gossip can't bring them back.
+    expired: HashSet<i64>,
+    /// `expired` in the order its values expired, so they can be let go a
+    /// TTL later.
+    tombstones: VecDeque<(Instant, i64)>,
+}
+
+/// A `read-quorum` waiting on peers' cached counter values.
+struct QuorumRead {
+    client: String,
+    msg_id: i64,
+    key: String,
+    /// Nodes whose value has been counted, this one included.
+    responded: HashSet<String>,
+    /// Peers that answered with an error.
+    failed: HashSet<String>,
+    value: i64,
+    /// Age of `value` as reported by whichever node supplied it.
+    age_ms: Option<u64>,
+    /// When the peer reads went out, for `quorum_timeout`.
+    sent_at: Instant,
+}
+
+/// The seq-kv key used by `add` and `read` requests that don't name one.
+const DEFAULT_COUNTER_KEY: &str = "counter";
+
+/// Ids handed out while seq-kv is unavailable set this bit, so they can never
+/// collide with ids claimed from the KV counter.
+const LOCAL_ID_BIT: u64 = 1 << 31;
+
+/// How often the full message set is re-sent along the spanning tree, as a
+/// backstop for gossip lost in the network.
+const ANTI_ENTROPY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long an unacked `update` waits before its first resend, and the most
+/// the wait can double up to.
+const GOSSIP_RETRY_INITIAL: Duration = Duration::from_millis(100);
+const GOSSIP_RETRY_MAX: Duration = Duration::from_secs(2);
+
+/// A node's replicable state: the broadcast set, txn lists and cached
+/// counters. Only tests copy it between nodes so far, to start a scenario
+/// from a given state without gossiping it there.
+#[cfg(test)]
+#[derive(Deserialize, Serialize)]
+struct Snapshot {
+    messages: BTreeSet<i64>,
+    lists: HashMap<i64, Vec<i64>>,
+    counters: HashMap<String, i64>,
+}
+
+#[cfg(test)]
+impl Node {
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            messages: self.messages.clone(),
+            lists: self.lists.clone(),
+            counters: self.counter_cache.clone(),
+        }
+    }
+
+    /// Replaces this node's replicable state with `snapshot`'s.
+    fn restore(&mut self, snapshot: Snapshot) {
+        self.messages = snapshot.messages;
+        self.lists = snapshot.lists;
+        self.counter_cache = snapshot.counters;
+    }
+}
+
+impl Node {
+    /// The seq-kv key holding this node's id counter.
+    fn generate_key(&self) -> String {
+        format!("generate/{}", self.node_id)
+    }
+
+    fn next_msg_id(&mut self) -> i64 {
+        self.next_msg_id += 1;
+        self.next_msg_id
+    }
+
+    /// The seq-kv key a counter or generate operation works on.
+    fn op_key(&self, op: &KvOp) -> String {
+        match op {
+            KvOp::Read(read) => read.key.clone(),
+            KvOp::Add(add) => add.key.clone(),
+            KvOp::Generate(_) => self.generate_key(),
+        }
+    }
+
+    /// Starts `op` over from a fresh read of its key, under a new `msg_id`.
+    fn reread(&mut self, op: KvOp, out: &mut Output) -> io::Result<()> {
+        let key = self.op_key(&op);
+        let msg_id = self.next_msg_id();
+        self.kv.read(out, msg_id, &key, op)
+    }
+
+    fn shutdown_report(&self) -> ShutdownReport {
+        let peers = self.node_ids.iter().filter(|&id| id != &self.node_id);
+        let synced = |peer: &String| {
+            self.peer_known
+                .get(peer)
+                .is_some_and(|known| known.is_superset(&self.messages))
+        };
+        ShutdownReport {
+            messages: self.messages.len(),
+            peers: peers.clone().count(),
+            peers_synced: peers.filter(|&peer| synced(peer)).count(),
+            pending_kv: self.kv.pending().count(),
+            pending_gossip: self.pending_gossip.len(),
+            unacked_gossip: self.unacked_gossip.len(),
+            pending_quorum_reads: self.quorum_reads.len(),
+            metrics: self.metrics,
+        }
+    }
+
+    /// Answers the clients of KV operations that have waited longer than
+    /// `kv_timeout` for the service.
+    fn expire_kv(&mut self, out: &mut Output) -> io::Result<()> {
+        for op in self.kv.take_expired(self.config.kv_timeout) {
+            self.on_kv_reply(op, KvReply::Timeout, out)?;
+        }
+        Ok(())
+    }
+
+    /// Runs the continuation `op` registered for a KV request on the reply
+    /// to it. A missing key reads as 0. A lost CAS race starts the `add` or
+    /// `generate` over, as does an unavailable service for a counter, while
+    /// the retry budget lasts. Other failures go back to the client, except
+    /// that `generate` falls back to a local id. A timed-out `add` may still
+    /// have been applied, hence a timeout rather than a definite failure.
+    fn on_kv_reply(
+        &mut self,
+        op: KvOp,
+        reply: KvReply<'_, i64>,
+        out: &mut Output,
+    ) -> io::Result<()> {
+        let (op, value) = match (op, reply) {
+            (op, KvReply::ReadOk(value)) => (op, value),
+            (op, KvReply::Error { code: 20, .. }) => (op, 0),
+            (KvOp::Add(add), KvReply::CasOk) => {
+                self.refresh_counter(&add.key, add.to);
+                let msg = Response {
+                    src: &self.node_id,
+                    dest: &add.client,
+                    body: ResponseBody::AddOk {
+                        msg_id: add.msg_id,
+                        in_reply_to: add.msg_id,
                     },
-                }
+                };
+                return out.reply(&msg);
             }
-            RequestBody::Echo { msg_id, echo } => Response {
-                src: request.dest,
-                dest: request.src,
-                body: ResponseBody::EchoOk {
-                    msg_id,
-                    in_reply_to: msg_id,
-                    echo,
-                },
-            },
-            RequestBody::Generate { msg_id } => {
-                let id = ((node.uuid_prefix as u64) << 32) + node.uuid_count as u64;
-                node.uuid_count += 1;
-                Response {
-                    src: request.dest,
-                    dest: request.src,
+            (KvOp::Generate(generate), KvReply::CasOk) => {
+                let msg = Response {
+                    src: &self.node_id,
+                    dest: &generate.client,
                     body: ResponseBody::GenerateOk {
-                        msg_id,
-                        in_reply_to: msg_id,
+                        msg_id: generate.msg_id,
+                        in_reply_to: generate.msg_id,
+                        id: self.kv_id(generate.count),
+                    },
+                };
+                return out.send(&msg);
+            }
+            (KvOp::Read(_), KvReply::CasOk) | (_, KvReply::WriteOk) => return Ok(()),
+            (KvOp::Add(add), KvReply::Error { code: 22, .. })
+                if add.attempts >= self.config.max_cas_attempts =>
+            {
+                let msg = Response {
+                    src: &self.node_id,
+                    dest: &add.client,
+                    body: ResponseBody::Error {
+                        in_reply_to: add.msg_id,
+                        code: 30,
+                        text: "add kept losing CAS races to other writers",
+                        details: error_details(serde_json::json!({
+                            "key": add.key,
+                            "attempts": add.attempts,
+                        })),
+                    },
+                };
+                return out.send(&msg);
+            }
+            (op, KvReply::Error { code: 22, .. }) if self.try_retry() => {
+                return self.reread(op, out);
+            }
+            (op @ (KvOp::Read(_) | KvOp::Add(_)), KvReply::Error { code: 11, .. })
+                if self.try_retry() =>
+            {
+                return self.reread(op, out);
+            }
+            (KvOp::Generate(generate), KvReply::Error { .. } | KvReply::Timeout) => {
+                let id = self.local_id();
+                let msg = Response {
+                    src: &self.node_id,
+                    dest: &generate.client,
+                    body: ResponseBody::GenerateOk {
+                        msg_id: generate.msg_id,
+                        in_reply_to: generate.msg_id,
                         id,
                     },
-                }
+                };
+                return out.send(&msg);
             }
-            RequestBody::Broadcast { msg_id, message } => {
-                node.messages.insert(message);
-                for node_id in node.topology.iter() {
-                    if node_id != &node.node_id {
-                        let msg = Response {
-                            src: &node.node_id,
-                            dest: &node_id,
-                            body: ResponseBody::Update {
-                                messages: node.messages.clone(),
-                            },
-                        };
-                        println!("{}", serde_json::to_string(&msg)?);
-                    }
-                }
-                Response {
-                    src: request.dest,
-                    dest: request.src,
-                    body: ResponseBody::BroadcastOk {
-                        msg_id,
+            (op, KvReply::Timeout) => {
+                let key = self.op_key(&op);
+                let (client, msg_id) = op.client();
+                let msg = Response {
+                    src: &self.node_id,
+                    dest: client,
+                    body: ResponseBody::Error {
                         in_reply_to: msg_id,
+                        code: 0,
+                        text: "timed out waiting for the KV service",
+                        details: error_details(serde_json::json!({
+                            "service": self.kv.service(),
+                            "key": key,
+                        })),
                     },
-                }
-            }
-            RequestBody::Read { msg_id } => {
-                node.read_queue.insert(msg_id, request.src.to_owned());
-                std::thread::sleep(Duration::from_millis(60));
-                Response {
-                    src: &node.node_id,
-                    dest: "seq-kv",
-                    body: ResponseBody::Read { msg_id, key: 0 },
-                }
+                };
+                return out.send(&msg);
             }
-            RequestBody::Topology { msg_id, topology } => {
-                node.topology = topology.get(node.node_id.as_str()).unwrap().clone();
-                Response {
-                    src: request.dest,
-                    dest: request.src,
-                    body: ResponseBody::TopologyOk {
-                        msg_id,
+            (
+                op,
+                KvReply::Error {
+                    code,
+                    text,
+                    details,
+                },
+            ) => {
+                let (client, msg_id) = op.client();
+                let msg = Response {
+                    src: &self.node_id,
+                    dest: client,
+                    body: ResponseBody::Error {
                         in_reply_to: msg_id,
+                        code,
+                        text,
+                        details,
                     },
-                }
+                };
+                return out.send(&msg);
             }
-            RequestBody::Update { messages } => {
-                let len = node.messages.len();
-                node.messages.extend(messages);
-                if node.messages.len() != len {
-                    for node_id in node.topology.iter() {
-                        if node_id != &node.node_id && node_id != request.src {
-                            let msg = Response {
-                                src: &node.node_id,
-                                dest: &node_id,
-                                body: ResponseBody::Update {
-                                    messages: node.messages.clone(),
-                                },
-                            };
-                            println!("{}", serde_json::to_string(&msg)?);
-                        }
-                    }
-                }
-                continue;
+        };
+        // A value was read: answer a `read`, or try the CAS an `add` or
+        // `generate` needs.
+        match op {
+            KvOp::Read(read) => {
+                self.refresh_counter(&read.key, value);
+                // seq-kv may answer from before this node's own last CAS.
+                // The cache has that write, and a G-counter never goes down,
+                // so reading through it keeps a client's acked adds visible
+                // to it.
+                let value = match self.config.counter_mode {
+                    CounterMode::GCounter => self.counter_cache[&read.key],
+                    CounterMode::PnCounter => value,
+                };
+                let msg = Response {
+                    src: &self.node_id,
+                    dest: &read.client,
+                    body: ResponseBody::ReadOk {
+                        msg_id: read.msg_id,
+                        in_reply_to: read.msg_id,
+                        value,
+                        age_ms: Some(0),
+                    },
+                };
+                out.reply(&msg)
             }
-            RequestBody::Add { msg_id, delta } => {
-                node.add_queue
-                    .insert(msg_id, (request.src.to_owned(), delta));
-                Response {
-                    src: &node.node_id,
-                    dest: "seq-kv",
-                    body: ResponseBody::Read { msg_id, key: 0 },
-                }
+            KvOp::Add(mut add) => {
+                self.refresh_counter(&add.key, value);
+                add.to = value + add.delta;
+                add.attempts += 1;
+                let (key, to) = (add.key.clone(), add.to);
+                let cas_id = self.next_msg_id();
+                self.kv
+                    .cas(out, cas_id, &key, value, to, true, KvOp::Add(add))
             }
-            RequestBody::ReadOk {
-                in_reply_to,
-                value,
-            } => {
-                if let Some(read) = node.read_queue.get(&in_reply_to) {
-                    Response {
-                        src: &node.node_id,
-                        dest: &read,
-                        body: ResponseBody::ReadOk {
-                            msg_id: in_reply_to,
-                            in_reply_to,
-                            value,
-                        },
-                    }
-                } else if let Some(add) = node.add_queue.get(&in_reply_to) {
-                    Response {
-                        src: &node.node_id,
-                        dest: "seq-kv",
-                        body: ResponseBody::Cas {
-                            msg_id: in_reply_to,
-                            key: 0,
-                            from: value,
-                            to: value + add.1,
-                            create_if_not_exists: true,
-                        },
-                    }
-                } else {
-                    continue;
-                }
+            KvOp::Generate(mut generate) => {
+                generate.count = value;
+                let key = self.generate_key();
+                let cas_id = self.next_msg_id();
+                let op = KvOp::Generate(generate);
+                self.kv.cas(out, cas_id, &key, value, value + 1, true, op)
             }
-            RequestBody::CasOk {
-                in_reply_to,
-            } => {
-                if let Some(add) = node.add_queue.get(&in_reply_to) {
-                    Response {
-                        src: &node.node_id,
-                        dest: &add.0,
-                        body: ResponseBody::AddOk {
-                            msg_id: in_reply_to,
-                            in_reply_to,
-                        },
-                    }
-                } else {
-                    continue;
-                }
+        }
+    }
+
+    /// Fails the quorum reads that have waited longer than `quorum_timeout`,
+    /// taking the peers still silent as failed.
+    fn expire_quorum_reads(&mut self, out: &mut Output) -> io::Result<()> {
+        let timeout = self.config.quorum_timeout;
+        let expired: Vec<i64> = self
+            .quorum_reads
+            .iter()
+            .filter(|(_, read)| self.clock.elapsed(read.sent_at) >= timeout)
+            .map(|(&read_id, _)| read_id)
+            .collect();
+        for read_id in expired {
+            let mut read = self.quorum_reads.remove(&read_id).unwrap();
+            let silent: Vec<String> = self
+                .node_ids
+                .iter()
+                .filter(|&id| !read.responded.contains(id))
+                .cloned()
+                .collect();
+            read.failed.extend(silent);
+            self.fail_quorum_read(read, out)?;
+        }
+        Ok(())
+    }
+
+    /// Answers the client of a quorum read that can no longer reach a
+    /// majority.
+    fn fail_quorum_read(&self, read: QuorumRead, out: &mut Output) -> io::Result<()> {
+        let msg = Response {
+            src: &self.node_id,
+            dest: &read.client,
+            body: ResponseBody::Error {
+                in_reply_to: read.msg_id,
+                code: 11,
+                text: "a majority of nodes could not be reached",
+                details: error_details(serde_json::json!({
+                    "errors": read.failed.len(),
+                    "nodes": self.node_ids.len(),
+                })),
+            },
+        };
+        out.send(&msg)
+    }
+
+    /// Whether `retry_budget` allows one more retry, spending it if so.
+    fn try_retry(&mut self) -> bool {
+        let allowed = self.retry_budget.as_mut().is_none_or(RetryBudget::try_take);
+        if !allowed {
+            self.metrics.retries_throttled += 1;
+        }
+        allowed
+    }
+
+    fn cache_counter(&mut self, key: &str, value: i64) {
+        let cached = self.counter_cache.entry(key.to_owned()).or_insert(value);
+        *cached = match self.config.counter_mode {
+            CounterMode::GCounter => value.max(*cached),
+            CounterMode::PnCounter => value,
+        };
+    }
+
+    /// Caches a counter value just observed in seq-kv.
+    fn refresh_counter(&mut self, key: &str, value: i64) {
+        self.cache_counter(key, value);
+        self.last_refresh.insert(key.to_owned(), self.clock.now());
+    }
+
+    fn counter_age_ms(&self, key: &str) -> Option<u64> {
+        let refreshed = self.last_refresh.get(key)?;
+        Some(self.clock.elapsed(*refreshed).as_millis() as u64)
+    }
+
+    /// An id claimed from seq-kv, unique across restarts of this node.
+    fn kv_id(&self, count: i64) -> u64 {
+        ((self.uuid_prefix as u64) << 32) + count as u64
+    }
+
+    /// An id from the in-memory counter, used when seq-kv can't be reached.
+    /// The counter starts from the time of init, in milliseconds, so a
+    /// restarted node starts past what it handed out before as long as it
+    /// averaged under one of these a millisecond. It wraps every 24 days.
+    fn local_id(&mut self) -> u64 {
+        let count = self.uuid_count as u64 & (LOCAL_ID_BIT - 1);
+        self.uuid_count = self.uuid_count.wrapping_add(1);
+        ((self.uuid_prefix as u64) << 32) + LOCAL_ID_BIT + count
+    }
+
+    /// This node's edges in a BFS spanning tree of the given topology
+    /// rooted at the lowest node id, so a broadcast crosses each of the N-1
+    /// edges exactly once. Falls back to the given neighbours if this node
+    /// isn't reachable from the root.
+    fn spanning_tree_neighbors(&self) -> Vec<String> {
+        let Some(root) = self.adjacency.keys().min_by_key(|id| (id.len(), *id)) else {
+            return Vec::new();
+        };
+        let Some(parent) = self.tree_parent(root) else {
+            return self.neighbors().to_vec();
+        };
+        let mut neighbors = self.tree_children(root);
+        if parent != self.node_id {
+            neighbors.push(parent);
+        }
+        neighbors.sort_by_key(|id| (id.len(), id.clone()));
+        neighbors
+    }
+
+    /// This node's neighbours as given, whichever of them `strategy` gossips to.
+    fn neighbors(&self) -> &[String] {
+        self.adjacency.get(&self.node_id).map_or(&[], Vec::as_slice)
+    }
+
+    /// The fewest-hop route from this node to `target` over the given
+    /// topology, ending with `target`. Empty if `target` is this node or
+    /// can't be reached.
+    fn path_to(&self, target: &str) -> Vec<String> {
+        let me = self.node_id.as_str();
+        let parents = self.bfs_tree(me);
+        if target == me || !parents.contains_key(target) {
+            return Vec::new();
+        }
+        let mut path = vec![target.to_owned()];
+        let mut at = target;
+        while let Some(&parent) = parents.get(at).filter(|&&parent| parent != me) {
+            path.push(parent.to_owned());
+            at = parent;
+        }
+        path.reverse();
+        path
+    }
+
+    /// Each node's parent in a BFS tree of the given topology rooted at
+    /// `root`, which is its own parent. Nodes `root` can't reach are left out.
+    fn bfs_tree<'a>(&'a self, root: &'a str) -> HashMap<&'a str, &'a str> {
+        let mut parents = HashMap::from([(root, root)]);
+        let mut queue = VecDeque::from([root]);
+        while let Some(current) = queue.pop_front() {
+            for next in self.adjacency.get(current).into_iter().flatten() {
+                if !parents.contains_key(next.as_str()) {
+                    parents.insert(next, current);
+                    queue.push_back(next);
+                }
             }
-            RequestBody::Error {
+        }
+        parents
+    }
+
+    /// This node's parent in `root`'s tree, itself if it is `root`, or
+    /// `None` if the tree doesn't reach it.
+    fn tree_parent(&self, root: &str) -> Option<String> {
+        let parents = self.bfs_tree(root);
+        parents.get(self.node_id.as_str()).map(|&p| p.to_owned())
+    }
+
+    /// The nodes this one forwards to in `root`'s tree.
+    fn tree_children(&self, root: &str) -> Vec<String> {
+        let me = self.node_id.as_str();
+        let mut children: Vec<String> = self
+            .bfs_tree(root)
+            .into_iter()
+            .filter(|&(child, parent)| parent == me && child != me)
+            .map(|(child, _)| child.to_owned())
+            .collect();
+        children.sort_by_key(|id| (id.len(), id.clone()));
+        children
+    }
+
+    /// The configured topology strategy, unless the cluster is too big for it.
+    fn topology_strategy(&self) -> TopologyStrategy {
+        match self.config.max_topology_nodes {
+            Some(max) if self.node_ids.len() > max => TopologyStrategy::RandomSubset,
+            _ => self.config.topology,
+        }
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    /// Up to `gossip_fanout` distinct peers other than this node and `skip`.
+    fn random_peers(&mut self, skip: &str) -> Vec<String> {
+        let mut peers: Vec<String> = self
+            .node_ids
+            .iter()
+            .filter(|&id| id != &self.node_id && id != skip)
+            .cloned()
+            .collect();
+        let picked = self.config.gossip_fanout.min(peers.len());
+        for i in 0..picked {
+            let j = i + (self.next_random() % (peers.len() - i) as u64) as usize;
+            peers.swap(i, j);
+        }
+        peers.truncate(picked);
+        peers
+    }
+
+    /// Tree neighbours to forward a change that arrived from `src` to. Only a
+    /// node peer is ever skipped, so a client-originated broadcast (`src` like
+    /// `c3`) reaches every neighbour.
+    fn gossip_targets<'a>(&'a self, src: &'a str) -> impl Iterator<Item = &'a String> {
+        let skip = self.node_ids.iter().any(|id| id == src).then_some(src);
+        self.topology
+            .iter()
+            .filter(move |&id| id != &self.node_id && Some(id.as_str()) != skip)
+    }
+
+    /// Whether `peer` has been silent past `peer_timeout` since we last
+    /// needed an answer from it.
+    fn is_dead(&self, peer: &str) -> bool {
+        let Some(timeout) = self.config.peer_timeout else {
+            return false;
+        };
+        self.unanswered_since
+            .get(peer)
+            .is_some_and(|since| self.clock.elapsed(*since) >= timeout)
+    }
+
+    fn health(&self) -> Health {
+        let dead = self
+            .node_ids
+            .iter()
+            .filter(|&id| id != &self.node_id && self.is_dead(id))
+            .count();
+        let majority = self.node_ids.len() / 2 + 1;
+        if self.is_dead(&self.config.kv_service) || self.node_ids.len() - dead < majority {
+            Health::Partitioned
+        } else if dead > 0 {
+            Health::Degraded
+        } else {
+            Health::Ok
+        }
+    }
+
+    /// `targets` starting from a different one each round, so that when
+    /// `max_gossip_per_iteration` or `retry_budget` cuts a round short it
+    /// isn't always the same neighbors left waiting.
+    fn rotate(&mut self, mut targets: Vec<String>) -> Vec<String> {
+        if !targets.is_empty() {
+            let start = self.gossip_cursor % targets.len();
+            targets.rotate_left(start);
+        }
+        self.gossip_cursor = self.gossip_cursor.wrapping_add(1);
+        targets
+    }
+
+    /// Starts a new generation of the message set and queues the `new`
+    /// values for every live gossip target that isn't known to have them;
+    /// sending happens in `flush_gossip`. A target this node knows nothing
+    /// about yet, such as one that just joined, gets the whole set.
+    fn gossip(&mut self, src: &str, new: &BTreeSet<i64>, origin: Option<String>) {
+        self.generation += 1;
+        if self.config.gossip_acks {
+            let peers = self.node_ids.iter().filter(|&id| id != &self.node_id);
+            self.dirty_peers.extend(peers.cloned());
+        }
+        // Values learned some other way, e.g. from anti-entropy, start a
+        // tree of their own here.
+        let origin = (self.strategy == TopologyStrategy::OriginTree)
+            .then(|| origin.unwrap_or_else(|| self.node_id.clone()));
+        let mut targets: Vec<String> = match (self.strategy, &origin) {
+            (TopologyStrategy::RandomSubset, _) => self.random_peers(src),
+            (_, Some(root)) if self.tree_parent(root).is_some() => {
+                let mut children = self.tree_children(root);
+                children.retain(|id| id != src);
+                children
+            }
+            _ => self.rotate(self.gossip_targets(src).cloned().collect()),
+        };
+        targets.retain(|peer| !self.is_dead(peer));
+        for node_id in targets {
+            let (messages, delta) = match self.peer_known.get(&node_id) {
+                Some(known) => (new.difference(known).copied().collect(), true),
+                None => (self.messages.clone(), false),
+            };
+            if messages.is_empty() {
+                continue;
+            }
+            if self.config.gossip_batch_interval.is_some() {
+                self.batch_started.get_or_insert(self.clock.now());
+            }
+            // One queued update per peer and tree, however many broadcasts
+            // feed it before it is flushed.
+            let queued = self
+                .pending_gossip
+                .iter_mut()
+                .find(|queued| queued.dest == node_id && queued.origin == origin);
+            if let Some(queued) = queued {
+                queued.messages.extend(messages);
+                queued.generation = self.generation;
+                queued.delta &= delta;
+                self.metrics.gossip_coalesced += 1;
+                continue;
+            }
+            self.pending_gossip.push_back(PendingGossip {
+                dest: node_id,
+                messages,
+                generation: self.generation,
+                delta,
+                origin: origin.clone(),
+            });
+        }
+    }
+
+    /// Applies a `txn`'s micro-ops in order, so reads see the appends before
+    /// them, and returns them with the reads filled in. Nothing is applied
+    /// if any op is malformed; its index is the error.
+    fn apply_txn<'a>(
+        &mut self,
+        txn: Vec<(&'a str, i64, Option<i64>)>,
+    ) -> Result<Vec<(&'a str, i64, serde_json::Value)>, usize> {
+        let malformed = txn
+            .iter()
+            .position(|&(f, _, value)| !matches!((f, value), ("r", None) | ("append", Some(_))));
+        if let Some(i) = malformed {
+            return Err(i);
+        }
+        let applied = txn
+            .into_iter()
+            .map(|(f, key, value)| match value {
+                Some(value) => {
+                    self.lists.entry(key).or_default().push(value);
+                    (f, key, value.into())
+                }
+                None => {
+                    let list = self.lists.get(&key).map(|list| list.as_slice().into());
+                    (f, key, list.unwrap_or_default())
+                }
+            })
+            .collect();
+        Ok(applied)
+    }
+
+    /// Adds a value a client sent and gossips it on.
+    fn insert_message(&mut self, src: &str, value: i64) {
+        // An expired value stays gone, even if a client sends it again,
+        // until its tombstone is let go.
+        if self.expiry.expired.contains(&value) {
+            return;
+        }
+        let new = self.messages.insert(value);
+        if new {
+            self.stamp(value);
+        }
+        self.metrics.count_values(1, new.into());
+        self.touch(value);
+        self.evict();
+        self.gossip(src, &BTreeSet::from([value]), None);
+    }
+
+    /// Marks `value` as just received, for `max_messages` eviction.
+    fn touch(&mut self, value: i64) {
+        if self.config.max_messages.is_some() {
+            self.recency.touch(value);
+        }
+    }
+
+    /// Starts `value`'s `message_ttl` clock; call it only for new values.
+    fn stamp(&mut self, value: i64) {
+        if self.config.message_ttl.is_some() {
+            self.expiry.queue.push_back((self.clock.now(), value));
+        }
+    }
+
+    /// Drops the messages older than `message_ttl`, and the tombstones of
+    /// ones that expired longer than that ago.
+    fn expire(&mut self) {
+        let Some(ttl) = self.config.message_ttl else {
+            return;
+        };
+        let now = self.clock.now();
+        while let Some(&(expired, value)) = self.expiry.tombstones.front() {
+            if now.saturating_duration_since(expired) < ttl {
+                break;
+            }
+            self.expiry.tombstones.pop_front();
+            self.expiry.expired.remove(&value);
+        }
+        while let Some(&(seen, value)) = self.expiry.queue.front() {
+            if now.saturating_duration_since(seen) < ttl {
+                break;
+            }
+            self.expiry.queue.pop_front();
+            self.messages.remove(&value);
+            self.expiry.expired.insert(value);
+            self.expiry.tombstones.push_back((now, value));
+        }
+    }
+
+    /// Drops the least recently received messages beyond `max_messages`.
+    fn evict(&mut self) {
+        let Some(max) = self.config.max_messages else {
+            return;
+        };
+        while self.messages.len() > max {
+            let Some(value) = self.recency.pop_oldest() else {
+                break;
+            };
+            self.messages.remove(&value);
+        }
+    }
+
+    /// Adds gossiped messages and passes any new ones on, along `origin`'s
+    /// tree with `origin-tree`. A set older than one already merged from
+    /// `src` is ignored.
+    fn merge(
+        &mut self,
+        src: &str,
+        mut messages: BTreeSet<i64>,
+        generation: Option<u64>,
+        origin: Option<String>,
+    ) {
+        if let Some(generation) = generation {
+            let seen = self.seen_generations.entry(src.to_owned()).or_default();
+            if generation < *seen {
+                return;
+            }
+            *seen = generation;
+            if let Some(hasher) = self.config.dedup_hash {
+                let hash = hasher.hash(&messages);
+                if self.last_payload.insert(src.to_owned(), hash) == Some(hash) {
+                    self.metrics.gossip_deduped += 1;
+                    return;
+                }
+            }
+        }
+        if !messages.is_empty() && self.sources.insert(src.to_owned()) {
+            self.metrics.distinct_sources += 1;
+        }
+        self.peer_known
+            .entry(src.to_owned())
+            .or_default()
+            .extend(&messages);
+        if self.config.message_ttl.is_some() {
+            messages.retain(|value| !self.expiry.expired.contains(value));
+            for &value in &messages {
+                if !self.messages.contains(&value) {
+                    self.stamp(value);
+                }
+            }
+        }
+        for &value in &messages {
+            self.touch(value);
+        }
+        let received = messages.len();
+        messages.retain(|value| !self.messages.contains(value));
+        self.metrics.count_values(received, messages.len());
+        self.messages.extend(&messages);
+        self.evict();
+        if !messages.is_empty() {
+            self.gossip(src, &messages, origin);
+        }
+    }
+
+    fn anti_entropy(&mut self) {
+        if self.messages.is_empty() {
+            return;
+        }
+        let targets = if self.strategy == TopologyStrategy::RandomSubset {
+            self.random_peers("")
+        } else {
+            self.rotate(self.topology.clone())
+        };
+        for node_id in targets {
+            if self.config.gossip_acks && !self.dirty_peers.contains(&node_id) {
+                continue;
+            }
+            if !self.try_retry() {
+                break;
+            }
+            self.pending_gossip.push_back(PendingGossip {
+                dest: node_id,
+                messages: self.messages.clone(),
+                generation: self.generation,
+                delta: false,
+                origin: None,
+            });
+        }
+    }
+
+    /// How long `peer` waits before an unacked `update` is resent.
+    fn gossip_backoff(&self, peer: &str) -> Duration {
+        self.gossip_backoff
+            .get(peer)
+            .copied()
+            .unwrap_or(GOSSIP_RETRY_INITIAL)
+    }
+
+    /// Queues one resend per peer whose `update`s have gone unacked for
+    /// longer than its backoff, then doubles the backoff. The resend carries
+    /// the current set if any of them was a whole set, or else every delta
+    /// they carried, and joins any update already queued for the peer.
+    fn retry_gossip(&mut self) {
+        let mut due: BTreeMap<String, Vec<i64>> = BTreeMap::new();
+        for (&msg_id, unacked) in &self.unacked_gossip {
+            if self.clock.elapsed(unacked.sent_at) >= self.gossip_backoff(&unacked.peer)
+                && !self.is_dead(&unacked.peer)
+            {
+                due.entry(unacked.peer.clone()).or_default().push(msg_id);
+            }
+        }
+        for (peer, msg_ids) in due {
+            if !self.try_retry() {
+                break;
+            }
+            let mut messages = BTreeSet::new();
+            let mut delta = true;
+            for msg_id in msg_ids {
+                let unacked = self.unacked_gossip.remove(&msg_id).unwrap();
+                messages.extend(unacked.messages);
+                delta &= unacked.delta;
+            }
+            if !delta {
+                messages = self.messages.clone();
+            }
+            let backoff = (self.gossip_backoff(&peer) * 2).min(GOSSIP_RETRY_MAX);
+            self.gossip_backoff.insert(peer.clone(), backoff);
+            let queued = self
+                .pending_gossip
+                .iter_mut()
+                .find(|queued| queued.dest == peer && queued.origin.is_none());
+            if let Some(queued) = queued {
+                queued.messages.extend(messages);
+                queued.generation = self.generation;
+                queued.delta &= delta;
+                self.metrics.gossip_coalesced += 1;
+                continue;
+            }
+            self.pending_gossip.push_back(PendingGossip {
+                dest: peer,
+                messages,
+                generation: self.generation,
+                delta,
+                origin: None,
+            });
+        }
+    }
+
+    /// How long until queued gossip should be flushed, or `None` if there is
+    /// none. Zero unless a batch is still filling.
+    fn gossip_wait(&self) -> Option<Duration> {
+        if self.pending_gossip.is_empty() {
+            return None;
+        }
+        let (Some(interval), Some(started)) =
+            (self.config.gossip_batch_interval, self.batch_started)
+        else {
+            return Some(Duration::ZERO);
+        };
+        let full = self.config.gossip_batch_size.is_some_and(|max| {
+            self.pending_gossip
+                .iter()
+                .any(|queued| queued.messages.len() >= max)
+        });
+        if full {
+            return Some(Duration::ZERO);
+        }
+        Some(interval.saturating_sub(self.clock.elapsed(started)))
+    }
+
+    /// Sends queued gossip, at most `max_gossip_per_iteration` messages per
+    /// call so a burst of broadcasts can't delay the next input for long.
+    /// Also stops once the iteration that began at `started` has used up
+    /// `iteration_budget`, though at least one message always goes out.
+    fn flush_gossip(&mut self, out: &mut Output, started: Instant) -> io::Result<()> {
+        let _span =
+            tracing::debug_span!("flush_gossip", queued = self.pending_gossip.len()).entered();
+        let limit = self.config.max_gossip_per_iteration.unwrap_or(usize::MAX);
+        let budget = self.config.iteration_budget;
+        let over_budget =
+            |sent| sent > 0 && budget.is_some_and(|budget| started.elapsed() >= budget);
+        let mut sent = 0;
+        while sent < limit && !over_budget(sent) {
+            let Some(PendingGossip {
+                dest,
+                messages,
+                generation,
+                delta,
+                origin,
+            }) = self.pending_gossip.pop_front()
+            else {
+                break;
+            };
+            self.peer_known
+                .entry(dest.clone())
+                .or_default()
+                .extend(&messages);
+            let msg_id = self.next_msg_id();
+            if self.config.gossip_acks {
+                let unacked = UnackedUpdate {
+                    peer: dest.clone(),
+                    generation,
+                    messages: messages.clone(),
+                    delta,
+                    sent_at: self.clock.now(),
+                };
+                self.unacked_gossip.insert(msg_id, unacked);
+            }
+            let body = if self.compact_peers.contains(&dest)
+                && messages.len() > self.config.compact_threshold
+            {
+                let compact = encode_compact(&messages);
+                let json = serde_json::to_vec(&messages).map_or(0, |json| json.len());
+                let metrics = &mut self.metrics;
+                metrics.compact_bytes_before += json as u64;
+                metrics.compact_bytes_after += compact.len() as u64;
+                metrics.compact_ratio =
+                    metrics.compact_bytes_after as f64 / metrics.compact_bytes_before as f64;
+                ResponseBody::CompactUpdate {
+                    msg_id,
+                    messages: compact,
+                    generation,
+                    delta,
+                    origin,
+                }
+            } else {
+                ResponseBody::Update {
+                    msg_id,
+                    messages,
+                    generation,
+                    delta,
+                    origin,
+                }
+            };
+            let msg = Response {
+                src: &self.node_id,
+                dest: &dest,
+                body,
+            };
+            out.send(&msg)?;
+            sent += 1;
+            self.unanswered_since
+                .entry(dest)
+                .or_insert(self.clock.now());
+        }
+        self.metrics.gossip_sent += sent as u64;
+        if self.pending_gossip.is_empty() {
+            self.batch_started = None;
+        }
+        Ok(())
+    }
+}
+
+/// Encodes an `echo_ok` reply directly into the output buffer without
+/// building a `Response`. The bytes match what `send` produces for the same
+/// `ResponseBody::EchoOk`.
+fn send_echo_ok(
+    out: &mut Output,
+    src: &str,
+    dest: &str,
+    msg_id: i64,
+    echo: &str,
+) -> io::Result<()> {
+    if out.traces_latency() {
+        let msg = Response {
+            src,
+            dest,
+            body: ResponseBody::EchoOk {
+                msg_id,
+                in_reply_to: msg_id,
+                echo,
+            },
+        };
+        return out.send(&msg);
+    }
+    out.send_raw(|buf| {
+        buf.extend_from_slice(br#"{"src":"#);
+        serde_json::to_writer(&mut *buf, src)?;
+        buf.extend_from_slice(br#","dest":"#);
+        serde_json::to_writer(&mut *buf, dest)?;
+        write!(
+            buf,
+            r#","body":{{"type":"echo_ok","msg_id":{msg_id},"in_reply_to":{msg_id},"echo":"#
+        )?;
+        serde_json::to_writer(&mut *buf, echo)?;
+        buf.extend_from_slice(b"}}");
+        Ok(())
+    })
+}
+
+/// Encodes a message set for `compact_update`: bincode, then base64 so it
+/// can travel in a JSON string.
+fn encode_compact(messages: &BTreeSet<i64>) -> String {
+    BASE64.encode(bincode::serialize(messages).expect("a set of integers always serializes"))
+}
+
+fn decode_compact(data: &str) -> Result<BTreeSet<i64>, Box<dyn std::error::Error>> {
+    Ok(bincode::deserialize(&BASE64.decode(data)?)?)
+}
+
+/// The number in a node id such as "n3", or `None` if it isn't one.
+fn node_number(node_id: &str) -> Option<u32> {
+    node_id.strip_prefix('n')?.parse().ok()
+}
+
+/// Just the `type` of an incoming message, for the watchdog.
+#[derive(Deserialize)]
+struct MessageType<'a> {
+    #[serde(borrow)]
+    body: TypeOnly<'a>,
+}
+
+#[derive(Deserialize)]
+struct TypeOnly<'a> {
+    #[serde(rename = "type")]
+    kind: &'a str,
+}
+
+/// What the main loop was last doing, shared with the watchdog thread.
+struct Activity {
+    since: Instant,
+    last_type: String,
+    warned: bool,
+}
+
+impl Activity {
+    fn touch(&mut self, last_type: Option<&str>) {
+        self.since = Instant::now();
+        self.warned = false;
+        if let Some(last_type) = last_type {
+            self.last_type.clear();
+            self.last_type.push_str(last_type);
+        }
+    }
+
+    /// The warning for a stall of at least `threshold`, once per stall.
+    fn stall_warning(&mut self, threshold: Duration) -> Option<String> {
+        let stalled = self.since.elapsed();
+        if stalled < threshold || self.warned {
+            return None;
+        }
+        self.warned = true;
+        Some(format!(
+            "main loop stalled for {stalled:?}; last message: {}",
+            self.last_type
+        ))
+    }
+}
+
+/// Starts a thread that warns once per stall when `Activity` hasn't been
+/// touched for `threshold`.
+fn spawn_watchdog(threshold: Duration) -> Arc<Mutex<Activity>> {
+    let activity = Arc::new(Mutex::new(Activity {
+        since: Instant::now(),
+        last_type: String::from("none"),
+        warned: false,
+    }));
+    let watched = Arc::clone(&activity);
+    thread::spawn(move || loop {
+        thread::sleep(threshold / 4);
+        let Ok(mut activity) = watched.lock() else {
+            break;
+        };
+        if let Some(warning) = activity.stall_warning(threshold) {
+            eprintln!("{warning}");
+        }
+    });
+    activity
+}
+
+fn main() -> anyhow::Result<()> {
+    let config = Config::from_env();
+    let runtime = Runtime {
+        tee_path: config.tee_path.clone(),
+        fsync: config.fsync,
+        trace_latency: config.trace_latency,
+        hold_replies: config.gossip_before_reply,
+        abort_on_parse_error: config.abort_on_parse_error,
+        trace: config.trace.as_deref().and_then(|level| level.parse().ok()),
+        aliases: config.field_profile.aliases(),
+    };
+    let mut node = Node {
+        watchdog: config.stall_warning.map(spawn_watchdog),
+        config,
+        ..Default::default()
+    };
+    runtime.run(&mut node)?;
+    let report = serde_json::to_string(&node.shutdown_report())?;
+    eprintln!("shutdown report: {report}");
+    Ok(())
+}
+
+impl maelstrom_core::Node for Node {
+    type Body<'a> = RequestBody<'a>;
+
+    fn timeout(&self) -> Duration {
+        let gossip = self.gossip_wait();
+        if gossip == Some(Duration::ZERO) {
+            return Duration::ZERO;
+        }
+        let anti_entropy = self.last_anti_entropy.map_or(ANTI_ENTROPY_INTERVAL, |at| {
+            ANTI_ENTROPY_INTERVAL.saturating_sub(self.clock.elapsed(at))
+        });
+        let kv = self.kv.oldest().map(|sent_at| {
+            self.config
+                .kv_timeout
+                .saturating_sub(self.clock.elapsed(sent_at))
+        });
+        let quorum = self.quorum_reads.values().map(|read| {
+            self.config
+                .quorum_timeout
+                .saturating_sub(self.clock.elapsed(read.sent_at))
+        });
+        self.unacked_gossip
+            .values()
+            .map(|unacked| {
+                self.gossip_backoff(&unacked.peer)
+                    .saturating_sub(self.clock.elapsed(unacked.sent_at))
+            })
+            .chain(kv)
+            .chain(quorum)
+            .chain(gossip)
+            .fold(anti_entropy, Duration::min)
+    }
+
+    fn tick(&mut self, out: &mut Output) -> anyhow::Result<()> {
+        self.expire();
+        let last_anti_entropy = *self.last_anti_entropy.get_or_insert(self.clock.now());
+        if self.clock.elapsed(last_anti_entropy) >= ANTI_ENTROPY_INTERVAL {
+            self.anti_entropy();
+            self.last_anti_entropy = Some(self.clock.now());
+        }
+        self.retry_gossip();
+        if self.gossip_wait() == Some(Duration::ZERO) {
+            self.flush_gossip(out, out.started())?;
+        }
+        self.expire_kv(out)?;
+        self.expire_quorum_reads(out)?;
+        if self.kv.pending().any(|op| !matches!(op, KvOp::Generate(_))) {
+            let kv = self.config.kv_service.clone();
+            self.unanswered_since.entry(kv).or_insert(self.clock.now());
+        }
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.lock().unwrap().touch(None);
+        }
+        Ok(())
+    }
+
+    fn received(&mut self, line: &str) {
+        if let Some(watchdog) = &self.watchdog {
+            let kind = serde_json::from_str::<MessageType>(line).map(|m| m.body.kind);
+            watchdog
+                .lock()
+                .unwrap()
+                .touch(Some(kind.unwrap_or("unparseable")));
+        }
+    }
+
+    fn handle(&mut self, request: Request<'_>, out: &mut Output) -> anyhow::Result<()> {
+        self.metrics.messages_received += 1;
+        self.unanswered_since.remove(request.src);
+        self.expire();
+
+        // Replies to KV requests go to the continuation they registered.
+        if let RequestBody::ReadOk { in_reply_to, .. }
+        | RequestBody::CasOk { in_reply_to }
+        | RequestBody::Error { in_reply_to, .. } = request.body
+        {
+            if self.kv.get(in_reply_to).is_some() {
+                let reply = match request.body {
+                    RequestBody::ReadOk { value, .. } => KvReply::ReadOk(value),
+                    RequestBody::Error {
+                        code,
+                        text,
+                        details,
+                        ..
+                    } => KvReply::Error {
+                        code,
+                        text,
+                        details,
+                    },
+                    _ => KvReply::CasOk,
+                };
+                let (op, reply) = self.kv.dispatch(in_reply_to, reply).unwrap();
+                return Ok(self.on_kv_reply(op, reply, out)?);
+            }
+        }
+
+        let reply = match request.body {
+            RequestBody::Init {
+                msg_id,
+                ref node_id,
+                ..
+            } if node_number(node_id).is_none() => Response {
+                src: request.dest,
+                dest: request.src,
+                body: ResponseBody::Error {
+                    in_reply_to: msg_id,
+                    code: 12,
+                    text: "node ids must be n followed by a number",
+                    details: error_details(serde_json::json!({ "node_id": node_id })),
+                },
+            },
+            RequestBody::Init {
+                msg_id,
+                node_id,
+                node_ids,
+                kv_service,
+            } => {
+                self.node_id = node_id;
+                self.node_ids = node_ids;
+                if let Some(kv_service) = kv_service {
+                    self.config.kv_service = kv_service;
+                }
+                let service = self.config.kv_service.clone();
+                self.kv = KvClient::new(service, self.node_id.clone(), self.clock.clone());
+                self.uuid_prefix = node_number(&self.node_id).expect("checked by the arm above");
+                self.uuid_count = self
+                    .clock
+                    .system_now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_millis() as u32);
+                let clock = &self.clock;
+                let budget = |per_sec| RetryBudget::new(per_sec, clock.clone());
+                self.retry_budget = self.config.retry_budget.map(budget);
+                self.rng = RandomState::new().build_hasher().finish() | 1;
+                self.generation = self
+                    .clock
+                    .system_now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_micros() as u64);
+                if self.config.compact_gossip {
+                    for peer in self.node_ids.iter().filter(|&id| id != &self.node_id) {
+                        let msg = Response {
+                            src: &self.node_id,
+                            dest: peer,
+                            body: ResponseBody::Capabilities { compact: true },
+                        };
+                        out.send(&msg)?;
+                    }
+                }
+                Response {
+                    src: request.dest,
+                    dest: request.src,
+                    body: ResponseBody::InitOk {
+                        in_reply_to: msg_id,
+                    },
+                }
+            }
+            RequestBody::Echo { msg_id, echo } => {
+                send_echo_ok(out, request.dest, request.src, msg_id, echo)?;
+                return Ok(());
+            }
+            RequestBody::EchoBatch { msg_id, echos } => Response {
+                src: request.dest,
+                dest: request.src,
+                body: ResponseBody::EchoBatchOk {
+                    msg_id,
+                    in_reply_to: msg_id,
+                    echos,
+                },
+            },
+            RequestBody::Generate { msg_id } => {
+                let generate = PendingGenerate {
+                    client: request.src.to_owned(),
+                    msg_id,
+                    count: 0,
+                };
+                self.reread(KvOp::Generate(generate), out)?;
+                return Ok(());
+            }
+            RequestBody::Broadcast { msg_id, message } => {
+                self.insert_message(request.src, message);
+                Response {
+                    src: request.dest,
+                    dest: request.src,
+                    body: ResponseBody::BroadcastOk {
+                        msg_id,
+                        in_reply_to: msg_id,
+                    },
+                }
+            }
+            RequestBody::Read {
+                msg_id, key: None, ..
+            } if self.config.g_set => Response {
+                src: request.dest,
+                dest: request.src,
+                body: ResponseBody::ReadOkSet {
+                    msg_id,
+                    in_reply_to: msg_id,
+                    value: self.messages.iter().copied().collect(),
+                },
+            },
+            RequestBody::Read {
+                msg_id,
+                consistency,
+                key,
+            } if consistency.unwrap_or(self.config.read_consistency) == Consistency::ReadQuorum
+                && self.node_ids.len() > 1 =>
+            {
+                let key = key.unwrap_or_else(|| DEFAULT_COUNTER_KEY.to_owned());
+                let read_id = self.next_msg_id();
+                for node_id in self.node_ids.iter().filter(|&id| id != &self.node_id) {
+                    let msg = Response {
+                        src: &self.node_id,
+                        dest: node_id,
+                        body: ResponseBody::PeerRead {
+                            msg_id: read_id,
+                            key: key.clone(),
+                        },
+                    };
+                    out.send(&msg)?;
+                }
+                let value = self.counter_cache.get(&key).copied().unwrap_or(0);
+                let age_ms = self.counter_age_ms(&key);
+                self.quorum_reads.insert(
+                    read_id,
+                    QuorumRead {
+                        client: request.src.to_owned(),
+                        msg_id,
+                        key,
+                        responded: HashSet::from([self.node_id.clone()]),
+                        failed: HashSet::new(),
+                        value,
+                        age_ms,
+                        sent_at: self.clock.now(),
+                    },
+                );
+                return Ok(());
+            }
+            RequestBody::Read { msg_id, key, .. } => {
+                let read = PendingRead {
+                    client: request.src.to_owned(),
+                    msg_id,
+                    key: key.unwrap_or_else(|| DEFAULT_COUNTER_KEY.to_owned()),
+                };
+                self.reread(KvOp::Read(read), out)?;
+                return Ok(());
+            }
+            RequestBody::PeerRead { msg_id, key } => Response {
+                src: request.dest,
+                dest: request.src,
+                body: ResponseBody::PeerReadOk {
+                    in_reply_to: msg_id,
+                    value: self.counter_cache.get(&key).copied().unwrap_or(0),
+                    age_ms: self.counter_age_ms(&key),
+                },
+            },
+            RequestBody::PeerReadOk {
                 in_reply_to,
-                code: 20,
-                text: _,
+                value,
+                age_ms,
+            } => {
+                let majority = self.node_ids.len() / 2 + 1;
+                let mode = self.config.counter_mode;
+                let Some(read) = self.quorum_reads.get_mut(&in_reply_to) else {
+                    return Ok(());
+                };
+                if !read.responded.insert(request.src.to_owned()) {
+                    return Ok(());
+                }
+                let newer = match mode {
+                    CounterMode::GCounter => value > read.value,
+                    // A value of unknown age never beats one of known age.
+                    CounterMode::PnCounter => {
+                        age_ms.is_some_and(|age| read.age_ms.is_none_or(|best| age < best))
+                    }
+                };
+                if newer {
+                    read.value = value;
+                    read.age_ms = age_ms;
+                } else if value == read.value {
+                    read.age_ms = match (read.age_ms, age_ms) {
+                        (Some(a), Some(b)) => Some(a.min(b)),
+                        (a, b) => a.or(b),
+                    };
+                }
+                if read.responded.len() < majority {
+                    return Ok(());
+                }
+                let read = self.quorum_reads.remove(&in_reply_to).unwrap();
+                self.cache_counter(&read.key, read.value);
+                let msg = Response {
+                    src: &self.node_id,
+                    dest: &read.client,
+                    body: ResponseBody::ReadOk {
+                        msg_id: read.msg_id,
+                        in_reply_to: read.msg_id,
+                        value: read.value,
+                        age_ms: read.age_ms,
+                    },
+                };
+                out.send(&msg)?;
+                return Ok(());
+            }
+            RequestBody::Path { msg_id, target } => Response {
+                src: request.dest,
+                dest: request.src,
+                body: ResponseBody::PathOk {
+                    msg_id,
+                    in_reply_to: msg_id,
+                    path: self.path_to(&target),
+                },
+            },
+            RequestBody::Txn { msg_id, txn } => Response {
+                src: request.dest,
+                dest: request.src,
+                body: match self.apply_txn(txn) {
+                    Ok(txn) => ResponseBody::TxnOk {
+                        msg_id,
+                        in_reply_to: msg_id,
+                        txn,
+                    },
+                    Err(op) => ResponseBody::Error {
+                        in_reply_to: msg_id,
+                        code: 12,
+                        text: "txn ops must be [\"r\", key, null] or [\"append\", key, value]",
+                        details: error_details(serde_json::json!({ "op": op })),
+                    },
+                },
+            },
+            RequestBody::Contains { msg_id, value } => Response {
+                src: request.dest,
+                dest: request.src,
+                body: ResponseBody::ContainsOk {
+                    msg_id,
+                    in_reply_to: msg_id,
+                    present: self.messages.contains(&value),
+                },
+            },
+            RequestBody::ReadPage {
+                msg_id, limit: 0, ..
+            } => Response {
+                src: request.dest,
+                dest: request.src,
+                body: ResponseBody::Error {
+                    in_reply_to: msg_id,
+                    code: 12,
+                    text: "read_page needs a limit of at least 1",
+                    details: Default::default(),
+                },
+            },
+            RequestBody::ReadPage {
+                msg_id,
+                after,
+                limit,
             } => {
-                if let Some(read) = node.read_queue.get(&in_reply_to) {
-                    Response {
-                        src: &node.node_id,
-                        dest: &read,
-                        body: ResponseBody::ReadOk {
-                            msg_id: in_reply_to,
-                            in_reply_to,
-                            value: 0,
+                let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+                let mut rest = self.messages.range((start, Bound::Unbounded));
+                let messages: Vec<i64> = rest.by_ref().take(limit).copied().collect();
+                let next = match rest.next() {
+                    Some(_) => messages.last().copied(),
+                    None => None,
+                };
+                Response {
+                    src: request.dest,
+                    dest: request.src,
+                    body: ResponseBody::ReadPageOk {
+                        msg_id,
+                        in_reply_to: msg_id,
+                        messages,
+                        next,
+                    },
+                }
+            }
+            RequestBody::Stats { msg_id, reset } => {
+                let metrics = self.metrics;
+                let body = if reset && !self.config.debug {
+                    ResponseBody::Error {
+                        in_reply_to: msg_id,
+                        code: 10,
+                        text: "stats reset requires ECHO_DEBUG",
+                        details: Default::default(),
+                    }
+                } else {
+                    if reset {
+                        self.metrics = Metrics::default();
+                        self.sources.clear();
+                    }
+                    ResponseBody::StatsOk {
+                        msg_id,
+                        in_reply_to: msg_id,
+                        metrics,
+                    }
+                };
+                Response {
+                    src: request.dest,
+                    dest: request.src,
+                    body,
+                }
+            }
+            RequestBody::Health { msg_id } => Response {
+                src: request.dest,
+                dest: request.src,
+                body: ResponseBody::HealthOk {
+                    msg_id,
+                    in_reply_to: msg_id,
+                    status: self.health(),
+                },
+            },
+            RequestBody::DumpConfig { msg_id } => Response {
+                src: request.dest,
+                dest: request.src,
+                body: ResponseBody::DumpConfigOk {
+                    msg_id,
+                    in_reply_to: msg_id,
+                    config: serde_json::to_value(&self.config)?,
+                },
+            },
+            RequestBody::Reconcile { msg_id, peer } => {
+                let body = if !self.config.debug {
+                    ResponseBody::Error {
+                        in_reply_to: msg_id,
+                        code: 10,
+                        text: "reconcile requires ECHO_DEBUG",
+                        details: Default::default(),
+                    }
+                } else if peer == self.node_id || !self.node_ids.contains(&peer) {
+                    ResponseBody::Error {
+                        in_reply_to: msg_id,
+                        code: 1,
+                        text: "no such peer",
+                        details: error_details(serde_json::json!({ "peer": peer })),
+                    }
+                } else {
+                    out.send(&Response {
+                        src: &self.node_id,
+                        dest: &peer,
+                        body: ResponseBody::Digest {
+                            messages: self.messages.clone(),
                         },
+                    })?;
+                    ResponseBody::ReconcileOk {
+                        msg_id,
+                        in_reply_to: msg_id,
                     }
-                } else if let Some(add) = node.add_queue.get(&in_reply_to) {
-                    Response {
-                        src: &node.node_id,
-                        dest: "seq-kv",
-                        body: ResponseBody::Cas {
-                            msg_id: in_reply_to,
-                            key: 0,
-                            from: 0,
-                            to: add.1,
-                            create_if_not_exists: true,
+                };
+                Response {
+                    src: request.dest,
+                    dest: request.src,
+                    body,
+                }
+            }
+            RequestBody::Digest { messages } => {
+                let missing: BTreeSet<i64> = self.messages.difference(&messages).copied().collect();
+                self.merge(request.src, messages, None, None);
+                if missing.is_empty() {
+                    return Ok(());
+                }
+                self.peer_known
+                    .entry(request.src.to_owned())
+                    .or_default()
+                    .extend(&missing);
+                Response {
+                    src: request.dest,
+                    dest: request.src,
+                    body: ResponseBody::Update {
+                        msg_id: self.next_msg_id(),
+                        messages: missing,
+                        generation: self.generation,
+                        delta: true,
+                        origin: None,
+                    },
+                }
+            }
+            RequestBody::Topology { msg_id, topology } => {
+                self.strategy = self.topology_strategy();
+                self.adjacency = topology
+                    .iter()
+                    .map(|(&id, neighbors)| (id.to_owned(), neighbors.clone()))
+                    .collect();
+                self.topology = match self.strategy {
+                    TopologyStrategy::SpanningTree => self.spanning_tree_neighbors(),
+                    TopologyStrategy::Given | TopologyStrategy::OriginTree => {
+                        self.neighbors().to_vec()
+                    }
+                    TopologyStrategy::RandomSubset => Vec::new(),
+                };
+                Response {
+                    src: request.dest,
+                    dest: request.src,
+                    body: ResponseBody::TopologyOk {
+                        msg_id,
+                        in_reply_to: msg_id,
+                    },
+                }
+            }
+            RequestBody::Update {
+                msg_id,
+                messages,
+                generation,
+                delta,
+                origin,
+            } => {
+                let generation = generation.filter(|_| !delta);
+                self.merge(request.src, messages, generation, origin);
+                match msg_id {
+                    Some(msg_id) if self.config.gossip_acks => Response {
+                        src: request.dest,
+                        dest: request.src,
+                        body: ResponseBody::UpdateOk {
+                            in_reply_to: msg_id,
                         },
+                    },
+                    _ => return Ok(()),
+                }
+            }
+            RequestBody::CompactUpdate {
+                msg_id,
+                messages,
+                generation,
+                delta,
+                origin,
+            } => {
+                let generation = generation.filter(|_| !delta);
+                match decode_compact(&messages) {
+                    Ok(messages) => self.merge(request.src, messages, generation, origin),
+                    Err(err) => {
+                        eprintln!("dropping bad compact_update from {}: {err}", request.src);
+                        return Ok(());
                     }
-                } else {
-                    continue;
+                }
+                match msg_id {
+                    Some(msg_id) if self.config.gossip_acks => Response {
+                        src: request.dest,
+                        dest: request.src,
+                        body: ResponseBody::UpdateOk {
+                            in_reply_to: msg_id,
+                        },
+                    },
+                    _ => return Ok(()),
+                }
+            }
+            RequestBody::UpdateOk { in_reply_to } => {
+                // Any ack shows the peer is reachable, but only a whole set
+                // of the current generation shows it is caught up.
+                if let Some(unacked) = self.unacked_gossip.remove(&in_reply_to) {
+                    if unacked.generation == self.generation && !unacked.delta {
+                        self.dirty_peers.remove(&unacked.peer);
+                    }
+                    self.gossip_backoff.remove(&unacked.peer);
+                }
+                return Ok(());
+            }
+            RequestBody::Capabilities { compact } => {
+                if compact && self.config.compact_gossip {
+                    self.compact_peers.insert(request.src.to_owned());
+                }
+                return Ok(());
+            }
+            RequestBody::Add {
+                msg_id,
+                element: Some(element),
+                ..
+            } => {
+                self.insert_message(request.src, element);
+                Response {
+                    src: request.dest,
+                    dest: request.src,
+                    body: ResponseBody::AddOk {
+                        msg_id,
+                        in_reply_to: msg_id,
+                    },
                 }
             }
-            RequestBody::Error {
-                in_reply_to,
-                code: 22,
-                text: _,
+            RequestBody::Add {
+                msg_id,
+                delta: None,
+                ..
             } => Response {
-                src: &node.node_id,
-                dest: "seq-kv",
-                body: ResponseBody::Read {
-                    msg_id: in_reply_to,
-                    key: 0,
+                src: request.dest,
+                dest: request.src,
+                body: ResponseBody::Error {
+                    in_reply_to: msg_id,
+                    code: 12,
+                    text: "add needs a delta or an element",
+                    details: Default::default(),
+                },
+            },
+            RequestBody::Add {
+                msg_id,
+                delta: Some(delta),
+                ..
+            } if delta < 0 && self.config.counter_mode == CounterMode::GCounter => Response {
+                src: request.dest,
+                dest: request.src,
+                body: ResponseBody::Error {
+                    in_reply_to: msg_id,
+                    code: 12,
+                    text: "negative deltas need ECHO_COUNTER_MODE=pn-counter",
+                    details: error_details(serde_json::json!({ "delta": delta })),
                 },
             },
+            RequestBody::Add {
+                msg_id,
+                key,
+                delta: Some(delta),
+                ..
+            } => {
+                let add = PendingAdd {
+                    client: request.src.to_owned(),
+                    msg_id,
+                    key: key.unwrap_or_else(|| DEFAULT_COUNTER_KEY.to_owned()),
+                    delta,
+                    to: 0,
+                    attempts: 0,
+                };
+                self.reread(KvOp::Add(add), out)?;
+                return Ok(());
+            }
+            // Replies to KV requests were dispatched above; these are strays.
+            RequestBody::ReadOk { .. } | RequestBody::CasOk { .. } => return Ok(()),
+            RequestBody::Error { in_reply_to, .. }
+                if self.quorum_reads.contains_key(&in_reply_to) =>
+            {
+                // The read still succeeds on the other peers' values unless
+                // too many fail for a majority to be reached.
+                let majority = self.node_ids.len() / 2 + 1;
+                let read = self.quorum_reads.get_mut(&in_reply_to).unwrap();
+                read.failed.insert(request.src.to_owned());
+                if read.failed.len() + majority <= self.node_ids.len() {
+                    return Ok(());
+                }
+                let read = self.quorum_reads.remove(&in_reply_to).unwrap();
+                self.fail_quorum_read(read, out)?;
+                return Ok(());
+            }
+            RequestBody::Error { code: 20 | 22, .. } => return Ok(()),
             RequestBody::Error {
                 in_reply_to: _,
                 code: _,
                 text: _,
+                details: _,
             } => Response {
                 src: request.dest,
                 dest: request.src,
@@ -353,10 +2619,1730 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     in_reply_to: 0,
                     code: 10,
                     text: "Boo Not Supported",
+                    details: Default::default(),
                 },
             },
         };
-        println!("{}", serde_json::to_string(&reply)?);
+        out.reply(&reply)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maelstrom_core::{
+        testing::{Cluster, Fate, TestNode},
+        VecSink,
+    };
+    use serde_json::{json, Value};
+
+    /// An initialised `id` in a cluster of `ids`, on a manual clock.
+    fn test_node(id: &str, ids: &[&str], config: Config) -> TestNode<Node> {
+        let node = Node {
+            config,
+            clock: Clock::manual(),
+            ..Default::default()
+        };
+        TestNode::new(node, id, ids)
+    }
+
+    /// Initialised nodes `ids`, given `topology`.
+    fn cluster(ids: &[&str], topology: Value, config: impl Fn() -> Config) -> Cluster<Node> {
+        let mut cluster = Cluster::new(ids, |clock| Node {
+            config: config(),
+            clock,
+            ..Default::default()
+        });
+        for &id in ids {
+            let body = json!({"type": "topology", "msg_id": 0, "topology": topology});
+            cluster.client(id, body);
+        }
+        cluster.pump_until(Duration::ZERO, |_| false);
+        cluster.replies.clear();
+        cluster.log.clear();
+        cluster
+    }
+
+    /// Delivers messages until every node has the same message set and none
+    /// are in flight or held, or `timeout` passes. Returns whether they
+    /// converged.
+    fn converge(cluster: &mut Cluster<Node>, timeout: Duration) -> bool {
+        cluster.pump_until(timeout, |cluster| {
+            let mut sets = cluster.nodes.values().map(|node| &node.node.messages);
+            let first = sets.next();
+            cluster.quiet() && sets.all(|set| Some(set) == first)
+        })
+    }
+
+    /// A counter operation as its client saw it, sent at step `start` of a
+    /// `Cluster` and answered at step `end`.
+    #[derive(Clone, Copy, Debug)]
+    struct CounterOp {
+        start: u64,
+        end: u64,
+        kind: CounterKind,
+    }
+
+    #[derive(Clone, Copy, Debug)]
+    enum CounterKind {
+        Add(i64),
+        /// The value read.
+        Read(i64),
+    }
+
+    /// Checks `history` against a linearizable grow-only counter: a read
+    /// sees at least every add that completed before it began, at most every
+    /// add that began before it ended, and no less than any read completed
+    /// before it began. Returns the first violation found.
+    fn check_counter(history: &[CounterOp]) -> Result<(), String> {
+        let adds = || {
+            history.iter().filter_map(|op| match op.kind {
+                CounterKind::Add(delta) => Some((op, delta)),
+                CounterKind::Read(_) => None,
+            })
+        };
+        let reads = || {
+            history.iter().filter_map(|op| match op.kind {
+                CounterKind::Read(value) => Some((op, value)),
+                CounterKind::Add(_) => None,
+            })
+        };
+        for (read, value) in reads() {
+            let at_least: i64 = adds()
+                .filter(|(add, _)| add.end < read.start)
+                .map(|(_, delta)| delta)
+                .sum();
+            let at_most: i64 = adds()
+                .filter(|(add, _)| add.start < read.end)
+                .map(|(_, delta)| delta)
+                .sum();
+            if !(at_least..=at_most).contains(&value) {
+                return Err(format!("{read:?} is outside {at_least}..={at_most}"));
+            }
+            let earlier = reads().find(|&(other, seen)| other.end < read.start && seen > value);
+            if let Some((other, _)) = earlier {
+                return Err(format!("{read:?} went back from {other:?}"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends `ops` to their nodes, each after the one before it has had a
+    /// step to get going, and pumps until all are answered. Returns the
+    /// history of the ops that succeeded; an add that gave up on its CAS
+    /// never happened, so it is left out.
+    fn run_counter_ops(cluster: &mut Cluster<Node>, ops: &[(&str, Value)]) -> Vec<CounterOp> {
+        let mut started = HashMap::new();
+        let mut history = Vec::new();
+        let deadline = cluster.clock.now() + Duration::from_secs(1);
+        let mut next = ops.iter().enumerate();
+        let mut answered = 0;
+        while answered < ops.len() {
+            assert!(cluster.clock.now() < deadline, "unanswered: {started:?}");
+            if let Some((i, (id, body))) = next.next() {
+                let mut body = body.clone();
+                body["msg_id"] = json!(i);
+                started.insert(i as i64, (cluster.steps, body["delta"].as_i64()));
+                cluster.client(id, body);
+            }
+            if !cluster.step() {
+                cluster.clock.advance(Duration::from_millis(1));
+                cluster.tick();
+            }
+            for reply in cluster.replies.drain(..) {
+                let body = &reply["body"];
+                let in_reply_to = body["in_reply_to"].as_i64().unwrap();
+                let (start, delta) = started.remove(&in_reply_to).expect("one reply per op");
+                answered += 1;
+                let kind = match (body["type"].as_str().unwrap(), delta) {
+                    ("error", Some(_)) if body["code"] == 30 => continue,
+                    ("add_ok", Some(delta)) => CounterKind::Add(delta),
+                    ("read_ok", None) => CounterKind::Read(body["value"].as_i64().unwrap()),
+                    _ => panic!("unexpected reply {reply}"),
+                };
+                let end = cluster.steps;
+                history.push(CounterOp { start, end, kind });
+            }
+        }
+        history
+    }
+
+    /// The bodies of the `kind` messages in `sent`.
+    fn bodies<'a>(sent: &'a [Value], kind: &str) -> Vec<&'a Value> {
+        sent.iter()
+            .map(|msg| &msg["body"])
+            .filter(|body| body["type"] == kind)
+            .collect()
+    }
+
+    #[test]
+    fn unacked_updates_are_resent_once_per_peer_until_acked() {
+        let config = Config {
+            gossip_acks: true,
+            ..Default::default()
+        };
+        let mut n1 = test_node("n1", &["n1", "n2"], config);
+        let topology = json!({"n1": ["n2"], "n2": ["n1"]});
+        n1.handle(
+            "c0",
+            json!({"type": "topology", "msg_id": 1, "topology": topology}),
+        );
+        // Both updates are lost: the whole set {1}, then the delta {2} of a
+        // later generation.
+        for (msg_id, message) in [(2, 1), (3, 2)] {
+            let sent = n1.handle(
+                "c1",
+                json!({"type": "broadcast", "msg_id": msg_id, "message": message}),
+            );
+            assert_eq!(bodies(&sent, "update").len(), 1);
+        }
+        assert_eq!(n1.node.unacked_gossip.len(), 2);
+
+        n1.node.clock.advance(GOSSIP_RETRY_INITIAL);
+        let sent = n1.tick();
+        let updates = bodies(&sent, "update");
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0]["messages"], json!([1, 2]));
+        assert_ne!(updates[0]["delta"], true, "a whole set");
+        assert_eq!(n1.node.gossip_backoff["n2"], GOSSIP_RETRY_INITIAL * 2);
+
+        let msg_id = updates[0]["msg_id"].clone();
+        n1.handle("n2", json!({"type": "update_ok", "in_reply_to": msg_id}));
+        assert!(n1.node.unacked_gossip.is_empty());
+        assert!(n1.node.gossip_backoff.is_empty());
+        assert!(!n1.node.dirty_peers.contains("n2"));
+    }
+
+    #[test]
+    fn contains_reports_presence() {
+        let mut n1 = test_node("n1", &["n1"], Config::default());
+        n1.request(json!({"type": "broadcast", "msg_id": 1, "message": 7}));
+        let reply = n1.request(json!({"type": "contains", "msg_id": 2, "value": 7}));
+        assert_eq!(reply["present"], true);
+        let reply = n1.request(json!({"type": "contains", "msg_id": 3, "value": 8}));
+        assert_eq!(reply["present"], false);
+    }
+
+    #[test]
+    fn fast_echo_ok_matches_serde() {
+        for echo in [
+            "hi",
+            "",
+            "quote \" and \\ backslash",
+            "line\nbreak\ttab",
+            "ünïcödé \u{1}",
+        ] {
+            let sink = VecSink::default();
+            let mut out = Output::with_sink(sink.clone());
+            send_echo_ok(&mut out, "n1", "c\"1", 42, echo).unwrap();
+            let msg = Response {
+                src: "n1",
+                dest: "c\"1",
+                body: ResponseBody::EchoOk {
+                    msg_id: 42,
+                    in_reply_to: 42,
+                    echo,
+                },
+            };
+            assert_eq!(sink.take(), [serde_json::to_string(&msg).unwrap()]);
+        }
+    }
+
+    #[test]
+    fn echo_batch_replies_once_in_order() {
+        let mut n1 = test_node("n1", &["n1"], Config::default());
+        let batch = json!({"type": "echo_batch", "msg_id": 1, "echos": ["c", "a", "b"]});
+        let reply = n1.request(batch);
+        assert_eq!(reply["type"], "echo_batch_ok");
+        assert_eq!(reply["in_reply_to"], 1);
+        assert_eq!(reply["echos"], json!(["c", "a", "b"]));
+    }
+
+    #[test]
+    fn read_page_returns_every_value_exactly_once() {
+        let mut n1 = test_node("n1", &["n1"], Config::default());
+        // Added out of order, so pages have to sort.
+        let values: Vec<i64> = (0..50).map(|i| (i * 37) % 101).collect();
+        for (msg_id, &value) in values.iter().enumerate() {
+            n1.request(json!({"type": "broadcast", "msg_id": msg_id, "message": value}));
+        }
+        let mut read = Vec::new();
+        let mut after = Value::Null;
+        let mut pages = 0;
+        loop {
+            let page = json!({"type": "read_page", "msg_id": 99, "after": after, "limit": 7});
+            let reply = n1.request(page);
+            let messages = reply["messages"].as_array().unwrap();
+            assert!(messages.len() <= 7);
+            read.extend(messages.iter().map(|value| value.as_i64().unwrap()));
+            pages += 1;
+            after = reply["next"].clone();
+            if after.is_null() {
+                break;
+            }
+        }
+        let mut expected = values;
+        expected.sort_unstable();
+        assert_eq!(read, expected);
+        assert_eq!(pages, 8);
+
+        let reply = n1.request(json!({"type": "read_page", "msg_id": 100, "limit": 0}));
+        assert_eq!(reply["code"], 12);
+    }
+
+    #[test]
+    fn three_nodes_converge_on_every_broadcast() {
+        let topology = json!({"n1": ["n2"], "n2": ["n1", "n3"], "n3": ["n2"]});
+        let mut cluster = cluster(&["n1", "n2", "n3"], topology, Config::default);
+        for (msg_id, (id, message)) in [("n1", 1), ("n3", 2), ("n2", 3), ("n1", 4)]
+            .into_iter()
+            .enumerate()
+        {
+            let body = json!({"type": "broadcast", "msg_id": msg_id, "message": message});
+            cluster.client(id, body);
+        }
+        assert!(converge(&mut cluster, Duration::from_secs(1)));
+        assert_eq!(
+            cluster.nodes["n3"].node.messages,
+            BTreeSet::from([1, 2, 3, 4])
+        );
+        assert_eq!(bodies(&cluster.replies, "broadcast_ok").len(), 4);
+    }
+
+    #[test]
+    fn anti_entropy_recovers_a_dropped_update() {
+        let topology = json!({"n1": ["n2"], "n2": ["n1", "n3"], "n3": ["n2"]});
+        let mut cluster = cluster(&["n1", "n2", "n3"], topology, Config::default);
+        let mut dropped = false;
+        cluster.schedule = Box::new(move |msg| {
+            let lost = msg["src"] == "n1" && msg["dest"] == "n2" && msg["body"]["type"] == "update";
+            if lost && !dropped {
+                dropped = true;
+                return Fate::Drop;
+            }
+            // Reordering n2's messages shouldn't matter either.
+            match msg["src"].as_str() {
+                Some("n2") => Fate::Delay(2),
+                _ => Fate::Deliver,
+            }
+        });
+        cluster.client(
+            "n1",
+            json!({"type": "broadcast", "msg_id": 1, "message": 7}),
+        );
+        assert!(cluster.pump_until(Duration::ZERO, Cluster::quiet));
+        assert!(cluster.nodes["n2"].node.messages.is_empty());
+        let drops = cluster.log.iter().filter(|(fate, _)| *fate == Fate::Drop);
+        assert_eq!(drops.count(), 1);
+        assert!(converge(&mut cluster, Duration::from_secs(1)));
+        assert_eq!(cluster.nodes["n3"].node.messages, BTreeSet::from([7]));
+    }
+
+    #[test]
+    fn replicated_state_reads_the_same() {
+        let config = || Config {
+            g_set: true,
+            ..Default::default()
+        };
+        let mut a = test_node("n1", &["n1", "n2"], config());
+        let mut b = test_node("n2", &["n1", "n2"], config());
+        for (msg_id, message) in [(1, 30), (2, 10), (3, 20)] {
+            a.handle(
+                "c1",
+                json!({"type": "broadcast", "msg_id": msg_id, "message": message}),
+            );
+        }
+        let txn = json!({"type": "txn", "msg_id": 4, "txn": [["append", 9, 1], ["append", 9, 2]]});
+        a.handle("c1", txn);
+
+        // Through JSON, as a snapshot sent between nodes would go.
+        let snapshot = serde_json::to_string(&a.node.snapshot()).unwrap();
+        b.node.restore(serde_json::from_str(&snapshot).unwrap());
+        let read = json!({"type": "read", "msg_id": 5});
+        assert_eq!(b.request(read.clone())["value"], a.request(read)["value"]);
+        assert_eq!(b.node.messages, BTreeSet::from([10, 20, 30]));
+        let txn = json!({"type": "txn", "msg_id": 6, "txn": [["r", 9, null]]});
+        assert_eq!(b.request(txn)["txn"], json!([["r", 9, [1, 2]]]));
+    }
+
+    #[test]
+    fn counter_check_accepts_a_good_history_and_flags_bad_ones() {
+        let op = |start, end, kind| CounterOp { start, end, kind };
+        let good = [
+            op(0, 2, CounterKind::Add(5)),
+            op(1, 3, CounterKind::Read(0)),
+            op(3, 4, CounterKind::Read(5)),
+            op(3, 6, CounterKind::Add(3)),
+            op(5, 7, CounterKind::Read(8)),
+            op(8, 9, CounterKind::Read(8)),
+        ];
+        assert_eq!(check_counter(&good), Ok(()));
+
+        let mut stale = good;
+        // Misses the add of 5 that completed before it began.
+        stale[2] = op(3, 4, CounterKind::Read(0));
+        assert!(check_counter(&stale).is_err());
+        let mut backwards = good;
+        // Within bounds, but lower than the read of 8 that finished first.
+        backwards[5] = op(8, 9, CounterKind::Read(5));
+        assert!(check_counter(&backwards).is_err());
+        let mut invented = good;
+        invented[1] = op(1, 3, CounterKind::Read(9));
+        assert!(check_counter(&invented).is_err());
+    }
+
+    #[test]
+    fn counter_is_linearizable() {
+        let topology = json!({"n1": ["n2", "n3"], "n2": ["n1", "n3"], "n3": ["n1", "n2"]});
+        let mut cluster = cluster(&["n1", "n2", "n3"], topology, Config::default);
+        let ops: Vec<(&str, Value)> = (0..30)
+            .map(|i| {
+                let id = ["n1", "n2", "n3"][i % 3];
+                let body = match i % 4 {
+                    0 | 2 => json!({"type": "add", "delta": i}),
+                    _ => json!({"type": "read"}),
+                };
+                (id, body)
+            })
+            .collect();
+        let history = run_counter_ops(&mut cluster, &ops);
+        assert_eq!(check_counter(&history), Ok(()));
+        let adds = history
+            .iter()
+            .filter(|op| matches!(op.kind, CounterKind::Add(_)));
+        assert!(adds.count() > 10, "{history:?}");
+        // Enough overlap that some adds raced for the same CAS.
+        let overlapping = history.windows(2).any(|pair| pair[1].start < pair[0].end);
+        assert!(overlapping, "{history:?}");
+    }
+
+    #[test]
+    fn kv_replies_run_the_continuation_their_request_registered() {
+        let mut cluster = cluster(&["n1"], json!({"n1": []}), Config::default);
+        let seq_kv = cluster.kv.entry("seq-kv".to_owned()).or_default();
+        seq_kv.values.insert("counter".to_owned(), json!(5));
+        let replies = cluster.request("n1", json!({"type": "read", "msg_id": 1}));
+        assert_eq!(
+            (&replies[0]["type"], &replies[0]["value"]),
+            (&json!("read_ok"), &json!(5))
+        );
+        // Read, then CAS.
+        let replies = cluster.request("n1", json!({"type": "add", "msg_id": 2, "delta": 3}));
+        assert_eq!(replies[0]["type"], "add_ok");
+        assert_eq!(cluster.kv["seq-kv"].values["counter"], 8);
+        // A key seq-kv has never seen reads as 0.
+        let read = json!({"type": "read", "msg_id": 3, "key": "other"});
+        let replies = cluster.request("n1", read);
+        assert_eq!(
+            (&replies[0]["type"], &replies[0]["value"]),
+            (&json!("read_ok"), &json!(0))
+        );
+        assert_eq!(cluster.nodes["n1"].node.kv.pending().count(), 0);
+    }
+
+    #[test]
+    fn a_lost_cas_rereads_and_a_timed_out_add_fails() {
+        let mut n1 = test_node("n1", &["n1"], Config::default());
+        let sent = n1.handle("c1", json!({"type": "add", "msg_id": 1, "delta": 2}));
+        let read_id = sent[0]["body"]["msg_id"].clone();
+        assert_eq!(sent[0]["body"]["type"], "read");
+        let read_ok =
+            |id: &Value, value| json!({"type": "read_ok", "in_reply_to": id, "value": value});
+        let sent = n1.handle("seq-kv", read_ok(&read_id, 1));
+        let cas = sent[0]["body"].clone();
+        assert_eq!(
+            (&cas["type"], &cas["from"], &cas["to"]),
+            (&json!("cas"), &json!(1), &json!(3))
+        );
+        // Another writer got there first, so the add starts over.
+        let lost = json!({"type": "error", "in_reply_to": cas["msg_id"], "code": 22, "text": ""});
+        let sent = n1.handle("seq-kv", lost);
+        assert_eq!(sent[0]["body"]["type"], "read");
+        let sent = n1.handle("seq-kv", read_ok(&sent[0]["body"]["msg_id"], 4));
+        let cas = sent[0]["body"].clone();
+        assert_eq!((&cas["from"], &cas["to"]), (&json!(4), &json!(6)));
+        let sent = n1.handle(
+            "seq-kv",
+            json!({"type": "cas_ok", "in_reply_to": cas["msg_id"]}),
+        );
+        assert_eq!(sent[0]["dest"], "c1");
+        assert_eq!(
+            sent[0]["body"],
+            json!({"type": "add_ok", "msg_id": 1, "in_reply_to": 1})
+        );
+
+        let sent = n1.handle("c1", json!({"type": "add", "msg_id": 2, "delta": 2}));
+        let read_id = sent[0]["body"]["msg_id"].clone();
+        n1.node.clock.advance(n1.node.config.kv_timeout);
+        let sent = n1.tick();
+        assert_eq!(sent[0]["body"]["code"], 0);
+        assert_eq!(sent[0]["body"]["in_reply_to"], 2);
+        assert_eq!(n1.node.kv.pending().count(), 0);
+        // The read's reply turning up late is a stray.
+        assert!(n1.handle("seq-kv", read_ok(&read_id, 4)).is_empty());
+    }
+
+    #[test]
+    fn both_payload_hashers_match_equal_sets_and_tell_different_ones_apart() {
+        let hashers: [&dyn PayloadHasher; 2] = [&FxPayloadHasher, &SipPayloadHasher];
+        let set = |values: &[i64]| values.iter().copied().collect::<BTreeSet<i64>>();
+        for hasher in hashers {
+            assert_eq!(hasher.hash(&set(&[1, 2, 3])), hasher.hash(&set(&[3, 2, 1])));
+            for other in [&[1, 2][..], &[1, 2, 4], &[], &[0, 1, 2, 3]] {
+                assert_ne!(hasher.hash(&set(&[1, 2, 3])), hasher.hash(&set(other)));
+            }
+        }
+    }
+
+    #[test]
+    fn a_resent_identical_set_is_not_merged_again() {
+        for dedup_hash in [DedupHash::Fx, DedupHash::Sip] {
+            let config = Config {
+                dedup_hash: Some(dedup_hash),
+                ..Default::default()
+            };
+            let mut n1 = test_node("n1", &["n1", "n2"], config);
+            let update = |generation, messages: &[i64]| json!({"type": "update", "messages": messages, "generation": generation});
+            n1.handle("n2", update(1, &[1, 2]));
+            n1.handle("n2", update(2, &[1, 2]));
+            assert_eq!(n1.node.metrics.gossip_deduped, 1);
+            assert_eq!(n1.node.metrics.values_duplicate, 0, "not merged");
+            n1.handle("n2", update(3, &[1, 2, 3]));
+            assert_eq!(n1.node.metrics.gossip_deduped, 1);
+            assert_eq!(n1.node.messages, BTreeSet::from([1, 2, 3]));
+        }
+    }
+
+    #[test]
+    fn expired_values_and_then_their_tombstones_are_let_go() {
+        let ttl = Duration::from_millis(100);
+        let config = Config {
+            message_ttl: Some(ttl),
+            ..Default::default()
+        };
+        let mut n1 = test_node("n1", &["n1", "n2"], config);
+        for message in [1, 2] {
+            n1.request(json!({"type": "broadcast", "msg_id": 1, "message": message}));
+        }
+        n1.node.clock.advance(ttl);
+        n1.tick();
+        assert!(n1.node.messages.is_empty());
+        assert_eq!(n1.node.expiry.expired, HashSet::from([1, 2]));
+        // Still refused while the tombstone lasts.
+        n1.handle(
+            "n2",
+            json!({"type": "update", "messages": [1], "generation": 1}),
+        );
+        assert!(n1.node.messages.is_empty());
+
+        n1.node.clock.advance(ttl);
+        n1.tick();
+        assert!(n1.node.expiry.expired.is_empty());
+        assert!(n1.node.expiry.tombstones.is_empty());
+        assert!(n1.node.expiry.queue.is_empty());
+    }
+
+    #[test]
+    fn a_restarted_node_hands_out_local_ids_past_its_last_run() {
+        let clock = Clock::manual();
+        let start = || {
+            let node = Node {
+                clock: clock.clone(),
+                ..Default::default()
+            };
+            TestNode::new(node, "n1", &["n1"])
+        };
+        let mut n1 = start();
+        let before: Vec<u64> = (0..3).map(|_| n1.node.local_id()).collect();
+        clock.advance(Duration::from_secs(1));
+        let mut n1 = start();
+        let after = n1.node.local_id();
+        assert!(
+            before.iter().all(|&id| id < after),
+            "{before:?} then {after}"
+        );
+        assert_ne!(after & LOCAL_ID_BIT, 0);
+        assert_eq!(after >> 32, 1, "still n1's prefix");
+    }
+
+    #[test]
+    fn spanning_tree_gossip_crosses_a_complete_graph_in_n_minus_one_messages() {
+        let ids = ["n1", "n2", "n3", "n4", "n5"];
+        let topology: HashMap<&str, Vec<&str>> = ids
+            .iter()
+            .map(|&id| (id, ids.iter().copied().filter(|&peer| peer != id).collect()))
+            .collect();
+        let mut cluster = cluster(&ids, json!(topology), Config::default);
+        for (message, id) in ids.into_iter().enumerate() {
+            let body = json!({"type": "broadcast", "msg_id": 1, "message": message});
+            cluster.request(id, body);
+            let sent: Vec<Value> = cluster.log.drain(..).map(|(_, msg)| msg).collect();
+            assert_eq!(bodies(&sent, "update").len(), ids.len() - 1);
+        }
+        for node in cluster.nodes.values() {
+            assert_eq!(node.node.messages.len(), ids.len());
+        }
+    }
+
+    #[test]
+    fn dump_config_reflects_an_init_override() {
+        let mut n1 = test_node("n1", &["n1"], Config::default());
+        let dump = json!({"type": "dump_config", "msg_id": 2});
+        assert_eq!(n1.request(dump.clone())["config"]["kv_service"], "seq-kv");
+        let init = json!({
+            "type": "init",
+            "msg_id": 1,
+            "node_id": "n1",
+            "node_ids": ["n1"],
+            "kv_service": "lin-kv",
+        });
+        n1.handle("c0", init);
+        let config = n1.request(dump)["config"].take();
+        assert_eq!(config["kv_service"], "lin-kv");
+        assert_eq!(config["max_cas_attempts"], 10);
+    }
+
+    /// A grid of `rows` by `cols` nodes, numbered from n1 row by row, each
+    /// linked to the ones above, below and beside it.
+    fn grid_topology(rows: usize, cols: usize) -> (Vec<String>, Value) {
+        let ids: Vec<String> = (1..=rows * cols).map(|i| format!("n{i}")).collect();
+        let topology: HashMap<&str, Vec<&str>> = ids
+            .iter()
+            .enumerate()
+            .map(|(i, id)| {
+                let (row, col) = (i / cols, i % cols);
+                let neighbours = [
+                    row.checked_sub(1).map(|row| (row, col)),
+                    (row + 1 < rows).then_some((row + 1, col)),
+                    col.checked_sub(1).map(|col| (row, col)),
+                    (col + 1 < cols).then_some((row, col + 1)),
+                ];
+                let neighbours = neighbours.into_iter().flatten();
+                (
+                    id.as_str(),
+                    neighbours
+                        .map(|(r, c)| ids[r * cols + c].as_str())
+                        .collect(),
+                )
+            })
+            .collect();
+        let topology = json!(topology);
+        (ids, topology)
+    }
+
+    /// Bytes the nodes of a 25-node line send to broadcast 100 values, from
+    /// each node in turn. With `full`, every node forgets what its peers have
+    /// before each broadcast, so it sends them whole sets as gossip did
+    /// before deltas.
+    fn line_broadcast_bytes(full: bool) -> usize {
+        let (ids, topology) = grid_topology(1, 25);
+        let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+        let mut cluster = cluster(&ids, topology, Config::default);
+        let sent = |cluster: &Cluster<Node>| -> usize {
+            cluster.nodes.values().map(|node| node.bytes_sent).sum()
+        };
+        let before = sent(&cluster);
+        for (message, id) in (1_000_000..1_000_100).zip(ids.iter().cycle()) {
+            if full {
+                for node in cluster.nodes.values_mut() {
+                    node.node.peer_known.clear();
+                }
+            }
+            cluster.request(
+                id,
+                json!({"type": "broadcast", "msg_id": 1, "message": message}),
+            );
+        }
+        for node in cluster.nodes.values() {
+            assert_eq!(node.node.messages.len(), 100);
+        }
+        sent(&cluster) - before
+    }
+
+    #[test]
+    fn delta_gossip_sends_a_fraction_of_the_bytes_of_whole_sets() {
+        let (delta, full) = (line_broadcast_bytes(false), line_broadcast_bytes(true));
+        assert!(delta * 3 < full, "delta {delta} bytes, full sets {full}");
+    }
+
+    /// Updates sent per broadcast on a 5x5 grid under `topology`, for one
+    /// broadcast to a corner and one to the centre.
+    fn grid_updates_per_broadcast(topology: TopologyStrategy) -> Vec<usize> {
+        let (ids, grid) = grid_topology(5, 5);
+        let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+        let config = || Config {
+            topology,
+            ..Default::default()
+        };
+        let mut cluster = cluster(&ids, grid, config);
+        [(1, "n1"), (2, "n13")]
+            .into_iter()
+            .map(|(message, id)| {
+                cluster.request(
+                    id,
+                    json!({"type": "broadcast", "msg_id": 1, "message": message}),
+                );
+                let sent: Vec<Value> = cluster.log.drain(..).map(|(_, msg)| msg).collect();
+                bodies(&sent, "update").len()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn origin_trees_on_a_grid_send_fewer_updates_than_flooding() {
+        let tree = grid_updates_per_broadcast(TopologyStrategy::OriginTree);
+        assert_eq!(tree, [24, 24], "one per edge of a 25-node tree");
+        let flooding = grid_updates_per_broadcast(TopologyStrategy::Given);
+        assert!(
+            flooding.iter().all(|&updates| updates > 2 * 24),
+            "{flooding:?}"
+        );
+    }
+
+    #[test]
+    fn path_to_takes_a_shortest_route_across_a_grid() {
+        let (ids, grid) = grid_topology(5, 5);
+        let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+        let mut n7 = test_node("n7", &ids, Config::default());
+        n7.request(json!({"type": "topology", "msg_id": 1, "topology": grid}));
+        let adjacent = |a: &str, b: &str| n7.node.adjacency[a].iter().any(|id| id == b);
+        // From row 1, column 1 to row 4, column 3: three down, two across.
+        let path = n7.node.path_to("n24");
+        assert_eq!(path.len(), 5, "{path:?}");
+        assert_eq!(path.last().map(String::as_str), Some("n24"));
+        let hops = std::iter::once("n7").chain(path.iter().map(String::as_str));
+        assert!(
+            hops.clone().zip(hops.skip(1)).all(|(a, b)| adjacent(a, b)),
+            "{path:?}"
+        );
+        assert_eq!(n7.node.neighbors(), ["n2", "n12", "n6", "n8"]);
+        assert!(n7.node.path_to("n7").is_empty());
+        assert!(n7.node.path_to("n99").is_empty());
+    }
+
+    #[test]
+    fn an_init_with_an_unnumbered_node_id_is_refused() {
+        let (_, sent) = TestNode::start(Node::default(), "x", &["x"]);
+        assert_eq!(sent[0]["body"]["type"], "error");
+        assert_eq!(sent[0]["body"]["code"], 12);
+        assert_eq!(sent[0]["body"]["details"]["node_id"], "x");
+    }
+
+    #[test]
+    fn a_client_broadcast_reaches_every_neighbor_and_a_peer_update_skips_its_sender() {
+        let mut n1 = test_node("n1", &["n1", "n2", "n3"], Config::default());
+        let topology = json!({"n1": ["n2", "n3"]});
+        n1.handle(
+            "c0",
+            json!({"type": "topology", "msg_id": 1, "topology": topology}),
+        );
+        let updated = |sent: &[Value]| {
+            let mut dests: Vec<String> = sent
+                .iter()
+                .filter(|msg| msg["body"]["type"] == "update")
+                .map(|msg| msg["dest"].as_str().unwrap().to_owned())
+                .collect();
+            dests.sort();
+            dests
+        };
+
+        let sent = n1.handle(
+            "c3",
+            json!({"type": "broadcast", "msg_id": 2, "message": 1}),
+        );
+        assert_eq!(updated(&sent), ["n2", "n3"]);
+        let sent = n1.handle(
+            "n2",
+            json!({"type": "broadcast", "msg_id": 3, "message": 2}),
+        );
+        assert_eq!(updated(&sent), ["n3"]);
+    }
+
+    #[test]
+    fn a_stats_reset_zeroes_the_counters_only_in_debug_mode() {
+        let config = Config {
+            debug: true,
+            ..Default::default()
+        };
+        let mut n1 = test_node("n1", &["n1", "n2"], config);
+        n1.handle(
+            "c1",
+            json!({"type": "broadcast", "msg_id": 1, "message": 1}),
+        );
+        n1.handle("n2", json!({"type": "update", "messages": [1, 2]}));
+        let stats = n1.request(json!({"type": "stats", "msg_id": 2, "reset": true}));
+        assert_eq!(stats["metrics"]["values_new"], 2);
+        assert_eq!(stats["metrics"]["values_duplicate"], 1);
+        assert_eq!(stats["metrics"]["distinct_sources"], 1);
+
+        let stats = n1.request(json!({"type": "stats", "msg_id": 3}));
+        let metrics = stats["metrics"].as_object().unwrap();
+        let counted: Vec<_> = metrics
+            .iter()
+            .filter(|&(name, value)| name != "messages_received" && value != 0 && value != 0.0)
+            .collect();
+        assert!(counted.is_empty(), "{counted:?}");
+        assert_eq!(metrics["messages_received"], 1, "only this stats request");
+
+        let mut n1 = test_node("n1", &["n1"], Config::default());
+        let reply = n1.request(json!({"type": "stats", "msg_id": 1, "reset": true}));
+        assert_eq!(reply["code"], 10);
+    }
+
+    #[test]
+    fn a_quorum_read_sees_an_add_made_through_another_node() {
+        let topology = json!({"n1": ["n2"], "n2": ["n1"]});
+        let mut cluster = cluster(&["n1", "n2"], topology, Config::default);
+        let replies = cluster.request("n2", json!({"type": "add", "msg_id": 1, "delta": 5}));
+        assert_eq!(replies[0]["type"], "add_ok");
+        let cached = |cluster: &Cluster<Node>| {
+            let cache = &cluster.nodes["n1"].node.counter_cache;
+            cache.get(DEFAULT_COUNTER_KEY).copied()
+        };
+        assert_eq!(cached(&cluster), None);
+
+        let read = json!({"type": "read", "msg_id": 2, "consistency": "read-quorum"});
+        let replies = cluster.request("n1", read);
+        assert_eq!(replies[0]["value"], 5);
+        assert_eq!(cached(&cluster), Some(5));
+    }
+
+    #[test]
+    fn an_unexpected_kv_error_reaches_the_client_and_not_initialized_is_retried() {
+        let mut n1 = test_node("n1", &["n1"], Config::default());
+        let sent = n1.handle("c1", json!({"type": "add", "msg_id": 1, "delta": 2}));
+        let error = |sent: &[Value], code| {
+            let id = &sent[0]["body"]["msg_id"];
+            json!({"type": "error", "in_reply_to": id, "code": code, "text": "kv says no"})
+        };
+        let sent = n1.handle("seq-kv", error(&sent, 11));
+        assert_eq!(sent[0]["dest"], "seq-kv");
+        assert_eq!(sent[0]["body"]["type"], "read", "retried");
+        let sent = n1.handle("seq-kv", error(&sent, 13));
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0]["dest"], "c1");
+        let body = &sent[0]["body"];
+        assert_eq!(
+            (&body["type"], &body["code"]),
+            (&json!("error"), &json!(13))
+        );
+        assert_eq!(
+            (&body["in_reply_to"], &body["text"]),
+            (&json!(1), &json!("kv says no"))
+        );
+        assert_eq!(n1.node.kv.pending().count(), 0);
+    }
+
+    /// n1 with six peers as neighbors, after a client broadcast of 1, with
+    /// how many updates that input sent and then each tick after it, until
+    /// one sends none.
+    fn updates_per_iteration(config: Config) -> Vec<usize> {
+        let ids = ["n1", "n2", "n3", "n4", "n5", "n6", "n7"];
+        let mut n1 = test_node("n1", &ids, config);
+        let topology = json!({"n1": &ids[1..]});
+        n1.handle(
+            "c0",
+            json!({"type": "topology", "msg_id": 1, "topology": topology}),
+        );
+        let mut counts = vec![bodies(
+            &n1.handle(
+                "c1",
+                json!({"type": "broadcast", "msg_id": 2, "message": 1}),
+            ),
+            "update",
+        )
+        .len()];
+        while counts.last() != Some(&0) {
+            counts.push(bodies(&n1.tick(), "update").len());
+        }
+        counts
+    }
+
+    #[test]
+    fn a_gossip_cap_spreads_a_round_across_iterations() {
+        let config = Config {
+            max_gossip_per_iteration: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(updates_per_iteration(config), [2, 2, 2, 0]);
+        assert_eq!(updates_per_iteration(Config::default()), [6, 0]);
+    }
+
+    #[test]
+    fn an_iteration_over_its_time_budget_defers_the_rest_of_its_gossip() {
+        // Any time at all is over a zero budget, so each iteration gets
+        // its one message out and leaves the rest.
+        let config = Config {
+            iteration_budget: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        assert_eq!(updates_per_iteration(config), [1, 1, 1, 1, 1, 1, 0]);
+        let config = Config {
+            iteration_budget: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        assert_eq!(updates_per_iteration(config), [6, 0]);
+    }
+
+    #[test]
+    fn named_counters_add_up_independently() {
+        let topology = json!({"n1": ["n2"], "n2": ["n1"]});
+        let mut cluster = cluster(&["n1", "n2"], topology, Config::default);
+        for (id, key, delta) in [("n1", "a", 3), ("n2", "b", 5), ("n2", "a", 1)] {
+            let add = json!({"type": "add", "msg_id": 1, "key": key, "delta": delta});
+            assert_eq!(cluster.request(id, add)[0]["type"], "add_ok");
+        }
+        for (id, key, value) in [
+            ("n1", json!("a"), 4),
+            ("n1", json!("b"), 5),
+            ("n2", Value::Null, 0),
+        ] {
+            let read = json!({"type": "read", "msg_id": 2, "key": key});
+            assert_eq!(cluster.request(id, read)[0]["value"], value, "{key}");
+        }
+        let seq_kv = &cluster.kv["seq-kv"].values;
+        assert_eq!((&seq_kv["a"], &seq_kv["b"]), (&json!(4), &json!(5)));
+    }
+
+    #[test]
+    fn a_reads_reported_age_grows_until_the_value_is_refreshed() {
+        let topology = json!({"n1": ["n2"], "n2": ["n1"]});
+        let mut cluster = cluster(&["n1", "n2"], topology, Config::default);
+        cluster.request("n2", json!({"type": "add", "msg_id": 1, "delta": 5}));
+        let quorum = json!({"type": "read", "msg_id": 2, "consistency": "read-quorum"});
+        // n1 has never read seq-kv itself, so only n2's refresh has an age.
+        for (wait, age) in [(300, 300), (200, 500)] {
+            cluster.clock.advance(Duration::from_millis(wait));
+            let read = cluster.request("n1", quorum.clone()).remove(0);
+            assert_eq!((&read["value"], &read["age_ms"]), (&json!(5), &json!(age)));
+        }
+        let read = cluster.request("n2", json!({"type": "read", "msg_id": 3}));
+        assert_eq!(read[0]["age_ms"], 0, "a read-one refreshes");
+    }
+
+    #[test]
+    fn a_cluster_past_the_topology_limit_falls_back_to_random_gossip() {
+        for (size, strategy, updates) in [
+            (10, TopologyStrategy::Given, 9),
+            (40, TopologyStrategy::RandomSubset, 3),
+        ] {
+            let ids: Vec<String> = (1..=size).map(|i| format!("n{i}")).collect();
+            let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+            let config = Config {
+                topology: TopologyStrategy::Given,
+                max_topology_nodes: Some(20),
+                ..Default::default()
+            };
+            let mut n1 = test_node("n1", &ids, config);
+            let topology = json!({"n1": &ids[1..]});
+            n1.handle(
+                "c0",
+                json!({"type": "topology", "msg_id": 1, "topology": topology}),
+            );
+            assert_eq!(n1.node.strategy, strategy);
+            let sent = n1.handle(
+                "c1",
+                json!({"type": "broadcast", "msg_id": 2, "message": 1}),
+            );
+            assert_eq!(bodies(&sent, "update").len(), updates, "{size} nodes");
+        }
+    }
+
+    #[test]
+    fn a_silent_peer_is_left_out_of_gossip_until_it_speaks_again() {
+        let config = Config {
+            topology: TopologyStrategy::Given,
+            peer_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let mut n1 = test_node("n1", &["n1", "n2", "n3"], config);
+        let topology = json!({"n1": ["n2", "n3"]});
+        n1.handle(
+            "c0",
+            json!({"type": "topology", "msg_id": 1, "topology": topology}),
+        );
+        let broadcast = |n1: &mut TestNode<Node>, message| {
+            let body = json!({"type": "broadcast", "msg_id": message, "message": message});
+            let sent = n1.handle("c1", body);
+            let mut dests: Vec<_> = sent
+                .iter()
+                .filter(|msg| msg["body"]["type"] == "update")
+                .map(|msg| msg["dest"].as_str().unwrap().to_owned())
+                .collect();
+            dests.sort();
+            dests
+        };
+        assert_eq!(broadcast(&mut n1, 1), ["n2", "n3"]);
+        n1.handle("n3", json!({"type": "update", "messages": [1]}));
+        n1.node.clock.advance(Duration::from_millis(100));
+        assert_eq!(broadcast(&mut n1, 2), ["n3"]);
+        n1.handle("n2", json!({"type": "update", "messages": [1]}));
+        assert_eq!(broadcast(&mut n1, 3), ["n2", "n3"]);
+    }
+
+    #[test]
+    fn a_quorum_read_survives_a_minority_of_peer_errors() {
+        let mut n1 = test_node("n1", &["n1", "n2", "n3", "n4", "n5"], Config::default());
+        let quorum = json!({"type": "read", "msg_id": 1, "consistency": "read-quorum"});
+        let sent = n1.handle("c1", quorum.clone());
+        let read_id = sent[0]["body"]["msg_id"].clone();
+        let error = json!({"type": "error", "in_reply_to": read_id, "code": 13, "text": ""});
+        let ok = |value| json!({"type": "peer_read_ok", "in_reply_to": read_id, "value": value});
+        assert!(n1.handle("n2", error.clone()).is_empty());
+        assert!(n1.handle("n3", ok(7)).is_empty());
+        assert!(n1.handle("n4", error).is_empty());
+        let sent = n1.handle("n5", ok(3));
+        assert_eq!(sent[0]["body"]["type"], "read_ok");
+        assert_eq!(sent[0]["body"]["value"], 7);
+
+        let sent = n1.handle("c1", quorum);
+        let read_id = sent[0]["body"]["msg_id"].clone();
+        let error = json!({"type": "error", "in_reply_to": read_id, "code": 13, "text": ""});
+        assert!(n1.handle("n2", error.clone()).is_empty());
+        assert!(n1.handle("n3", error.clone()).is_empty());
+        let sent = n1.handle("n4", error);
+        assert_eq!(sent[0]["body"]["code"], 11, "a majority can't be reached");
+        assert!(n1.node.quorum_reads.is_empty());
+    }
+
+    #[test]
+    fn compact_gossip_round_trips_between_nodes_that_both_support_it() {
+        let set: BTreeSet<i64> = [-3, 0, 7, i64::MAX].into();
+        assert_eq!(decode_compact(&encode_compact(&set)).unwrap(), set);
+
+        let ids = ["n1", "n2", "n3"];
+        let config = || Config {
+            compact_gossip: true,
+            compact_threshold: 2,
+            topology: TopologyStrategy::Given,
+            ..Default::default()
+        };
+        let (mut n1, mut n2) = (
+            test_node("n1", &ids, config()),
+            test_node("n2", &ids, config()),
+        );
+        let topology = json!({"n1": ["n2", "n3"], "n2": ["n1"]});
+        for node in [&mut n1, &mut n2] {
+            node.handle(
+                "c0",
+                json!({"type": "topology", "msg_id": 1, "topology": topology}),
+            );
+        }
+        n1.handle("n2", json!({"type": "capabilities", "compact": true}));
+        // Past the threshold, only the peer that said it can decode gets
+        // the set compacted.
+        let sent = n1.handle("c1", json!({"type": "update", "messages": [1, 2, 3]}));
+        let to = |dest| sent.iter().find(|msg| msg["dest"] == dest).unwrap();
+        assert_eq!(to("n3")["body"]["type"], "update");
+        assert_eq!(to("n2")["body"]["type"], "compact_update");
+        n2.handle("n1", to("n2")["body"].clone());
+        assert_eq!(n2.node.messages, [1, 2, 3].into());
+        assert!(n1.node.metrics.compact_bytes_after > 0);
+
+        let sent = n1.handle("c1", json!({"type": "update", "messages": [4]}));
+        assert_eq!(bodies(&sent, "update").len(), 2, "within the threshold");
+    }
+
+    #[test]
+    fn compaction_metrics_count_only_sets_past_the_threshold() {
+        let config = Config {
+            compact_gossip: true,
+            compact_threshold: 100,
+            ..Default::default()
+        };
+        let mut n1 = test_node("n1", &["n1", "n2"], config);
+        let topology = json!({"n1": ["n2"]});
+        n1.handle(
+            "c0",
+            json!({"type": "topology", "msg_id": 1, "topology": topology}),
+        );
+        n1.handle("n2", json!({"type": "capabilities", "compact": true}));
+        let metrics = |n1: &mut TestNode<Node>| {
+            n1.request(json!({"type": "stats", "msg_id": 2}))["metrics"].take()
+        };
+
+        n1.handle("c1", json!({"type": "update", "messages": [1, 2, 3, 4, 5]}));
+        let small = metrics(&mut n1);
+        assert_eq!(
+            (&small["compact_bytes_before"], &small["compact_ratio"]),
+            (&json!(0), &json!(0.0))
+        );
+
+        let many: Vec<i64> = (1 << 50..(1 << 50) + 500).collect();
+        n1.handle("c1", json!({"type": "update", "messages": many}));
+        let large = metrics(&mut n1);
+        let before = large["compact_bytes_before"].as_u64().unwrap();
+        let after = large["compact_bytes_after"].as_u64().unwrap();
+        assert!(after > 0 && after < before, "{before} -> {after}");
+        let ratio = large["compact_ratio"].as_f64().unwrap();
+        assert_eq!(ratio, after as f64 / before as f64);
+    }
+
+    #[test]
+    fn counter_requests_go_to_the_configured_kv_service() {
+        let config = || Config {
+            kv_service: "lin-kv".to_owned(),
+            ..Default::default()
+        };
+        let mut cluster = cluster(&["n1"], json!({"n1": []}), config);
+        cluster.request("n1", json!({"type": "add", "msg_id": 1, "delta": 4}));
+        let dests: BTreeSet<&str> = cluster
+            .log
+            .iter()
+            .filter(|(_, msg)| msg["src"] == "n1" && msg["dest"] != "c1")
+            .filter_map(|(_, msg)| msg["dest"].as_str())
+            .collect();
+        assert_eq!(dests, ["lin-kv"].into());
+        assert_eq!(cluster.kv["lin-kv"].values[DEFAULT_COUNTER_KEY], 4);
+        assert!(!cluster.kv.contains_key("seq-kv"));
+
+        // An init naming a service overrides the config.
+        let mut n1 = test_node("n1", &["n1"], config());
+        let init = json!({"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"], "kv_service": "lww-kv"});
+        n1.handle("c0", init);
+        let sent = n1.handle("c1", json!({"type": "read", "msg_id": 2}));
+        assert_eq!(sent[0]["dest"], "lww-kv");
+    }
+
+    #[test]
+    fn a_stall_is_warned_about_once_naming_the_last_message() {
+        let threshold = Duration::from_secs(5);
+        let activity = Arc::new(Mutex::new(Activity {
+            since: Instant::now(),
+            last_type: String::from("none"),
+            warned: false,
+        }));
+        let mut n1 = test_node("n1", &["n1"], Config::default());
+        n1.node.watchdog = Some(Arc::clone(&activity));
+        n1.handle(
+            "c1",
+            json!({"type": "broadcast", "msg_id": 1, "message": 1}),
+        );
+        let mut activity = activity.lock().unwrap();
+        assert_eq!(activity.stall_warning(threshold), None, "not stalled yet");
+
+        // As if the handler had been stuck since.
+        activity.since -= threshold;
+        let warning = activity.stall_warning(threshold).unwrap();
+        assert!(warning.ends_with("last message: broadcast"), "{warning}");
+        assert_eq!(activity.stall_warning(threshold), None, "warned already");
+        activity.touch(None);
+        activity.since -= threshold;
+        assert!(activity.stall_warning(threshold).is_some(), "a new stall");
+    }
+
+    #[test]
+    fn an_update_older_than_one_merged_from_the_same_peer_is_ignored() {
+        let mut n1 = test_node("n1", &["n1", "n2", "n3"], Config::default());
+        let update = |messages: &[i64], generation| json!({"type": "update", "messages": messages, "generation": generation});
+        n1.handle("n2", update(&[2], 5));
+        n1.handle("n2", update(&[1], 3));
+        assert_eq!(n1.node.messages, [2].into(), "stale");
+        n1.handle("n3", update(&[1], 3));
+        assert_eq!(
+            n1.node.messages,
+            [1, 2].into(),
+            "another peer's generations"
+        );
+        n1.handle("n2", update(&[3], 5));
+        n1.handle("n2", update(&[4], 6));
+        assert_eq!(n1.node.messages, [1, 2, 3, 4].into());
+        assert_eq!(n1.node.seen_generations["n2"], 6);
+    }
+
+    #[test]
+    fn a_bounded_message_set_keeps_the_most_recent_values() {
+        let config = Config {
+            max_messages: Some(10),
+            ..Default::default()
+        };
+        let mut n1 = test_node("n1", &["n1", "n2"], config);
+        for message in 0..100 {
+            n1.handle(
+                "c1",
+                json!({"type": "broadcast", "msg_id": message, "message": message}),
+            );
+            assert!(n1.node.messages.len() <= 10);
+        }
+        // 90 is received again, so it outlives the 91 received before it.
+        n1.handle("n2", json!({"type": "update", "messages": [90, 100]}));
+        let kept: Vec<i64> = n1.node.messages.iter().copied().collect();
+        assert_eq!(kept, [90, 92, 93, 94, 95, 96, 97, 98, 99, 100]);
+    }
+
+    #[test]
+    fn error_details_round_trip_and_are_left_out_when_empty() {
+        let mut n1 = test_node("n1", &["n1"], Config::default());
+        let sent = n1.handle("c1", json!({"type": "add", "msg_id": 1, "delta": 2}));
+        let details = json!({"key": "counter", "retries": 3});
+        let error = json!({
+            "type": "error",
+            "in_reply_to": sent[0]["body"]["msg_id"],
+            "code": 13,
+            "text": "",
+            "details": details,
+        });
+        let sent = n1.handle("seq-kv", error);
+        assert_eq!(sent[0]["body"]["details"], details);
+
+        let reply = n1.request(json!({"type": "stats", "msg_id": 2, "reset": true}));
+        assert_eq!(reply["type"], "error");
+        assert!(reply.get("details").is_none(), "{reply}");
+    }
+
+    #[test]
+    fn health_goes_degraded_then_partitioned_as_peers_fall_silent() {
+        let timeout = Duration::from_millis(100);
+        let config = Config {
+            topology: TopologyStrategy::Given,
+            peer_timeout: Some(timeout),
+            ..Default::default()
+        };
+        let mut n1 = test_node("n1", &["n1", "n2", "n3"], config);
+        let topology = json!({"n1": ["n2", "n3"]});
+        n1.handle(
+            "c0",
+            json!({"type": "topology", "msg_id": 1, "topology": topology}),
+        );
+        let health = |n1: &mut TestNode<Node>| {
+            n1.request(json!({"type": "health", "msg_id": 2}))["status"].take()
+        };
+        let broadcast = |message| json!({"type": "broadcast", "msg_id": 3, "message": message});
+
+        n1.handle("c1", broadcast(1));
+        assert_eq!(health(&mut n1), "ok");
+        n1.handle("n3", json!({"type": "update", "messages": [1]}));
+        n1.node.clock.advance(timeout);
+        assert_eq!(health(&mut n1), "degraded", "n2 is silent");
+        n1.handle("c1", broadcast(2));
+        n1.node.clock.advance(timeout);
+        assert_eq!(health(&mut n1), "partitioned", "only n1 is left");
+        for peer in ["n2", "n3"] {
+            n1.handle(peer, json!({"type": "update", "messages": [1, 2]}));
+        }
+        assert_eq!(health(&mut n1), "ok");
+    }
+
+    #[test]
+    fn cas_and_gossip_retries_draw_on_one_budget() {
+        let config = Config {
+            retry_budget: Some(1),
+            gossip_acks: true,
+            ..Default::default()
+        };
+        let mut n1 = test_node("n1", &["n1", "n2"], config);
+        let topology = json!({"n1": ["n2"]});
+        n1.handle(
+            "c0",
+            json!({"type": "topology", "msg_id": 1, "topology": topology}),
+        );
+        let reply = |sent: &[Value], kind| {
+            let id = &sent[0]["body"]["msg_id"];
+            match kind {
+                "read_ok" => json!({"type": "read_ok", "in_reply_to": id, "value": 0}),
+                _ => json!({"type": "error", "in_reply_to": id, "code": 22, "text": ""}),
+            }
+        };
+        let lose_cas = |n1: &mut TestNode<Node>| {
+            let sent = n1.handle("c1", json!({"type": "add", "msg_id": 2, "delta": 1}));
+            let sent = n1.handle("seq-kv", reply(&sent, "read_ok"));
+            n1.handle("seq-kv", reply(&sent, "error"))
+        };
+        // The lost CAS takes the only token to read again.
+        let sent = lose_cas(&mut n1);
+        assert_eq!(sent[0]["body"]["type"], "read");
+        n1.handle("seq-kv", reply(&sent, "read_ok"));
+
+        let sent = n1.handle(
+            "c1",
+            json!({"type": "broadcast", "msg_id": 3, "message": 1}),
+        );
+        assert_eq!(bodies(&sent, "update").len(), 1);
+        n1.node.clock.advance(GOSSIP_RETRY_INITIAL);
+        assert!(
+            bodies(&n1.tick(), "update").is_empty(),
+            "no token left to resend"
+        );
+        assert_eq!(n1.node.metrics.retries_throttled, 1);
+
+        // A second of refill pays for the resend.
+        n1.node.clock.advance(Duration::from_secs(1));
+        assert_eq!(bodies(&n1.tick(), "update").len(), 1);
+        let throttled = n1.node.metrics.retries_throttled;
+        let sent = lose_cas(&mut n1);
+        assert_eq!(sent[0]["dest"], "c1", "{sent:?}");
+        assert_eq!(sent[0]["body"]["code"], 22);
+        assert!(n1.node.metrics.retries_throttled > throttled);
+    }
+
+    #[test]
+    fn every_message_a_node_starts_gets_a_fresh_rising_msg_id() {
+        let config = Config {
+            gossip_acks: true,
+            ..Default::default()
+        };
+        let mut n1 = test_node("n1", &["n1", "n2"], config);
+        let topology = json!({"n1": ["n2"]});
+        n1.handle(
+            "c0",
+            json!({"type": "topology", "msg_id": 1, "topology": topology}),
+        );
+        let mut started = Vec::new();
+        let mut keep = |sent: Vec<Value>| {
+            let own = sent
+                .iter()
+                .filter(|msg| msg["dest"] != "c1" && msg["body"]["in_reply_to"].is_null());
+            started.extend(own.map(|msg| msg["body"]["msg_id"].as_i64().unwrap()));
+            sent
+        };
+        keep(n1.handle(
+            "c1",
+            json!({"type": "broadcast", "msg_id": 1, "message": 1}),
+        ));
+        let sent = keep(n1.handle("c1", json!({"type": "add", "msg_id": 1, "delta": 1})));
+        let id = &sent[0]["body"]["msg_id"];
+        let sent = keep(n1.handle(
+            "seq-kv",
+            json!({"type": "read_ok", "in_reply_to": id, "value": 0}),
+        ));
+        assert_eq!(sent[0]["body"]["type"], "cas");
+        keep(n1.handle(
+            "c1",
+            json!({"type": "broadcast", "msg_id": 2, "message": 2}),
+        ));
+        assert_eq!(started.len(), 4, "two updates, a read and a cas");
+        assert!(
+            started.windows(2).all(|pair| pair[0] < pair[1]),
+            "{started:?}"
+        );
+    }
+
+    #[test]
+    fn anti_entropy_skips_a_peer_acked_up_to_date() {
+        let config = Config {
+            gossip_acks: true,
+            ..Default::default()
+        };
+        let mut n1 = test_node("n1", &["n1", "n2", "n3"], config);
+        let topology = json!({"n1": ["n2", "n3"]});
+        n1.handle(
+            "c0",
+            json!({"type": "topology", "msg_id": 1, "topology": topology}),
+        );
+        let sent = n1.handle(
+            "c1",
+            json!({"type": "broadcast", "msg_id": 2, "message": 1}),
+        );
+        let to_n2 = sent.iter().find(|msg| msg["dest"] == "n2").unwrap();
+        let ack = json!({"type": "update_ok", "in_reply_to": to_n2["body"]["msg_id"]});
+        n1.handle("n2", ack);
+        assert!(!n1.node.dirty_peers.contains("n2"));
+
+        n1.node.clock.advance(ANTI_ENTROPY_INTERVAL);
+        let sent = n1.tick();
+        let dests: BTreeSet<&str> = sent
+            .iter()
+            .filter(|msg| msg["body"]["type"] == "update")
+            .filter_map(|msg| msg["dest"].as_str())
+            .collect();
+        assert_eq!(dests, ["n3"].into(), "n2 is up to date");
+    }
+
+    #[test]
+    fn a_reconcile_command_makes_two_divergent_nodes_converge() {
+        let topology = json!({"n1": ["n2"], "n2": ["n1"]});
+        let config = || Config {
+            debug: true,
+            ..Default::default()
+        };
+        let mut cluster = cluster(&["n1", "n2"], topology, config);
+        cluster.schedule = Box::new(|msg| match msg["body"]["type"].as_str() {
+            Some("update") => Fate::Drop,
+            _ => Fate::Deliver,
+        });
+        cluster.request(
+            "n1",
+            json!({"type": "broadcast", "msg_id": 1, "message": 1}),
+        );
+        cluster.request(
+            "n2",
+            json!({"type": "broadcast", "msg_id": 2, "message": 2}),
+        );
+        assert_eq!(cluster.nodes["n1"].node.messages, BTreeSet::from([1]));
+        assert_eq!(cluster.nodes["n2"].node.messages, BTreeSet::from([2]));
+
+        cluster.schedule = Box::new(|_| Fate::Deliver);
+        let replies = cluster.request(
+            "n1",
+            json!({"type": "reconcile", "msg_id": 3, "peer": "n2"}),
+        );
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0]["type"], "reconcile_ok");
+        for node in cluster.nodes.values() {
+            assert_eq!(node.node.messages, BTreeSet::from([1, 2]), "{}", node.id());
+        }
+    }
+
+    #[test]
+    fn the_distinct_source_count_grows_with_each_new_peer() {
+        let mut n1 = test_node("n1", &["n1", "n2", "n3", "n4"], Config::default());
+        let sources = |n1: &mut TestNode<Node>| {
+            let stats = n1.request(json!({"type": "stats", "msg_id": 1}));
+            stats["metrics"]["distinct_sources"].as_u64().unwrap()
+        };
+        assert_eq!(sources(&mut n1), 0);
+        n1.handle("n2", json!({"type": "update", "messages": [1]}));
+        assert_eq!(sources(&mut n1), 1);
+        n1.handle("n2", json!({"type": "update", "messages": [2]}));
+        assert_eq!(sources(&mut n1), 1, "n2 was already counted");
+        n1.handle("n3", json!({"type": "update", "messages": [2]}));
+        assert_eq!(sources(&mut n1), 2, "values already known still count");
+        n1.handle("n4", json!({"type": "update", "messages": []}));
+        assert_eq!(sources(&mut n1), 2, "an empty update contributes nothing");
+    }
+
+    #[test]
+    fn g_set_adds_on_different_nodes_read_back_as_their_union() {
+        let ids = ["n1", "n2", "n3"];
+        let topology = json!({"n1": ["n2"], "n2": ["n1", "n3"], "n3": ["n2"]});
+        let config = || Config {
+            g_set: true,
+            ..Default::default()
+        };
+        let mut cluster = cluster(&ids, topology, config);
+        for (msg_id, (id, element)) in [("n1", 5), ("n3", 1), ("n2", 3), ("n3", 5)]
+            .into_iter()
+            .enumerate()
+        {
+            let add = json!({"type": "add", "msg_id": msg_id, "element": element});
+            cluster.client(id, add);
+        }
+        assert!(converge(&mut cluster, Duration::from_secs(1)));
+        assert_eq!(bodies(&cluster.replies, "add_ok").len(), 4);
+        for (msg_id, id) in ids.into_iter().enumerate() {
+            let read = json!({"type": "read", "msg_id": 10 + msg_id});
+            let replies = cluster.request(id, read);
+            let read_ok = replies.last().unwrap();
+            assert_eq!(read_ok["type"], "read_ok");
+            assert_eq!(read_ok["value"], json!([1, 3, 5]), "{id}");
+        }
+    }
+
+    #[test]
+    fn a_pn_counter_reads_the_sum_of_interleaved_increments_and_decrements() {
+        let ids = ["n1", "n2", "n3"];
+        let topology = json!({"n1": ["n2", "n3"], "n2": ["n1", "n3"], "n3": ["n1", "n2"]});
+        let config = || Config {
+            counter_mode: CounterMode::PnCounter,
+            ..Default::default()
+        };
+        let mut cluster = cluster(&ids, topology, config);
+        let deltas = [5, -3, 7, -10, 2, -1];
+        for (i, delta) in deltas.into_iter().enumerate() {
+            let add = json!({"type": "add", "msg_id": i, "delta": delta});
+            cluster.client(ids[i % 3], add);
+        }
+        assert!(cluster.pump_until(Duration::from_secs(1), |cluster| {
+            cluster.quiet() && cluster.replies.len() == deltas.len()
+        }));
+        assert_eq!(bodies(&cluster.replies, "add_ok").len(), deltas.len());
+        cluster.replies.clear();
+        for id in ids {
+            let replies = cluster.request(id, json!({"type": "read", "msg_id": 10}));
+            assert_eq!(replies.len(), 1, "{replies:?}");
+            assert_eq!(replies[0]["value"], deltas.iter().sum::<i64>(), "{id}");
+        }
+
+        let mut n1 = test_node("n1", &["n1"], Config::default());
+        let reply = n1.request(json!({"type": "add", "msg_id": 1, "delta": -1}));
+        assert_eq!(reply["code"], 12, "a g-counter only grows");
+    }
+
+    #[test]
+    fn an_add_rereads_through_repeated_lost_cas_and_gives_up_at_the_cap() {
+        let config = Config {
+            max_cas_attempts: 3,
+            ..Default::default()
+        };
+        let mut n1 = test_node("n1", &["n1"], config);
+        // Answers each read with the value another writer just moved to,
+        // and fails the CAS that follows, `losses` times; then lets it in.
+        let race = |n1: &mut TestNode<Node>, msg_id, losses| {
+            let add = json!({"type": "add", "msg_id": msg_id, "key": "k", "delta": 1});
+            let mut sent = n1.handle("c1", add);
+            for value in 0.. {
+                let read = sent[0]["body"].clone();
+                assert_eq!((&read["type"], &read["key"]), (&json!("read"), &json!("k")));
+                let read_ok =
+                    json!({"type": "read_ok", "in_reply_to": read["msg_id"], "value": value});
+                let cas = n1.handle("seq-kv", read_ok).remove(0)["body"].take();
+                assert_eq!(
+                    (&cas["from"], &cas["to"]),
+                    (&json!(value), &json!(value + 1))
+                );
+                let reply = if value < losses {
+                    json!({"type": "error", "in_reply_to": cas["msg_id"], "code": 22, "text": ""})
+                } else {
+                    json!({"type": "cas_ok", "in_reply_to": cas["msg_id"]})
+                };
+                sent = n1.handle("seq-kv", reply);
+                if sent[0]["dest"] == "c1" {
+                    return (value + 1, sent.remove(0)["body"].take());
+                }
+            }
+            unreachable!()
+        };
+
+        let (attempts, reply) = race(&mut n1, 1, 2);
+        assert_eq!(attempts, 3);
+        assert_eq!(reply["type"], "add_ok");
+        let (attempts, reply) = race(&mut n1, 2, 5);
+        assert_eq!(attempts, 3);
+        assert_eq!(
+            (&reply["type"], &reply["code"]),
+            (&json!("error"), &json!(30))
+        );
+        assert_eq!(reply["details"], json!({"key": "k", "attempts": 3}));
+    }
+
+    #[test]
+    fn capped_gossip_rounds_take_turns_contacting_each_neighbor_first() {
+        let config = Config {
+            max_gossip_per_iteration: Some(1),
+            ..Default::default()
+        };
+        let mut n1 = test_node("n1", &["n1", "n2", "n3", "n4"], config);
+        let topology = json!({"n1": ["n2", "n3", "n4"]});
+        n1.handle(
+            "c0",
+            json!({"type": "topology", "msg_id": 1, "topology": topology}),
+        );
+        let mut firsts = Vec::new();
+        for message in 0..6 {
+            let broadcast = json!({"type": "broadcast", "msg_id": 2, "message": message});
+            let mut sent = n1.handle("c1", broadcast);
+            let mut dests = Vec::new();
+            while !bodies(&sent, "update").is_empty() {
+                let updates = sent.iter().filter(|msg| msg["body"]["type"] == "update");
+                dests.extend(updates.map(|msg| msg["dest"].as_str().unwrap().to_owned()));
+                sent = n1.tick();
+            }
+            firsts.push(dests[0].clone());
+            dests.sort();
+            assert_eq!(dests, ["n2", "n3", "n4"], "every round reaches everyone");
+        }
+        assert_eq!(firsts, ["n2", "n3", "n4", "n2", "n3", "n4"]);
+    }
+
+    #[test]
+    fn a_counter_read_waits_for_seq_kv_not_a_sleep() {
+        let mut n1 = test_node("n1", &["n1"], Config::default());
+        let started = std::time::Instant::now();
+        let sent = n1.handle("c1", json!({"type": "read", "msg_id": 1}));
+        assert_eq!(sent.len(), 1, "nothing for the client until seq-kv answers");
+        assert_eq!(sent[0]["dest"], "seq-kv");
+        let read = &sent[0]["body"];
+        let read_ok = json!({"type": "read_ok", "in_reply_to": read["msg_id"], "value": 7});
+        let sent = n1.handle("seq-kv", read_ok);
+        let took = started.elapsed();
+        assert_eq!(sent[0]["dest"], "c1");
+        assert_eq!(
+            sent[0]["body"],
+            json!({"type": "read_ok", "msg_id": 1, "in_reply_to": 1, "value": 7, "age_ms": 0})
+        );
+        // Well under the 60ms the read used to sleep for.
+        assert!(took < Duration::from_millis(60), "{took:?}");
+    }
+
+    #[test]
+    fn a_read_that_seq_kv_never_answers_gets_a_timeout_error() {
+        let mut n1 = test_node("n1", &["n1"], Config::default());
+        let sent = n1.handle("c1", json!({"type": "read", "msg_id": 1}));
+        assert_eq!(sent[0]["dest"], "seq-kv");
+        n1.node.clock.advance(n1.node.config.kv_timeout / 2);
+        assert!(n1.tick().is_empty(), "still waiting");
+        n1.node.clock.advance(n1.node.config.kv_timeout / 2);
+        let sent = n1.tick();
+        assert_eq!(sent.len(), 1, "{sent:?}");
+        assert_eq!(sent[0]["dest"], "c1");
+        let error = &sent[0]["body"];
+        assert_eq!(
+            (&error["type"], &error["in_reply_to"]),
+            (&json!("error"), &json!(1))
+        );
+        assert_eq!(error["code"], 0, "timeout");
+        assert_eq!(n1.node.kv.pending().count(), 0);
+        assert!(n1.tick().is_empty(), "answered once");
+    }
+
+    #[test]
+    fn reinserting_a_known_value_counts_as_a_duplicate() {
+        let mut n1 = test_node("n1", &["n1", "n2"], Config::default());
+        let metrics = |n1: &mut TestNode<Node>| {
+            let stats = n1.request(json!({"type": "stats", "msg_id": 9}));
+            let metrics = &stats["metrics"];
+            (
+                metrics["values_new"].as_u64().unwrap(),
+                metrics["values_duplicate"].as_u64().unwrap(),
+                metrics["duplicate_ratio"].as_f64().unwrap(),
+            )
+        };
+        assert_eq!(metrics(&mut n1), (0, 0, 0.0));
+        n1.handle(
+            "c1",
+            json!({"type": "broadcast", "msg_id": 1, "message": 1}),
+        );
+        assert_eq!(metrics(&mut n1), (1, 0, 0.0));
+        n1.handle(
+            "c1",
+            json!({"type": "broadcast", "msg_id": 2, "message": 1}),
+        );
+        assert_eq!(metrics(&mut n1), (1, 1, 0.5));
+        n1.handle("n2", json!({"type": "update", "messages": [1, 2, 3, 4]}));
+        assert_eq!(metrics(&mut n1), (4, 2, 2.0 / 6.0));
+    }
+
+    #[test]
+    fn a_read_after_an_add_on_the_same_node_sees_it_despite_a_stale_store() {
+        let mut n1 = test_node("n1", &["n1"], Config::default());
+        let sent = n1.handle("c1", json!({"type": "add", "msg_id": 1, "delta": 5}));
+        let read = &sent[0]["body"];
+        let read_ok = json!({"type": "read_ok", "in_reply_to": read["msg_id"], "value": 0});
+        let cas = n1.handle("seq-kv", read_ok).remove(0)["body"].take();
+        let cas_ok = json!({"type": "cas_ok", "in_reply_to": cas["msg_id"]});
+        assert_eq!(n1.handle("seq-kv", cas_ok)[0]["body"]["type"], "add_ok");
+
+        let sent = n1.handle("c1", json!({"type": "read", "msg_id": 2}));
+        let read = &sent[0]["body"];
+        // seq-kv may serve a read from before the CAS.
+        let stale = json!({"type": "read_ok", "in_reply_to": read["msg_id"], "value": 0});
+        let sent = n1.handle("seq-kv", stale);
+        assert_eq!(sent[0]["dest"], "c1");
+        assert!(sent[0]["body"]["value"].as_i64().unwrap() >= 5, "{sent:?}");
+    }
+
+    #[test]
+    fn the_shutdown_report_sums_up_a_scripted_run() {
+        let ids = ["n1", "n2", "n3"];
+        let topology = json!({"n1": ["n2", "n3"], "n2": ["n1", "n3"], "n3": ["n1", "n2"]});
+        let mut cluster = cluster(&ids, topology, Config::default);
+        for message in [1, 2] {
+            let broadcast = json!({"type": "broadcast", "msg_id": message, "message": message});
+            cluster.request("n1", broadcast);
+        }
+        // Cut off from seq-kv, so this read is still pending at the end.
+        cluster.schedule = Box::new(|msg| match msg["dest"].as_str() {
+            Some("seq-kv") => Fate::Drop,
+            _ => Fate::Deliver,
+        });
+        cluster.request("n2", json!({"type": "read", "msg_id": 3}));
+
+        let report =
+            |id: &str| serde_json::to_value(cluster.nodes[id].node.shutdown_report()).unwrap();
+        let n1 = report("n1");
+        assert_eq!(
+            (&n1["messages"], &n1["peers"], &n1["peers_synced"]),
+            (&json!(2), &json!(2), &json!(2))
+        );
+        assert_eq!(n1["pending_kv"], 0);
+        assert_eq!(n1["metrics"]["gossip_sent"], 4, "two peers, two broadcasts");
+        let n2 = report("n2");
+        assert_eq!(n2["messages"], 2);
+        assert_eq!(n2["pending_kv"], 1);
+        assert_eq!(n2["metrics"]["gossip_sent"], 0, "n1 had told everyone");
+    }
+
+    #[test]
+    fn rapid_broadcasts_are_batched_into_one_update_per_neighbor() {
+        let run = |config: Config| {
+            let mut n1 = test_node("n1", &["n1", "n2", "n3"], config);
+            let topology = json!({"n1": ["n2", "n3"]});
+            n1.handle(
+                "c0",
+                json!({"type": "topology", "msg_id": 1, "topology": topology}),
+            );
+            let mut sent = Vec::new();
+            for message in 0..10 {
+                let broadcast = json!({"type": "broadcast", "msg_id": 2, "message": message});
+                sent.extend(n1.handle("c1", broadcast));
+                n1.node.clock.advance(Duration::from_millis(5));
+                sent.extend(n1.tick());
+            }
+            n1.node.clock.advance(Duration::from_millis(100));
+            sent.extend(n1.tick());
+            let mut updates: Vec<(String, Value)> = sent
+                .iter()
+                .filter(|msg| msg["body"]["type"] == "update")
+                .map(|msg| {
+                    (
+                        msg["dest"].as_str().unwrap().to_owned(),
+                        msg["body"]["messages"].clone(),
+                    )
+                })
+                .collect();
+            updates.sort_by(|a, b| a.0.cmp(&b.0));
+            updates
+        };
+        let batched = run(Config {
+            gossip_batch_interval: Some(Duration::from_millis(100)),
+            ..Default::default()
+        });
+        let all = json!((0..10).collect::<Vec<_>>());
+        assert_eq!(
+            batched,
+            [("n2".to_owned(), all.clone()), ("n3".to_owned(), all)]
+        );
+
+        let by_size = run(Config {
+            gossip_batch_interval: Some(Duration::from_secs(1)),
+            gossip_batch_size: Some(4),
+            ..Default::default()
+        });
+        let sizes: Vec<usize> = by_size
+            .iter()
+            .map(|(_, messages)| messages.as_array().unwrap().len())
+            .collect();
+        assert_eq!(
+            sizes,
+            [4, 4, 4, 4],
+            "the last two values wait out the second"
+        );
+
+        assert_eq!(run(Config::default()).len(), 20);
+    }
+
+    #[test]
+    fn a_burst_is_coalesced_into_one_update_per_peer_with_every_new_value() {
+        let config = Config {
+            gossip_batch_interval: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let mut n1 = test_node("n1", &["n1", "n2", "n3"], config);
+        let topology = json!({"n1": ["n2", "n3"]});
+        n1.handle(
+            "c0",
+            json!({"type": "topology", "msg_id": 1, "topology": topology}),
+        );
+        // Learned from n2, so n2's queue never gets it.
+        let mut sent = n1.handle("n2", json!({"type": "update", "messages": [1]}));
+        for message in 2..=5 {
+            let broadcast = json!({"type": "broadcast", "msg_id": message, "message": message});
+            sent.extend(n1.handle("c1", broadcast));
+        }
+        assert!(bodies(&sent, "update").is_empty(), "{sent:?}");
+        assert_eq!(n1.node.pending_gossip.len(), 2, "one queue entry per peer");
+        n1.node.clock.advance(Duration::from_millis(100));
+        let sent = n1.tick();
+        let updates: BTreeMap<&str, &Value> = sent
+            .iter()
+            .filter(|msg| msg["body"]["type"] == "update")
+            .map(|msg| (msg["dest"].as_str().unwrap(), &msg["body"]["messages"]))
+            .collect();
+        assert_eq!(updates.len(), 2, "{sent:?}");
+        assert_eq!(updates["n2"], &json!([2, 3, 4, 5]));
+        assert_eq!(updates["n3"], &json!([1, 2, 3, 4, 5]));
+        // Three broadcasts joined n2's entry and four joined n3's.
+        assert_eq!(n1.node.metrics.gossip_coalesced, 7);
     }
-    Ok(())
 }